@@ -30,7 +30,7 @@ pub(super) fn install_service() -> Result<()> {
     let reader = BufReader::new(template_file);
     let mut service_file = std::fs::File::create(service_dir.join("vpn-ip-tracker.service"))?;
 
-    for line in reader.lines().flatten() {
+    for line in reader.lines().map_while(core::result::Result::ok) {
         let sub_start = line.find('@');
 
         if let Some(sub_start) = sub_start {
@@ -1,5 +1,5 @@
 /* SPDX-License-Identifier: MIT OR Apache-2.0 */
-use std::io;
+use std::{fs, io};
 
 use clap::{Parser, Subcommand};
 use confy::ConfyError;
@@ -9,7 +9,7 @@ use thiserror::Error;
 use config_linux::{config_setup, install_service, uninstall_service};
 #[cfg(windows)]
 use config_win::{config_setup, install_service, uninstall_service};
-use vpn_ip_tracker::{TrackerConfig, DEFAULT_REPORT_URL};
+use vpn_ip_tracker::{outbox::Outbox, sink::SinkError, TrackerConfig, DEFAULT_REPORT_URL};
 
 #[cfg(unix)]
 mod config_linux;
@@ -35,6 +35,11 @@ enum Commands {
     },
     #[command(about = "Uninstall VPN IP Tracker service")]
     Uninstall,
+    #[command(about = "List reports that were not delivered yet by each sink")]
+    Outbox {
+        #[arg(long, help = "Drop all undelivered reports, stop the tracker first")]
+        purge: bool,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -43,6 +48,12 @@ enum ConfigError {
     Path(#[from] io::Error),
     #[error("configuration file error")]
     ConfigFile(#[from] ConfyError),
+    #[error("state directory is not available")]
+    StateDir,
+    #[error("outbox error")]
+    Outbox(#[source] io::Error),
+    #[error("sink configuration error")]
+    Sinks(#[from] SinkError),
     #[cfg(target_os = "windows")]
     #[error("windows service error")]
     Service(#[from] windows_service::Error),
@@ -62,6 +73,56 @@ fn main() -> Result<(), ConfigError> {
         Commands::Uninstall => {
             uninstall_service()?;
         }
+        Commands::Outbox { purge } => {
+            outbox(purge)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print undelivered reports of every sink, drop them if `purge` is set
+/// An unreadable outbox is an error unless it is purged
+fn outbox(purge: bool) -> Result<(), ConfigError> {
+    let config = TrackerConfig::try_load()?.unwrap_or_default();
+
+    for sink in config.sinks()? {
+        let path = Outbox::sink_path(&sink.name).ok_or(ConfigError::StateDir)?;
+
+        println!("[{}]", sink.name);
+
+        let mut outbox = match Outbox::open(&path, &config.outbox) {
+            Ok(outbox) => outbox,
+            Err(e) if purge => {
+                fs::remove_file(&path).map_err(ConfigError::Outbox)?;
+                println!("Removed unreadable outbox {}: {}", path.display(), e);
+                continue;
+            }
+            Err(e) => return Err(ConfigError::Outbox(e)),
+        };
+
+        for report in outbox.reports() {
+            let addresses: Vec<String> = report
+                .addresses
+                .iter()
+                .map(|addr| format!("{}/{}", addr.addr, addr.prefix_len))
+                .collect();
+
+            println!(
+                "{} {} {} {}",
+                report.timestamp,
+                report.event.as_str(),
+                report.interface.name,
+                addresses.join(",")
+            );
+        }
+
+        if purge {
+            let count = outbox.len();
+
+            outbox.purge().map_err(ConfigError::Outbox)?;
+            println!("Dropped {count} reports");
+        }
     }
 
     Ok(())
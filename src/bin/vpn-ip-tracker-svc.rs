@@ -23,7 +23,7 @@ mod vpn_tracker_service {
         service_dispatcher, Result,
     };
 
-    use vpn_ip_tracker::APP_NAME;
+    use vpn_ip_tracker::{APP_NAME, EXIT_TOKEN_REJECTED};
 
     const SERVICE_NAME: &str = APP_NAME;
     const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
@@ -115,7 +115,12 @@ mod vpn_tracker_service {
 
             match result {
                 Ok(Some(exit_status)) => {
-                    status = exit_status.code().unwrap_or(3) as u32;
+                    status = match exit_status.code() {
+                        // Stop cleanly so that the recovery actions do not restart the tracker
+                        // with the rejected token
+                        Some(code) if code == i32::from(EXIT_TOKEN_REJECTED) => 0,
+                        code => code.unwrap_or(3) as u32,
+                    };
                     break;
                 }
                 Ok(None) => println!("Process is still executing"),
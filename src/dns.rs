@@ -0,0 +1,379 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Minimal DNS client (RFC 1035) for the address lookups over UDP
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use vpn_ip_tracker::DnsRecord;
+
+use crate::utils;
+
+pub(crate) const HEADER_LEN: usize = 12;
+pub(crate) const CLASS_IN: u16 = 1;
+/// Recursion desired flag of the query
+const FLAG_RD: u16 = 0x0100;
+/// Response flag
+pub(crate) const FLAG_QR: u16 = 0x8000;
+/// Number of queries sent before giving up on a server
+const ATTEMPTS: u32 = 3;
+/// Longest label of a domain name (RFC 1035)
+const MAX_LABEL_LEN: usize = 63;
+/// Longest encoded domain name (RFC 1035)
+const MAX_NAME_LEN: usize = 255;
+
+/// Errors that can occur during a DNS lookup
+#[derive(Debug, Error)]
+pub(crate) enum DnsError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("unable to resolve DNS server {0}")]
+    Resolve(String),
+    #[error("no response from DNS server")]
+    Timeout,
+    #[error("malformed DNS message")]
+    Malformed,
+    #[error("DNS error response code {0}")]
+    Rcode(u16),
+    #[error("no address in the DNS answer")]
+    NoAnswer,
+    #[error("invalid domain name '{0}'")]
+    Name(String),
+}
+
+/// Type code of the DNS record
+pub(crate) fn record_type(record: DnsRecord) -> u16 {
+    match record {
+        DnsRecord::A => 1,
+        DnsRecord::Aaaa => 28,
+        DnsRecord::Txt => 16,
+    }
+}
+
+/// Look up addresses held by the `record` of `name`
+/// Arguments:
+/// - `server` - DNS server as `host:port`
+/// - `local` - address the query is sent from
+/// - `device` - interface the query is sent through
+/// - `name` - queried domain name
+/// - `record` - queried record type, TXT records must hold the bare address
+/// - `timeout` - time to wait for the first response
+pub(crate) fn lookup(
+    server: &str,
+    local: IpAddr,
+    device: Option<&str>,
+    name: &str,
+    record: DnsRecord,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, DnsError> {
+    let id = query_id();
+    let response = exchange(server, local, device, &query(id, name, record)?, timeout)?;
+
+    parse_answers(&response, id, record)
+}
+
+/// Send the DNS `msg` and wait for the response with the same ID
+/// The message is retransmitted with doubled timeout if no response arrives
+/// Arguments:
+/// - `server` - DNS server as `host:port`
+/// - `local` - address the message is sent from
+/// - `device` - interface the message is sent through, the routes decide if not set
+/// - `msg` - encoded DNS message
+/// - `timeout` - time to wait for the first response
+pub(crate) fn exchange(
+    server: &str,
+    local: IpAddr,
+    device: Option<&str>,
+    msg: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, DnsError> {
+    let server_addr = server
+        .to_socket_addrs()
+        .map_err(|_| DnsError::Resolve(server.into()))?
+        .find(|addr| addr.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| DnsError::Resolve(server.into()))?;
+    let socket = utils::udp_socket(local, device)?;
+    let mut buf = [0u8; 4096];
+    let mut wait = timeout;
+
+    socket.connect(server_addr)?;
+
+    for _ in 0..ATTEMPTS {
+        socket.send(msg)?;
+        let deadline = Instant::now() + wait;
+
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            // Stray datagrams of other queries are ignored
+            if len >= HEADER_LEN && buf[..2] == msg[..2] {
+                return Ok(buf[..len].to_vec());
+            }
+        }
+
+        wait *= 2;
+    }
+
+    Err(DnsError::Timeout)
+}
+
+/// Encode standard query for `record` of `name`
+pub(crate) fn query(id: u16, name: &str, record: DnsRecord) -> Result<Vec<u8>, DnsError> {
+    let mut msg = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+
+    for field in [id, FLAG_RD, 1, 0, 0, 0] {
+        msg.extend_from_slice(&field.to_be_bytes());
+    }
+
+    encode_name(&mut msg, name)?;
+    msg.extend_from_slice(&record_type(record).to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+/// Append `name` as a sequence of labels
+/// Labels are limited to 63 bytes and the encoded name to 255 bytes
+pub(crate) fn encode_name(msg: &mut Vec<u8>, name: &str) -> Result<(), DnsError> {
+    let start = msg.len();
+
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        if label.len() > MAX_LABEL_LEN {
+            msg.truncate(start);
+            return Err(DnsError::Name(name.into()));
+        }
+
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+
+    msg.push(0);
+
+    if msg.len() - start > MAX_NAME_LEN {
+        msg.truncate(start);
+        return Err(DnsError::Name(name.into()));
+    }
+
+    Ok(())
+}
+
+/// Random query ID
+pub(crate) fn query_id() -> u16 {
+    let mut id = [0u8; 2];
+
+    utils::fill_random(&mut id);
+    u16::from_be_bytes(id)
+}
+
+/// Check response header and return the response code
+pub(crate) fn response_code(msg: &[u8], id: u16) -> Result<u16, DnsError> {
+    if msg.len() < HEADER_LEN || read_u16(msg, 0)? != id || read_u16(msg, 2)? & FLAG_QR == 0 {
+        return Err(DnsError::Malformed);
+    }
+
+    Ok(read_u16(msg, 2)? & 0x000f)
+}
+
+/// Parse addresses of the `record` type from the answer section
+fn parse_answers(msg: &[u8], id: u16, record: DnsRecord) -> Result<Vec<IpAddr>, DnsError> {
+    let rcode = response_code(msg, id)?;
+
+    if rcode != 0 {
+        return Err(DnsError::Rcode(rcode));
+    }
+
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    let mut pos = HEADER_LEN;
+    let mut addresses = Vec::new();
+
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let kind = read_u16(msg, pos)?;
+        let len = read_u16(msg, pos + 8)? as usize;
+        let data = msg
+            .get(pos + 10..pos + 10 + len)
+            .ok_or(DnsError::Malformed)?;
+
+        pos += 10 + len;
+
+        if kind != record_type(record) {
+            continue;
+        }
+
+        let addr = match record {
+            DnsRecord::A => <[u8; 4]>::try_from(data)
+                .ok()
+                .map(Ipv4Addr::from)
+                .map(IpAddr::V4),
+            DnsRecord::Aaaa => <[u8; 16]>::try_from(data)
+                .ok()
+                .map(Ipv6Addr::from)
+                .map(IpAddr::V6),
+            DnsRecord::Txt => txt_value(data)?.trim().parse().ok(),
+        };
+
+        addresses.extend(addr);
+    }
+
+    if addresses.is_empty() {
+        return Err(DnsError::NoAnswer);
+    }
+
+    Ok(addresses)
+}
+
+/// Concatenate the character strings of TXT record data
+fn txt_value(mut data: &[u8]) -> Result<String, DnsError> {
+    let mut value = String::new();
+
+    while let Some((&len, rest)) = data.split_first() {
+        let chunk = rest.get(..len as usize).ok_or(DnsError::Malformed)?;
+
+        value += &String::from_utf8_lossy(chunk);
+        data = &rest[len as usize..];
+    }
+
+    Ok(value)
+}
+
+/// Get position after the possibly compressed name at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *msg.get(pos).ok_or(DnsError::Malformed)?;
+
+        match len {
+            0 => return Ok(pos + 1),
+            // Compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, DnsError> {
+    msg.get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or(DnsError::Malformed)
+}
+
+#[cfg(test)]
+mod dns_tests {
+    use std::time::Duration;
+
+    use vpn_ip_tracker::DnsRecord;
+
+    use crate::dns::{encode_name, lookup, parse_answers, query, DnsError};
+    use crate::testing::DnsStandIn;
+
+    #[test]
+    fn test_query() {
+        let msg = query(0x1234, "myip.opendns.com.", DnsRecord::A).unwrap();
+
+        assert_eq!(msg[..4], [0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&msg[12..30], b"\x04myip\x07opendns\x03com\x00");
+        assert_eq!(msg[30..], [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_encode_name() {
+        let mut msg = Vec::new();
+        let label = "a".repeat(63);
+        let long = [label.as_str(); 4].join(".");
+
+        encode_name(&mut msg, &format!("{label}.example")).unwrap();
+        assert_eq!(msg.len(), 73);
+        assert!(matches!(
+            encode_name(&mut msg, &format!("{label}a.example")),
+            Err(DnsError::Name(_))
+        ));
+        // 4 labels of 63 bytes take 257 bytes with their lengths and the root
+        assert!(matches!(
+            encode_name(&mut msg, &long),
+            Err(DnsError::Name(_))
+        ));
+        assert_eq!(msg.len(), 73);
+    }
+
+    #[test]
+    fn test_parse_answers() {
+        let mut response = query(7, "myip.opendns.com", DnsRecord::A).unwrap();
+
+        response[2] = 0x81;
+        response[7] = 1;
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 198, 51, 100, 7]);
+
+        assert_eq!(
+            parse_answers(&response, 7, DnsRecord::A).unwrap(),
+            ["198.51.100.7".parse::<std::net::IpAddr>().unwrap()]
+        );
+        assert!(matches!(
+            parse_answers(&response, 7, DnsRecord::Aaaa),
+            Err(DnsError::NoAnswer)
+        ));
+        assert!(matches!(
+            parse_answers(&response, 8, DnsRecord::A),
+            Err(DnsError::Malformed)
+        ));
+
+        response[3] = 3;
+        assert!(matches!(
+            parse_answers(&response, 7, DnsRecord::A),
+            Err(DnsError::Rcode(3))
+        ));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let server = DnsStandIn::start("198.51.100.7".parse().unwrap());
+        let local = "127.0.0.1".parse().unwrap();
+        let timeout = Duration::from_millis(500);
+        let expected = ["198.51.100.7".parse::<std::net::IpAddr>().unwrap()];
+
+        assert_eq!(
+            lookup(
+                &server.addr().to_string(),
+                local,
+                Some("lo"),
+                "myip.opendns.com",
+                DnsRecord::A,
+                timeout
+            )
+            .unwrap(),
+            expected
+        );
+        assert_eq!(
+            lookup(
+                &server.addr().to_string(),
+                local,
+                None,
+                "o-o.myaddr.l.google.com",
+                DnsRecord::Txt,
+                timeout
+            )
+            .unwrap(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,583 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use vpn_ip_tracker::{EgressConfig, EgressFormat, EgressMethod, EgressProvider};
+
+use crate::dns::{self, DnsError};
+use crate::http::{self, HttpError};
+use crate::stun::{self, StunError};
+use crate::utils::IfaceInfo;
+
+/// Time to wait for the first STUN or DNS response
+const UDP_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time to wait for the HTTP connection and every read of the response
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval of checking whether the running probes finished
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Errors that can occur while discovering the egress IP address
+#[derive(Debug, Error)]
+pub(crate) enum EgressError {
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error("invalid JSON response")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Stun(#[from] StunError),
+    #[error(transparent)]
+    Dns(#[from] DnsError),
+    #[error("no IP address in the response")]
+    NoAddress,
+    #[error("only {votes} providers agree, {quorum} required")]
+    NoQuorum { votes: usize, quorum: usize },
+    #[error("providers disagree, several addresses have {votes} votes")]
+    Tie { votes: usize },
+}
+
+/// Egress IP address agreed by the providers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct EgressIp {
+    pub(crate) addr: IpAddr,
+    /// Disagreement of the providers to surface in the report
+    pub(crate) warning: Option<String>,
+}
+
+impl From<IpAddr> for EgressIp {
+    fn from(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            warning: None,
+        }
+    }
+}
+
+struct CachedEgress {
+    addresses: Vec<IpAddr>,
+    egress: Option<EgressIp>,
+    probed_at: Instant,
+}
+
+/// Probe running in the background
+struct RunningProbe {
+    /// Interface addresses the probe was started for
+    addresses: Vec<IpAddr>,
+    result: Receiver<Result<EgressIp, EgressError>>,
+}
+
+/// Public egress IP address discovery through the VPN interfaces
+/// The probes run in background threads so that slow providers do not delay the reports of the
+/// interface changes
+pub(crate) struct EgressProbe {
+    config: Arc<EgressConfig>,
+    cache: HashMap<String, CachedEgress>,
+    running: HashMap<String, RunningProbe>,
+}
+
+impl EgressProbe {
+    pub(crate) fn new(config: &EgressConfig) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            cache: HashMap::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Get the known egress IP address of `iface`
+    /// The address is probed again in the background if the interface addresses changed or the
+    /// check interval elapsed, its result is returned by a later call. The last known address is
+    /// kept while the interface addresses are unchanged and if the probe fails
+    pub(crate) fn egress_ip(&mut self, iface: &IfaceInfo) -> Option<EgressIp> {
+        let addresses: Vec<IpAddr> = iface.addresses().collect();
+        let local = *addresses.first()?;
+        let interval = Duration::from_secs(self.config.interval);
+
+        self.finish(&iface.name);
+
+        let cached = self
+            .cache
+            .get(&iface.name)
+            .filter(|cached| cached.addresses == addresses);
+
+        if cached.is_some_and(|cached| cached.probed_at.elapsed() < interval) {
+            return cached.and_then(|cached| cached.egress.clone());
+        }
+
+        let egress = cached.and_then(|cached| cached.egress.clone());
+
+        if !self.running.contains_key(&iface.name) {
+            let (sender, result) = mpsc::channel();
+            let config = self.config.clone();
+            let device = iface.name.clone();
+
+            std::thread::spawn(move || sender.send(probe(&config, local, &device)));
+            self.running
+                .insert(iface.name.clone(), RunningProbe { addresses, result });
+        }
+
+        egress
+    }
+
+    /// Time until the results of the running probes should be collected, `None` if no probe
+    /// is running
+    pub(crate) fn poll_delay(&self) -> Option<Duration> {
+        (!self.running.is_empty()).then_some(PROBE_POLL_INTERVAL)
+    }
+
+    /// Forget interfaces that are not in `snapshot` anymore
+    pub(crate) fn retain(&mut self, snapshot: &[IfaceInfo]) {
+        let tracked = |name: &String| snapshot.iter().any(|iface| &iface.name == name);
+
+        self.cache.retain(|name, _| tracked(name));
+        self.running.retain(|name, _| tracked(name));
+    }
+
+    /// Cache result of the probe of the interface `name` if it finished
+    fn finish(&mut self, name: &str) {
+        let Some(running) = self.running.get(name) else {
+            return;
+        };
+        let result = match running.result.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => Err(EgressError::NoAddress),
+        };
+        let Some(RunningProbe { addresses, .. }) = self.running.remove(name) else {
+            return;
+        };
+        let previous = self
+            .cache
+            .get(name)
+            .filter(|cached| cached.addresses == addresses)
+            .and_then(|cached| cached.egress.clone());
+        let egress = match result {
+            Ok(egress) => {
+                debug!("Egress IP of {} is {}", name, egress.addr);
+                Some(egress)
+            }
+            Err(e) => {
+                warn!("Failed to discover egress IP of {}: {}", name, e);
+                previous
+            }
+        };
+
+        self.cache.insert(
+            name.to_string(),
+            CachedEgress {
+                addresses,
+                egress,
+                probed_at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Discover egress IP address with requests sent from the `local` address through the interface
+/// `device`
+/// The configured providers are queried in parallel, the single `method` is used if there are
+/// none
+fn probe(config: &EgressConfig, local: IpAddr, device: &str) -> Result<EgressIp, EgressError> {
+    let providers = &config.providers;
+
+    if providers.is_empty() {
+        return match config.method {
+            EgressMethod::Http => {
+                query_http(&config.url, config.format, &config.field, local, device)
+            }
+            EgressMethod::Stun => probe_stun(&config.stun_servers, local, device),
+        }
+        .map(EgressIp::from);
+    }
+
+    let answers: Vec<(String, Result<IpAddr, EgressError>)> = std::thread::scope(|scope| {
+        let queries: Vec<_> = providers
+            .iter()
+            .map(|provider| scope.spawn(move || query_provider(provider, local, device)))
+            .collect();
+
+        providers
+            .iter()
+            .zip(queries)
+            .map(|(provider, query)| {
+                let answer = query.join().unwrap_or(Err(EgressError::NoAddress));
+
+                (provider_name(provider).to_string(), answer)
+            })
+            .collect()
+    });
+
+    consensus(answers, config.quorum)
+}
+
+/// Ask the STUN `servers` in order for the mapped address
+fn probe_stun(servers: &[String], local: IpAddr, device: &str) -> Result<IpAddr, EgressError> {
+    let mut last_error = EgressError::NoAddress;
+
+    for server in servers {
+        match stun::mapped_address(server, local, Some(device), UDP_TIMEOUT) {
+            Ok(mapped) => return Ok(mapped.ip()),
+            Err(e) => {
+                debug!("STUN server {} failed: {}", server, e);
+                last_error = e.into();
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+fn provider_name(provider: &EgressProvider) -> &str {
+    match provider {
+        EgressProvider::Http { url, .. } => url,
+        EgressProvider::Dns { server, .. } => server,
+        EgressProvider::Stun { server } => server,
+    }
+}
+
+fn query_provider(
+    provider: &EgressProvider,
+    local: IpAddr,
+    device: &str,
+) -> Result<IpAddr, EgressError> {
+    match provider {
+        EgressProvider::Http { url, format, field } => {
+            query_http(url, *format, field, local, device)
+        }
+        EgressProvider::Dns {
+            name,
+            server,
+            record,
+        } => Ok(dns::lookup(server, local, Some(device), name, *record, UDP_TIMEOUT)?[0]),
+        EgressProvider::Stun { server } => {
+            Ok(stun::mapped_address(server, local, Some(device), UDP_TIMEOUT)?.ip())
+        }
+    }
+}
+
+/// Query the "what is my IP" HTTP service
+fn query_http(
+    url: &str,
+    format: EgressFormat,
+    field: &str,
+    local: IpAddr,
+    device: &str,
+) -> Result<IpAddr, EgressError> {
+    let body = http::get(url, local, Some(device), HTTP_TIMEOUT)?;
+
+    parse_response(&body, format, field)
+}
+
+/// Pick the address reported by most providers if at least `quorum` of them agree
+/// No address is picked if another one has as many votes, answers that differ from the picked
+/// address are described in the warning
+fn consensus(
+    answers: Vec<(String, Result<IpAddr, EgressError>)>,
+    quorum: usize,
+) -> Result<EgressIp, EgressError> {
+    let mut votes: BTreeMap<IpAddr, Vec<String>> = BTreeMap::new();
+
+    for (provider, answer) in answers {
+        match answer {
+            Ok(addr) => votes.entry(addr).or_default().push(provider),
+            Err(e) => debug!("Egress IP provider {} failed: {}", provider, e),
+        }
+    }
+
+    let total: usize = votes.values().map(Vec::len).sum();
+    let (addr, agreed) = votes
+        .iter()
+        .max_by_key(|(_, providers)| providers.len())
+        .map(|(addr, providers)| (*addr, providers.len()))
+        .ok_or(EgressError::NoAddress)?;
+
+    if agreed < quorum.max(1) {
+        return Err(EgressError::NoQuorum {
+            votes: agreed,
+            quorum,
+        });
+    }
+
+    if votes
+        .values()
+        .filter(|providers| providers.len() == agreed)
+        .count()
+        > 1
+    {
+        return Err(EgressError::Tie { votes: agreed });
+    }
+
+    let dissent: Vec<String> = votes
+        .iter()
+        .filter(|(other, _)| **other != addr)
+        .flat_map(|(other, providers)| {
+            providers
+                .iter()
+                .map(move |provider| format!("{provider} reported {other}"))
+        })
+        .collect();
+    let warning = (!dissent.is_empty()).then(|| {
+        let warning = format!(
+            "{agreed} of {total} providers agree, {}",
+            dissent.join(", ")
+        );
+
+        warn!("Egress IP providers disagree: {}", warning);
+        warning
+    });
+
+    Ok(EgressIp { addr, warning })
+}
+
+fn parse_response(body: &str, format: EgressFormat, field: &str) -> Result<IpAddr, EgressError> {
+    match format {
+        EgressFormat::Plain => body.trim().parse().map_err(|_| EgressError::NoAddress),
+        EgressFormat::Json => {
+            let json: serde_json::Value = serde_json::from_str(body)?;
+
+            field
+                .split('.')
+                .try_fold(&json, |value, key| value.get(key))
+                .and_then(|value| value.as_str())
+                .and_then(|addr| addr.parse().ok())
+                .ok_or(EgressError::NoAddress)
+        }
+    }
+}
+
+#[cfg(test)]
+mod egress_tests {
+    use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+
+    use vpn_ip_tracker::{DnsRecord, EgressConfig, EgressFormat, EgressMethod, EgressProvider};
+
+    use crate::egress::{consensus, parse_response, EgressError, EgressIp, EgressProbe};
+    use crate::testing::{DnsStandIn, HttpStandIn, Response, StunStandIn};
+    use crate::utils::IfaceInfo;
+
+    fn egress(addr: &str) -> Option<EgressIp> {
+        Some(addr.parse::<IpAddr>().unwrap().into())
+    }
+
+    fn loopback_iface() -> IfaceInfo {
+        IfaceInfo {
+            name: "lo".into(),
+            ipv4: BTreeMap::from([("127.0.0.1".parse().unwrap(), 8)]),
+            ..Default::default()
+        }
+    }
+
+    /// Wait until the running probe of `iface` finishes
+    fn wait(probe: &mut EgressProbe, iface: &IfaceInfo) {
+        while probe.running.contains_key(&iface.name) {
+            std::thread::sleep(Duration::from_millis(10));
+            probe.finish(&iface.name);
+        }
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let plain = parse_response(" 198.51.100.7\n", EgressFormat::Plain, "");
+        let json = parse_response(
+            r#"{"data": {"address": "2001:db8::7"}}"#,
+            EgressFormat::Json,
+            "data.address",
+        );
+
+        assert_eq!(
+            plain.unwrap(),
+            "198.51.100.7".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(
+            json.unwrap(),
+            "2001:db8::7".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert!(parse_response("<html>", EgressFormat::Plain, "").is_err());
+        assert!(parse_response(r#"{"ip": 7}"#, EgressFormat::Json, "ip").is_err());
+    }
+
+    #[test]
+    fn test_egress_ip_cached() {
+        let server = HttpStandIn::start(vec![
+            Response::new(200, r#"{"ip": "198.51.100.7"}"#),
+            Response::new(503, ""),
+        ]);
+        let mut probe = EgressProbe::new(&EgressConfig {
+            enabled: true,
+            url: server.url("/ip"),
+            format: EgressFormat::Json,
+            interval: 0,
+            ..Default::default()
+        });
+        let iface = loopback_iface();
+
+        // The probe runs in the background, the address is known once it finishes
+        assert_eq!(probe.egress_ip(&iface), None);
+        assert!(probe.poll_delay().is_some());
+        wait(&mut probe, &iface);
+        assert_eq!(probe.egress_ip(&iface), egress("198.51.100.7"));
+        // The failed probe keeps the last known address
+        wait(&mut probe, &iface);
+        assert_eq!(probe.egress_ip(&iface), egress("198.51.100.7"));
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(server.requests()[0].method, "GET");
+        assert_eq!(server.requests()[0].path, "/ip");
+        assert!(server.requests()[0].header("Host").is_some());
+    }
+
+    #[test]
+    fn test_egress_ip_interval() {
+        let server = HttpStandIn::start(vec![Response::new(200, "198.51.100.7")]);
+        let mut probe = EgressProbe::new(&EgressConfig {
+            enabled: true,
+            url: server.url("/"),
+            ..Default::default()
+        });
+        let iface = loopback_iface();
+
+        probe.egress_ip(&iface);
+        wait(&mut probe, &iface);
+        assert_eq!(probe.egress_ip(&iface), egress("198.51.100.7"));
+        assert_eq!(probe.egress_ip(&iface), egress("198.51.100.7"));
+        assert_eq!(probe.poll_delay(), None);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_egress_ip_stun() {
+        let server = StunStandIn::start("198.51.100.7:4500".parse().unwrap());
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0")
+            .and_then(|socket| socket.local_addr())
+            .unwrap();
+        let mut probe = EgressProbe::new(&EgressConfig {
+            enabled: true,
+            method: EgressMethod::Stun,
+            stun_servers: vec![closed.to_string(), server.addr().to_string()],
+            ..Default::default()
+        });
+        let iface = loopback_iface();
+
+        probe.egress_ip(&iface);
+        wait(&mut probe, &iface);
+        assert_eq!(probe.egress_ip(&iface), egress("198.51.100.7"));
+    }
+
+    #[test]
+    fn test_consensus() {
+        let answers = || {
+            vec![
+                (
+                    "http".to_string(),
+                    "198.51.100.7".parse().map_err(|_| EgressError::NoAddress),
+                ),
+                (
+                    "dns".to_string(),
+                    "198.51.100.7".parse().map_err(|_| EgressError::NoAddress),
+                ),
+                (
+                    "stun".to_string(),
+                    "203.0.113.9".parse().map_err(|_| EgressError::NoAddress),
+                ),
+                ("down".to_string(), Err(EgressError::NoAddress)),
+            ]
+        };
+        let agreed = consensus(answers(), 2).unwrap();
+
+        assert_eq!(agreed.addr, "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            agreed.warning.as_deref(),
+            Some("2 of 3 providers agree, stun reported 203.0.113.9")
+        );
+        assert!(matches!(
+            consensus(answers(), 3),
+            Err(EgressError::NoQuorum {
+                votes: 2,
+                quorum: 3
+            })
+        ));
+        assert!(matches!(
+            consensus(vec![("down".into(), Err(EgressError::NoAddress))], 1),
+            Err(EgressError::NoAddress)
+        ));
+    }
+
+    #[test]
+    fn test_consensus_tie() {
+        let answer = |provider: &str, addr: &str| {
+            (
+                provider.to_string(),
+                addr.parse().map_err(|_| EgressError::NoAddress),
+            )
+        };
+        let answers = |count: usize| {
+            vec![
+                answer("http", "198.51.100.7"),
+                answer("dns", "203.0.113.9"),
+                answer("stun", "203.0.113.9"),
+                answer("backup", "198.51.100.7"),
+            ]
+            .into_iter()
+            .take(count)
+            .collect()
+        };
+
+        assert!(matches!(
+            consensus(answers(4), 1),
+            Err(EgressError::Tie { votes: 2 })
+        ));
+        assert!(matches!(
+            consensus(answers(2), 1),
+            Err(EgressError::Tie { votes: 1 })
+        ));
+        assert!(matches!(
+            consensus(answers(3), 1),
+            Ok(EgressIp { addr, .. }) if addr == "203.0.113.9".parse::<IpAddr>().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_egress_ip_providers() {
+        let http = HttpStandIn::start(vec![Response::new(200, "198.51.100.7")]);
+        let dns = DnsStandIn::start("198.51.100.7".parse().unwrap());
+        let stun = StunStandIn::start("203.0.113.9:4500".parse().unwrap());
+        let mut probe = EgressProbe::new(&EgressConfig {
+            enabled: true,
+            quorum: 2,
+            providers: vec![
+                EgressProvider::Http {
+                    url: http.url("/"),
+                    format: EgressFormat::Plain,
+                    field: String::new(),
+                },
+                EgressProvider::Dns {
+                    name: "myip.opendns.com".into(),
+                    server: dns.addr().to_string(),
+                    record: DnsRecord::A,
+                },
+                EgressProvider::Stun {
+                    server: stun.addr().to_string(),
+                },
+            ],
+            ..Default::default()
+        });
+        let iface = loopback_iface();
+
+        probe.egress_ip(&iface);
+        wait(&mut probe, &iface);
+
+        let egress = probe.egress_ip(&iface).unwrap();
+
+        assert_eq!(egress.addr, "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert!(egress.warning.unwrap().ends_with("reported 203.0.113.9"));
+    }
+}
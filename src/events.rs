@@ -0,0 +1,551 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use vpn_ip_tracker::report::{
+    EventKind, ReportAddress, ReportEgress, ReportInterface, ReportPayload, REPORT_SCHEMA_VERSION,
+};
+
+use crate::utils::IfaceInfo;
+
+/// Version of the reported state file format
+const STATE_VERSION: u32 = 1;
+/// Reported state file name in the state directory
+pub(crate) const STATE_FILE: &str = "reported.json";
+
+/// Change of a VPN interface between two consecutive snapshots
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum IfaceEvent {
+    /// Interface appeared
+    Up { iface: IfaceInfo },
+    /// Interface is still present but its addresses changed
+    Changed {
+        previous: IfaceInfo,
+        iface: IfaceInfo,
+    },
+    /// Interface disappeared after being up for `session`
+    Down {
+        previous: IfaceInfo,
+        session: Duration,
+    },
+    /// Interface is unchanged and has been up for `session`
+    Heartbeat { iface: IfaceInfo, session: Duration },
+}
+
+impl IfaceEvent {
+    /// Kind of the event
+    pub(crate) fn kind(&self) -> EventKind {
+        match self {
+            IfaceEvent::Up { .. } => EventKind::Up,
+            IfaceEvent::Changed { .. } => EventKind::Changed,
+            IfaceEvent::Down { .. } => EventKind::Down,
+            IfaceEvent::Heartbeat { .. } => EventKind::Heartbeat,
+        }
+    }
+
+    /// Name of the interface the event belongs to
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            IfaceEvent::Up { iface }
+            | IfaceEvent::Changed { iface, .. }
+            | IfaceEvent::Heartbeat { iface, .. } => &iface.name,
+            IfaceEvent::Down { previous, .. } => &previous.name,
+        }
+    }
+
+    /// Current interface state, `None` if the interface is down
+    pub(crate) fn current(&self) -> Option<&IfaceInfo> {
+        match self {
+            IfaceEvent::Up { iface }
+            | IfaceEvent::Changed { iface, .. }
+            | IfaceEvent::Heartbeat { iface, .. } => Some(iface),
+            IfaceEvent::Down { .. } => None,
+        }
+    }
+
+    /// Previously reported interface state, `None` if the interface just came up
+    pub(crate) fn previous(&self) -> Option<&IfaceInfo> {
+        match self {
+            IfaceEvent::Changed { previous, .. } | IfaceEvent::Down { previous, .. } => {
+                Some(previous)
+            }
+            IfaceEvent::Up { .. } | IfaceEvent::Heartbeat { .. } => None,
+        }
+    }
+
+    /// Build JSON report payload of the event
+    /// Arguments:
+    /// - `hostname` - name of the host the tracker runs on
+    /// - `device` - configured device label
+    /// - `now` - time the report is created
+    pub(crate) fn payload(
+        &self,
+        hostname: &str,
+        device: Option<&str>,
+        now: SystemTime,
+    ) -> ReportPayload {
+        let iface = self.current().or(self.previous()).unwrap();
+
+        ReportPayload {
+            version: REPORT_SCHEMA_VERSION,
+            tracker_version: env!("CARGO_PKG_VERSION").into(),
+            hostname: hostname.into(),
+            device: device.map(str::to_string),
+            event: self.kind(),
+            timestamp: now
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            interface: ReportInterface {
+                name: iface.name.clone(),
+                // Zero is never a valid index, it stands for an unknown one
+                index: (iface.index != 0).then_some(iface.index),
+                kind: iface.kind,
+                mtu: iface.mtu,
+                oper_state: iface.oper_state,
+                peer: iface.peer,
+                tunnel: iface.tunnel,
+            },
+            addresses: self.current().map(report_addresses).unwrap_or_default(),
+            previous_addresses: self.previous().map(report_addresses).unwrap_or_default(),
+            egress: self
+                .current()
+                .and_then(|iface| iface.egress.as_ref())
+                .map(|egress| ReportEgress {
+                    addr: egress.addr,
+                    warning: egress.warning.clone(),
+                }),
+            session_duration: match self {
+                IfaceEvent::Down { session, .. } | IfaceEvent::Heartbeat { session, .. } => {
+                    Some(session.as_secs())
+                }
+                _ => None,
+            },
+            uptime: None,
+        }
+    }
+}
+
+fn report_addresses(iface: &IfaceInfo) -> Vec<ReportAddress> {
+    let ipv4 = iface.ipv4.iter().map(|(addr, len)| ReportAddress {
+        addr: (*addr).into(),
+        prefix_len: *len,
+    });
+    let ipv6 = iface.ipv6.iter().map(|(addr, len)| ReportAddress {
+        addr: (*addr).into(),
+        prefix_len: *len,
+    });
+
+    ipv4.chain(ipv6).collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IfaceState {
+    iface: IfaceInfo,
+    up_since: SystemTime,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    interfaces: HashMap<String, IfaceState>,
+}
+
+/// Last reported state of all VPN interfaces
+/// The state is kept in the state file if there is one so that restarts do not repeat reports
+#[derive(Debug, Default)]
+pub(crate) struct IfaceStates {
+    states: HashMap<String, IfaceState>,
+    path: Option<PathBuf>,
+}
+
+impl IfaceStates {
+    /// Load reported state stored at `path`
+    /// A missing file is an empty state, an unreadable, corrupted or incompatible one is an error
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let states = match fs::read(path) {
+            Ok(data) => {
+                let file: StateFile = serde_json::from_slice(&data)?;
+
+                if file.version != STATE_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported state version {}", file.version),
+                    ));
+                }
+
+                file.interfaces
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            states,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Create empty state stored at `path`, an existing file is replaced on the first store
+    pub(crate) fn empty(path: &Path) -> Self {
+        Self {
+            states: HashMap::new(),
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    /// Write the state to the state file if there is one
+    pub(crate) fn store(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec(&StateFile {
+            version: STATE_VERSION,
+            interfaces: self.states.clone(),
+        })?;
+
+        vpn_ip_tracker::write_atomic(path, &data, None)
+    }
+
+    /// Compute events that turn the reported state into `snapshot`
+    /// Arguments:
+    /// - `snapshot` - currently available VPN interfaces
+    /// - `now` - time the snapshot was taken
+    pub(crate) fn events(&self, snapshot: &[IfaceInfo], now: SystemTime) -> Vec<IfaceEvent> {
+        let mut events: Vec<IfaceEvent> = snapshot
+            .iter()
+            .filter_map(|iface| match self.states.get(&iface.name) {
+                None => Some(IfaceEvent::Up {
+                    iface: iface.clone(),
+                }),
+                Some(state) if iface.is_changed(&state.iface) => Some(IfaceEvent::Changed {
+                    previous: state.iface.clone(),
+                    iface: iface.clone(),
+                }),
+                Some(_) => None,
+            })
+            .collect();
+
+        let mut gone: Vec<&IfaceState> = self
+            .states
+            .values()
+            .filter(|state| !snapshot.iter().any(|it| it.name == state.iface.name))
+            .collect();
+
+        gone.sort_by(|lhs, rhs| lhs.iface.name.cmp(&rhs.iface.name));
+        events.extend(gone.into_iter().map(|state| IfaceEvent::Down {
+            previous: state.iface.clone(),
+            session: now.duration_since(state.up_since).unwrap_or_default(),
+        }));
+
+        events
+    }
+
+    /// Heartbeat events of all reported interfaces ordered by name
+    /// An interface without addresses stands in if none is reported so that heartbeats are
+    /// sent even while the VPN is down
+    pub(crate) fn heartbeats(&self, now: SystemTime) -> Vec<IfaceEvent> {
+        let mut states: Vec<&IfaceState> = self.states.values().collect();
+
+        states.sort_by(|lhs, rhs| lhs.iface.name.cmp(&rhs.iface.name));
+
+        if states.is_empty() {
+            return vec![IfaceEvent::Heartbeat {
+                iface: IfaceInfo::default(),
+                session: Duration::ZERO,
+            }];
+        }
+
+        states
+            .into_iter()
+            .map(|state| IfaceEvent::Heartbeat {
+                iface: state.iface.clone(),
+                session: now.duration_since(state.up_since).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Record `event` whose reports are queued
+    pub(crate) fn apply(&mut self, event: &IfaceEvent, now: SystemTime) {
+        match event {
+            IfaceEvent::Up { iface } => {
+                self.states.insert(
+                    iface.name.clone(),
+                    IfaceState {
+                        iface: iface.clone(),
+                        up_since: now,
+                    },
+                );
+            }
+            IfaceEvent::Changed { iface, .. } => {
+                if let Some(state) = self.states.get_mut(&iface.name) {
+                    state.iface = iface.clone();
+                }
+            }
+            IfaceEvent::Down { previous, .. } => {
+                self.states.remove(&previous.name);
+            }
+            IfaceEvent::Heartbeat { .. } => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, SystemTime},
+    };
+
+    use vpn_ip_tracker::report::{EventKind, OperState, ReportAddress, REPORT_SCHEMA_VERSION};
+
+    use crate::egress::EgressIp;
+    use crate::events::{IfaceEvent, IfaceStates};
+    use crate::utils::IfaceInfo;
+
+    fn iface(name: &str, addr: &str) -> IfaceInfo {
+        IfaceInfo {
+            name: name.into(),
+            ipv4: BTreeMap::from([(addr.parse().unwrap(), 24)]),
+            ..Default::default()
+        }
+    }
+
+    fn apply_all(states: &mut IfaceStates, events: &[IfaceEvent], now: SystemTime) {
+        for event in events {
+            states.apply(event, now);
+        }
+    }
+
+    #[test]
+    fn test_up_changed_down() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut states = IfaceStates::default();
+
+        let events = states.events(&[iface("tun0", "10.8.0.2")], start);
+        assert_eq!(
+            events,
+            [IfaceEvent::Up {
+                iface: iface("tun0", "10.8.0.2")
+            }]
+        );
+        apply_all(&mut states, &events, start);
+
+        let events = states.events(
+            &[iface("tun0", "10.8.0.3")],
+            start + Duration::from_secs(60),
+        );
+        assert_eq!(
+            events,
+            [IfaceEvent::Changed {
+                previous: iface("tun0", "10.8.0.2"),
+                iface: iface("tun0", "10.8.0.3"),
+            }]
+        );
+        apply_all(&mut states, &events, start + Duration::from_secs(60));
+
+        let events = states.events(&[], start + Duration::from_secs(90));
+        assert_eq!(
+            events,
+            [IfaceEvent::Down {
+                previous: iface("tun0", "10.8.0.3"),
+                session: Duration::from_secs(90),
+            }]
+        );
+        apply_all(&mut states, &events, start + Duration::from_secs(90));
+
+        assert!(states
+            .events(&[], start + Duration::from_secs(120))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_egress_warning_is_not_a_change() {
+        let now = SystemTime::now();
+        let mut states = IfaceStates::default();
+        let with_egress = |addr: &str, warning: &str| IfaceInfo {
+            egress: Some(EgressIp {
+                addr: addr.parse().unwrap(),
+                warning: Some(warning.into()),
+            }),
+            ..iface("tun0", "10.8.0.2")
+        };
+
+        let events = states.events(
+            &[with_egress("198.51.100.7", "dns reported 203.0.113.9")],
+            now,
+        );
+        apply_all(&mut states, &events, now);
+
+        assert!(states
+            .events(
+                &[with_egress("198.51.100.7", "stun reported 203.0.113.5")],
+                now
+            )
+            .is_empty());
+        assert_eq!(
+            states
+                .events(
+                    &[with_egress("203.0.113.9", "http reported 198.51.100.7")],
+                    now
+                )
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_unreported_event_is_repeated() {
+        let now = SystemTime::now();
+        let mut states = IfaceStates::default();
+
+        let events = states.events(&[iface("tun0", "10.8.0.2")], now);
+        apply_all(&mut states, &events, now);
+
+        let events = states.events(&[], now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(states.events(&[], now), events);
+    }
+
+    #[test]
+    fn test_several_interfaces() {
+        let now = SystemTime::now();
+        let mut states = IfaceStates::default();
+        let snapshot = [iface("tun0", "10.8.0.2"), iface("wg0", "10.9.0.2")];
+
+        let events = states.events(&snapshot, now);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.kind() == EventKind::Up));
+        apply_all(&mut states, &events, now);
+
+        let events = states.events(&[iface("wg0", "10.9.0.5")], now);
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|event| (event.name(), event.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [("wg0", EventKind::Changed), ("tun0", EventKind::Down)]
+        );
+    }
+
+    #[test]
+    fn test_payload() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let event = IfaceEvent::Changed {
+            previous: iface("wg0", "10.8.0.2"),
+            iface: IfaceInfo {
+                ipv6: BTreeMap::from([("fd00::2".parse().unwrap(), 64)]),
+                index: 7,
+                oper_state: Some(OperState::Up),
+                ..iface("wg0", "10.8.0.3")
+            },
+        };
+        let payload = event.payload("laptop", None, now);
+
+        assert_eq!(payload.version, REPORT_SCHEMA_VERSION);
+        assert_eq!(payload.tracker_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(payload.hostname, "laptop");
+        assert_eq!(payload.event, EventKind::Changed);
+        assert_eq!(payload.timestamp, 1_700_000_000);
+        assert_eq!(payload.interface.name, "wg0");
+        assert_eq!(payload.interface.index, Some(7));
+        assert_eq!(payload.interface.oper_state, Some(OperState::Up));
+        assert_eq!(
+            payload.addresses,
+            [
+                ReportAddress {
+                    addr: "10.8.0.3".parse().unwrap(),
+                    prefix_len: 24
+                },
+                ReportAddress {
+                    addr: "fd00::2".parse().unwrap(),
+                    prefix_len: 64
+                },
+            ]
+        );
+        assert_eq!(payload.previous_addresses.len(), 1);
+        assert_eq!(payload.session_duration, None);
+    }
+
+    #[test]
+    fn test_heartbeats() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut states = IfaceStates::default();
+
+        assert_eq!(
+            states.heartbeats(start),
+            [IfaceEvent::Heartbeat {
+                iface: IfaceInfo::default(),
+                session: Duration::ZERO,
+            }]
+        );
+
+        let events = states.events(
+            &[iface("wg0", "10.9.0.2"), iface("tun0", "10.8.0.2")],
+            start,
+        );
+
+        apply_all(&mut states, &events, start);
+
+        let heartbeats = states.heartbeats(start + Duration::from_secs(60));
+        let payload = heartbeats[1].payload("laptop", None, start + Duration::from_secs(60));
+
+        assert_eq!(heartbeats.len(), 2);
+        assert_eq!(heartbeats[0].name(), "tun0");
+        assert!(states
+            .events(
+                &[iface("wg0", "10.9.0.2"), iface("tun0", "10.8.0.2")],
+                start
+            )
+            .is_empty());
+        assert_eq!(payload.event, EventKind::Heartbeat);
+        assert_eq!(payload.interface.name, "wg0");
+        assert_eq!(payload.addresses[0].addr.to_string(), "10.9.0.2");
+        assert!(payload.previous_addresses.is_empty());
+        assert_eq!(payload.session_duration, Some(60));
+    }
+
+    #[test]
+    fn test_persistent_states() {
+        let path = std::env::temp_dir()
+            .join(format!("vpn-ip-tracker-states-{}", std::process::id()))
+            .join("reported.json");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut states = IfaceStates::open(&path).unwrap();
+        let wg0 = IfaceInfo {
+            ipv6: BTreeMap::from([("fd00::2".parse().unwrap(), 64)]),
+            ..iface("wg0", "10.9.0.2")
+        };
+
+        let events = states.events(&[iface("tun0", "10.8.0.2"), wg0.clone()], start);
+        apply_all(&mut states, &events, start);
+        states.store().unwrap();
+
+        let restored = IfaceStates::open(&path).unwrap();
+
+        assert!(restored
+            .events(&[iface("tun0", "10.8.0.2"), wg0], start)
+            .is_empty());
+        assert_eq!(
+            restored.events(&[], start + Duration::from_secs(60))[0],
+            IfaceEvent::Down {
+                previous: iface("tun0", "10.8.0.2"),
+                session: Duration::from_secs(60),
+            }
+        );
+
+        std::fs::write(&path, "{\"version\": 1, \"interfaces\": {").unwrap();
+        assert!(IfaceStates::open(&path).is_err());
+        std::fs::write(&path, "{\"version\": 99, \"interfaces\": {}}").unwrap();
+        assert!(IfaceStates::open(&path).is_err());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
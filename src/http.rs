@@ -0,0 +1,209 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Minimal HTTP/1.0 client for the GET requests that must leave through a given interface
+//! reqwest cannot bind its sockets to an interface, so the egress probes use this one. HTTP/1.0
+//! keeps the servers from using chunked responses, redirects are not followed
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::IpAddr,
+    time::Duration,
+};
+
+use reqwest::Url;
+use thiserror::Error;
+
+use crate::utils;
+
+/// Largest accepted response with its headers
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// Errors that can occur during an HTTP request
+#[derive(Debug, Error)]
+pub(crate) enum HttpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid URL {0}")]
+    Url(String),
+    #[error("unable to resolve {0}")]
+    Resolve(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("malformed HTTP response")]
+    Malformed,
+    #[error("HTTP status {0}")]
+    Status(u16),
+}
+
+/// Fetch body of `url`
+/// Arguments:
+/// - `url` - `http` or `https` URL
+/// - `local` - address the request is sent from
+/// - `device` - interface the request is sent through
+/// - `timeout` - time to wait for the connection and for every read
+pub(crate) fn get(
+    url: &str,
+    local: IpAddr,
+    device: Option<&str>,
+    timeout: Duration,
+) -> Result<String, HttpError> {
+    let url = Url::parse(url).map_err(|_| HttpError::Url(url.into()))?;
+    let invalid = || HttpError::Url(url.to_string());
+    let host = url.host_str().ok_or_else(invalid)?;
+    let addr = url
+        .socket_addrs(|| None)
+        .map_err(|_| HttpError::Resolve(host.into()))?
+        .into_iter()
+        .find(|addr| addr.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| HttpError::Resolve(host.into()))?;
+    let tcp = utils::tcp_connect(local, device, addr, timeout)?;
+    let authority = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let target = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "GET {target} HTTP/1.0\r\nHost: {authority}\r\nUser-Agent: vpn-ip-tracker/{}\r\n\
+         Accept: */*\r\nConnection: close\r\n\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+
+    match url.scheme() {
+        "http" => exchange(tcp, &request),
+        "https" => {
+            let domain = host.trim_start_matches('[').trim_end_matches(']');
+            let tls = native_tls::TlsConnector::new()
+                .map_err(|e| HttpError::Tls(e.to_string()))?
+                .connect(domain, tcp)
+                .map_err(|e| HttpError::Tls(e.to_string()))?;
+
+            exchange(tls, &request)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn exchange(mut stream: impl Read + Write, request: &str) -> Result<String, HttpError> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    read_response(stream)
+}
+
+/// Read the response, the body is read by its length if known, so a peer that does not close
+/// the TLS session cleanly does not fail the request
+fn read_response(stream: impl Read) -> Result<String, HttpError> {
+    let mut reader = BufReader::new(stream.take(MAX_RESPONSE_LEN));
+    let mut line = String::new();
+
+    reader.read_line(&mut line)?;
+
+    let status: u16 = line
+        .strip_prefix("HTTP/")
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(HttpError::Malformed)?;
+    let mut length = None;
+
+    loop {
+        line.clear();
+
+        if reader.read_line(&mut line)? == 0 {
+            return Err(HttpError::Malformed);
+        }
+
+        let header = line.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        let (name, value) = header.split_once(':').ok_or(HttpError::Malformed)?;
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.parse::<u64>().map_err(|_| HttpError::Malformed)?);
+        }
+    }
+
+    if !(200..300).contains(&status) {
+        return Err(HttpError::Status(status));
+    }
+
+    let mut body = Vec::new();
+
+    if let Some(length) = length {
+        (&mut reader).take(length).read_to_end(&mut body)?;
+
+        if (body.len() as u64) < length {
+            return Err(HttpError::Malformed);
+        }
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+
+    String::from_utf8(body).map_err(|_| HttpError::Malformed)
+}
+
+#[cfg(test)]
+mod http_tests {
+    use std::time::Duration;
+
+    use crate::http::{get, read_response, HttpError};
+    use crate::testing::{HttpStandIn, Response};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_get() {
+        let server = HttpStandIn::start(vec![
+            Response::new(200, "198.51.100.7\n"),
+            Response {
+                headers: vec![("Location".into(), "/ip".into())],
+                ..Response::new(302, "")
+            },
+        ]);
+        let local = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(
+            get(&server.url("/ip?format=text"), local, Some("lo"), TIMEOUT).unwrap(),
+            "198.51.100.7\n"
+        );
+        // Redirects are not followed
+        assert!(matches!(
+            get(&server.url("/"), local, Some("lo"), TIMEOUT),
+            Err(HttpError::Status(302))
+        ));
+
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/ip?format=text");
+        assert_eq!(
+            requests[0].header("Host"),
+            Some(server.url("").trim_start_matches("http://"))
+        );
+    }
+
+    #[test]
+    fn test_read_response() {
+        let sized = "HTTP/1.0 200 OK\r\nContent-Length: 12\r\n\r\n198.51.100.7\n";
+        let to_eof = "HTTP/1.0 200 OK\r\n\r\n198.51.100.7\n";
+        let truncated = "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n198.51";
+
+        assert_eq!(read_response(sized.as_bytes()).unwrap(), "198.51.100.7");
+        assert_eq!(read_response(to_eof.as_bytes()).unwrap(), "198.51.100.7\n");
+        assert!(matches!(
+            read_response(truncated.as_bytes()),
+            Err(HttpError::Malformed)
+        ));
+        assert!(matches!(
+            read_response("SSH-2.0-OpenSSH\r\n".as_bytes()),
+            Err(HttpError::Malformed)
+        ));
+    }
+}
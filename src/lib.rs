@@ -1,11 +1,28 @@
 /* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::path::{Path, PathBuf};
+
 use confy::ConfyError;
+use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 
+use matching::MatchConfig;
+use outbox::OutboxConfig;
+use report::ReportFormat;
+use sink::{HttpSinkConfig, SinkConfig, SinkError, SinkKind, DEFAULT_SINK};
+
+pub mod matching;
+pub mod outbox;
+pub mod report;
+pub mod signature;
+pub mod sink;
+
 /// Application name that is used for configuration stuff
 pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
 #[cfg(windows)]
 pub const APP_SERVICE_NAME: &str = "vpn-ip-tracker-svc";
+/// Exit code of the tracker when no sink accepts its token, the service is not restarted then
+/// `EX_NOPERM` of sysexits
+pub const EXIT_TOKEN_REJECTED: u8 = 77;
 /// Default report service URL
 pub const DEFAULT_REPORT_URL: &str = env!("VPN_IP_TRACKER_REPORT_URL");
 /// Environment variable name that provides report URL to send VPN IP address reports
@@ -17,9 +34,302 @@ const TOKEN_ENV_VAR: &str = "IPREPORT_APP_TOKEN";
 #[derive(Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TrackerConfig {
     /// Application token
+    #[serde(default)]
     pub token: String,
-    /// Report URL
+    /// Report URL, an empty one disables the default sink
+    #[serde(default)]
     pub report_url: String,
+    /// Report body format
+    #[serde(default)]
+    pub report_format: ReportFormat,
+    /// Sign the reports of the default sink with the token instead of sending it
+    #[serde(default)]
+    pub sign_reports: bool,
+    /// Device label included in the JSON reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Seconds between heartbeat reports, 0 disables heartbeats
+    #[serde(default)]
+    pub heartbeat_interval: u64,
+    /// Rules that select the tracked VPN interfaces
+    #[serde(default, rename = "match")]
+    pub iface_match: MatchConfig,
+    /// Tracked interface addresses
+    #[serde(default)]
+    pub addresses: AddressConfig,
+    /// Interface change monitoring
+    #[serde(default)]
+    pub monitor: MonitorConfig,
+    /// Public egress IP discovery
+    #[serde(default)]
+    pub egress: EgressConfig,
+    /// Retry policy of the failed reports
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Limits of the undelivered reports queue
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    /// Report destinations in addition to the default one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<SinkConfig>,
+}
+
+/// Get directory that keeps the tracker state between restarts
+/// `$XDG_STATE_HOME/vpn-ip-tracker` on Linux, the local data directory elsewhere
+pub fn state_dir() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("", "", APP_NAME)?;
+
+    Some(
+        dirs.state_dir()
+            .unwrap_or_else(|| dirs.data_local_dir())
+            .to_path_buf(),
+    )
+}
+
+/// Get directory for the files shared with the other programs of the user
+/// `$XDG_RUNTIME_DIR/vpn-ip-tracker` on Linux, the state directory elsewhere
+pub fn runtime_dir() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("", "", APP_NAME)?;
+
+    dirs.runtime_dir().map(Path::to_path_buf).or_else(state_dir)
+}
+
+/// Replace file at `path` atomically so that a crash never leaves a partial one
+/// Missing parent directories are created
+/// Arguments:
+/// - `path` - replaced file
+/// - `data` - new content of the file
+/// - `mode` - Unix permissions of the file, the created directories can be entered by whoever
+///   may read it; the defaults apply if `None`
+pub fn write_atomic(path: &Path, data: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    use std::io::Write;
+    #[cfg(unix)]
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    let tmp_path = path.with_extension("tmp");
+
+    if let Some(dir) = path.parent() {
+        let mut builder = std::fs::DirBuilder::new();
+
+        builder.recursive(true);
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            builder.mode(mode | (mode & 0o444) >> 2);
+        }
+        builder.create(dir)?;
+    }
+
+    // The mode applies to new files only, a stale one may have a different one
+    let _ = std::fs::remove_file(&tmp_path);
+    let mut options = std::fs::OpenOptions::new();
+
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options.open(&tmp_path)?;
+
+    file.write_all(data)?;
+    // The data must reach the disk before the rename, or a crash may leave an empty file
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Way the tracker learns about interface changes
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MonitorMode {
+    /// Wait for kernel notifications, falls back to polling where they are not available
+    #[default]
+    Netlink,
+    /// Re-check interfaces periodically
+    Poll,
+}
+
+/// `monitor` section of the tracker configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MonitorConfig {
+    /// Monitoring mode
+    pub mode: MonitorMode,
+    /// Interval between interface checks in seconds, also applies to the netlink mode as the
+    /// longest time without a check
+    pub poll_interval: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            mode: MonitorMode::default(),
+            poll_interval: 30,
+        }
+    }
+}
+
+/// Format of the egress IP service response
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressFormat {
+    /// Response body is the bare IP address
+    #[default]
+    Plain,
+    /// Response body is a JSON object that holds the IP address in `field`
+    Json,
+}
+
+/// Egress IP discovery method
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressMethod {
+    /// Query the "what is my IP" HTTP service
+    #[default]
+    Http,
+    /// Send STUN Binding Requests to the STUN servers
+    Stun,
+}
+
+/// DNS record that holds the egress IP address
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecord {
+    #[default]
+    A,
+    Aaaa,
+    Txt,
+}
+
+fn default_egress_field() -> String {
+    "ip".into()
+}
+
+/// Egress IP provider queried for the consensus
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EgressProvider {
+    /// "What is my IP" HTTP service
+    Http {
+        url: String,
+        #[serde(default)]
+        format: EgressFormat,
+        #[serde(default = "default_egress_field")]
+        field: String,
+    },
+    /// DNS server that answers with the address of the client, like `myip.opendns.com` at
+    /// `resolver1.opendns.com:53`
+    Dns {
+        name: String,
+        server: String,
+        #[serde(default)]
+        record: DnsRecord,
+    },
+    /// STUN server as `host:port`
+    Stun { server: String },
+}
+
+/// `egress` section of the tracker configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EgressConfig {
+    /// Discover public egress IP address of the VPN interfaces
+    pub enabled: bool,
+    /// Discovery method
+    pub method: EgressMethod,
+    /// "What is my IP" service URL
+    pub url: String,
+    /// Service response format
+    pub format: EgressFormat,
+    /// Dot-separated path to the address in the JSON response
+    pub field: String,
+    /// STUN servers as `host:port`, tried in order until one responds
+    pub stun_servers: Vec<String>,
+    /// Interval between egress IP checks in seconds if the interface did not change
+    pub interval: u64,
+    /// Number of providers that must report the same address
+    pub quorum: usize,
+    /// Providers queried in parallel, `method` is used if there are none
+    pub providers: Vec<EgressProvider>,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            method: EgressMethod::default(),
+            url: "https://api.ipify.org".into(),
+            format: EgressFormat::default(),
+            field: "ip".into(),
+            stun_servers: vec![
+                "stun.l.google.com:19302".into(),
+                "stun.cloudflare.com:3478".into(),
+            ],
+            interval: 300,
+            quorum: 1,
+            providers: Vec::new(),
+        }
+    }
+}
+
+/// IP address family to track
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+    Both,
+}
+
+impl IpFamily {
+    /// Check whether IPv4 addresses are tracked
+    pub fn has_ipv4(&self) -> bool {
+        matches!(self, IpFamily::Ipv4 | IpFamily::Both)
+    }
+
+    /// Check whether IPv6 addresses are tracked
+    pub fn has_ipv6(&self) -> bool {
+        matches!(self, IpFamily::Ipv6 | IpFamily::Both)
+    }
+}
+
+/// `addresses` section of the tracker configuration
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AddressConfig {
+    /// Address family to track
+    pub family: IpFamily,
+    /// Track link-local addresses (`169.254.0.0/16` and `fe80::/10`)
+    pub link_local: bool,
+    /// Track temporary and deprecated IPv6 addresses
+    pub temporary: bool,
+}
+
+/// `retry` section of the tracker configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Delivery attempts of a report before it is postponed to the next check
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled with every attempt
+    pub base_delay_ms: u64,
+    /// Longest delay between attempts in milliseconds
+    pub max_delay_ms: u64,
+    /// Random deviation of the delay as a fraction of it, `0.0` to `1.0`
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+            jitter: 0.2,
+        }
+    }
 }
 
 impl TrackerConfig {
@@ -28,21 +338,54 @@ impl TrackerConfig {
     /// - `token` - application token string
     /// - `report_url` - report service URL
     pub fn new(token: String, report_url: String) -> Self {
-        Self { token, report_url }
+        Self {
+            token,
+            report_url,
+            ..Default::default()
+        }
+    }
+
+    /// Get all report destinations
+    /// The top-level `token`, `report_url` and `report_format` make up the default sink that
+    /// goes first if the report URL is set
+    pub fn sinks(&self) -> Result<Vec<SinkConfig>, SinkError> {
+        let default = (!self.report_url.is_empty()).then(|| SinkConfig {
+            name: DEFAULT_SINK.into(),
+            events: Vec::new(),
+            kind: SinkKind::Http(HttpSinkConfig {
+                url: self.report_url.clone(),
+                token: self.token.clone(),
+                format: self.report_format,
+                sign: self.sign_reports,
+            }),
+            retry: None,
+        });
+
+        let sinks: Vec<SinkConfig> = default.into_iter().chain(self.sinks.clone()).collect();
+
+        sink::check_names(&sinks)?;
+        Ok(sinks)
     }
 
     /// Try to load tracker configuration
     /// 1. tries to get configuration from OS specific user configuration directory
     /// 2. if the configuration is not available, try to load configuration from the environment
-    /// variables (see [`REPORT_URL_VAR`](REPORT_URL_VAR) and [`TOKEN_ENV_VAR`](TOKEN_ENV_VAR))
+    ///    variables (see [`REPORT_URL_VAR`](REPORT_URL_VAR) and [`TOKEN_ENV_VAR`](TOKEN_ENV_VAR))
     pub fn load() -> Option<Self> {
-        if let Ok(config) = Self::load_config() {
-            if config != TrackerConfig::default() {
-                return Some(config);
-            }
+        Self::try_load().unwrap_or_else(|_| Self::from_env())
+    }
+
+    /// Load tracker configuration like [`load`](Self::load), but fail on an unreadable
+    /// configuration file instead of falling back to the environment variables
+    /// Returns none if neither the file nor the variables configure the tracker
+    pub fn try_load() -> Result<Option<Self>, ConfyError> {
+        let config = Self::load_config()?;
+
+        if config != TrackerConfig::default() {
+            return Ok(Some(config));
         }
 
-        Self::from_env()
+        Ok(Self::from_env())
     }
 
     #[cfg(windows)]
@@ -75,10 +418,16 @@ impl TrackerConfig {
 }
 
 #[cfg(test)]
-mod config_tests {
+pub(crate) mod config_tests {
     use std::env;
 
-    use crate::{TrackerConfig, APP_NAME, REPORT_URL_VAR, TOKEN_ENV_VAR};
+    use crate::report::ReportFormat;
+    use crate::sink::{HttpSinkConfig, SinkKind, DEFAULT_SINK};
+    use crate::{
+        AddressConfig, DnsRecord, EgressConfig, EgressFormat, EgressMethod, EgressProvider,
+        IpFamily, MonitorConfig, MonitorMode, RetryConfig, TrackerConfig, APP_NAME, REPORT_URL_VAR,
+        TOKEN_ENV_VAR,
+    };
 
     const TEST_TOKEN: &str = "some_env_token";
     const TEST_URL: &str = "https://some_url/";
@@ -130,6 +479,7 @@ mod config_tests {
                 TrackerConfig {
                     token: exp_token.to_string(),
                     report_url: exp_url.to_string(),
+                    ..Default::default()
                 },
             );
         }
@@ -146,4 +496,167 @@ mod config_tests {
         assert_eq!(config.report_url, TEST_URL);
         teardown();
     }
+
+    /// Store `config` to a file and check that it loads back unchanged
+    /// Arguments:
+    /// - `name` - name of the temporary file, unique per test
+    /// - `config` - stored configuration
+    pub(crate) fn assert_round_trip(name: &str, config: TrackerConfig) {
+        let path = env::temp_dir().join(format!("{APP_NAME}-{name}-test.toml"));
+
+        confy::store_path(&path, &config).unwrap();
+        let loaded: TrackerConfig = confy::load_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, config);
+    }
+
+    #[test]
+    fn test_load_top_level() {
+        assert_round_trip(
+            "top-level",
+            TrackerConfig {
+                token: TEST_TOKEN.into(),
+                report_url: TEST_URL.into(),
+                report_format: ReportFormat::Json,
+                sign_reports: true,
+                device: Some("office-laptop".into()),
+                heartbeat_interval: 600,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_load_addresses_section() {
+        assert_round_trip(
+            "addresses",
+            TrackerConfig {
+                addresses: AddressConfig {
+                    family: IpFamily::Both,
+                    link_local: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_load_monitor_section() {
+        assert_round_trip(
+            "monitor",
+            TrackerConfig {
+                monitor: MonitorConfig {
+                    mode: MonitorMode::Poll,
+                    poll_interval: 10,
+                },
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_load_retry_section() {
+        assert_round_trip(
+            "retry",
+            TrackerConfig {
+                retry: RetryConfig {
+                    max_attempts: 2,
+                    jitter: 0.0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_load_egress_section() {
+        assert_round_trip(
+            "egress",
+            TrackerConfig {
+                egress: EgressConfig {
+                    enabled: true,
+                    method: EgressMethod::Stun,
+                    url: "https://ip.example/json".into(),
+                    format: EgressFormat::Json,
+                    field: "data.ip".into(),
+                    stun_servers: vec!["stun.example:3478".into()],
+                    quorum: 2,
+                    providers: vec![
+                        EgressProvider::Http {
+                            url: "https://ip.example".into(),
+                            format: EgressFormat::Plain,
+                            field: "ip".into(),
+                        },
+                        EgressProvider::Dns {
+                            name: "myip.opendns.com".into(),
+                            server: "resolver1.opendns.com:53".into(),
+                            record: DnsRecord::A,
+                        },
+                        EgressProvider::Stun {
+                            server: "stun.example:3478".into(),
+                        },
+                    ],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_load_match_without_rules() {
+        let path = env::temp_dir().join(format!("{APP_NAME}-match-test.toml"));
+
+        std::fs::write(
+            &path,
+            format!("token = \"{TEST_TOKEN}\"\n\n[match]\nmode = \"route\"\ninclude_down = true\n"),
+        )
+        .unwrap();
+        let loaded: TrackerConfig = confy::load_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            loaded.iface_match,
+            crate::matching::MatchConfig {
+                include_down: true,
+                mode: crate::matching::MatchMode::Route,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_legacy_config() {
+        let path = env::temp_dir().join(format!("{APP_NAME}-legacy-test.toml"));
+
+        std::fs::write(
+            &path,
+            format!("token = \"{TEST_TOKEN}\"\nreport_url = \"{TEST_URL}\"\n"),
+        )
+        .unwrap();
+        let loaded: TrackerConfig = confy::load_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            loaded,
+            TrackerConfig::new(TEST_TOKEN.into(), TEST_URL.into())
+        );
+
+        let sinks = loaded.sinks().unwrap();
+
+        assert_eq!(sinks.len(), 1);
+        assert_eq!(sinks[0].name, DEFAULT_SINK);
+        assert_eq!(
+            sinks[0].kind,
+            SinkKind::Http(HttpSinkConfig {
+                url: TEST_URL.into(),
+                token: TEST_TOKEN.into(),
+                format: ReportFormat::Plain,
+                sign: false,
+            })
+        );
+    }
 }
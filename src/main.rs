@@ -1,11 +1,39 @@
 /* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{process::ExitCode, time::Duration};
+
 use clap::Parser;
 use ifcfg::IfCfg;
-use log::{debug, warn};
-
+use log::{debug, error, warn};
+
+use egress::EgressProbe;
+use events::IfaceStates;
+use procfs::ProcNet;
+use routes::RouteTable;
+use sysfs::SysfsNet;
+use tracker::{IfaceScanner, Tracker};
 use utils::IfaceInfo;
-use vpn_ip_tracker::TrackerConfig;
-
+use vpn_ip_tracker::{
+    matching::{IfaceMatcher, MatchMode},
+    TrackerConfig, EXIT_TOKEN_REJECTED,
+};
+
+mod dns;
+mod egress;
+mod events;
+mod http;
+mod monitor;
+mod mqtt;
+#[cfg(target_os = "linux")]
+mod netlink;
+mod procfs;
+mod retry;
+mod routes;
+mod sinks;
+mod stun;
+mod sysfs;
+#[cfg(test)]
+mod testing;
+mod tracker;
 mod utils;
 
 #[derive(Parser)]
@@ -13,21 +41,38 @@ mod utils;
 struct Cli {
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
+    /// Report all interfaces even if they did not change since the last run
+    #[arg(long, default_value_t = false)]
+    force_report: bool,
 }
 
 #[derive(Debug)]
 enum AppError {
     ConfigInvalid,
+    MatchRulesInvalid,
+    SinksInvalid,
+    TokenRejected,
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        // Let the service manager know that restarting does not help
+        Err(AppError::TokenRejected) => ExitCode::from(EXIT_TOKEN_REJECTED),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn main() -> Result<(), AppError> {
+fn run() -> Result<(), AppError> {
     let args = Cli::parse();
 
     if args.verbose {
         env_logger::init();
     }
 
-    let mut stored_iface: Option<IfaceInfo> = None;
     let config = TrackerConfig::load();
 
     if config.is_none() {
@@ -35,80 +80,147 @@ fn main() -> Result<(), AppError> {
     }
 
     let config = config.unwrap();
-    let client = reqwest::blocking::Client::builder()
-        .timeout(core::time::Duration::from_secs(10))
-        .build()
-        .unwrap();
-
-    loop {
-        let net = IfCfg::get().expect("Unable to get network interface info");
-
-        for iface in net
-            .into_iter()
-            .filter(|it_iface| vpn_iface_name_check(it_iface) && vpn_iface_ipv4_check(it_iface))
-        {
-            if let Ok(ser_iface) = IfaceInfo::try_from(iface) {
-                if stored_iface.is_none() || stored_iface.as_ref().unwrap() != &ser_iface {
-                    match send_report(client.clone(), &ser_iface, &config) {
-                        Ok(_) => {
-                            debug!("Successfully report");
-                            stored_iface = Some(ser_iface);
-                            debug!("{:?}", &stored_iface);
-                        }
-                        Err(e) => warn!("Failed to send report: {}", e),
-                    }
-
-                    break;
-                }
-            }
-        }
-
-        std::thread::sleep(std::time::Duration::from_secs(30));
+    let matcher = IfaceMatcher::new(&config.iface_match).map_err(|e| {
+        error!("Invalid interface match rules: {}", e);
+        AppError::MatchRulesInvalid
+    })?;
+    let sinks = config.sinks().map_err(|e| {
+        error!("Invalid sinks: {}", e);
+        AppError::SinksInvalid
+    })?;
+
+    if sinks.is_empty() {
+        error!("No sinks to report to, set the report URL or add sinks");
+        return Err(AppError::SinksInvalid);
     }
+
+    let mut source =
+        monitor::event_source(&config.monitor, config.iface_match.mode == MatchMode::Route);
+    let mut scanner = SystemScanner {
+        matcher,
+        sysfs: SysfsNet::default(),
+        procfs: ProcNet::default(),
+        egress: config
+            .egress
+            .enabled
+            .then(|| EgressProbe::new(&config.egress)),
+        config: &config,
+    };
+    let state_path = vpn_ip_tracker::state_dir().map(|dir| dir.join(events::STATE_FILE));
+    let reported = match &state_path {
+        Some(path) if args.force_report => IfaceStates::empty(path),
+        Some(path) => IfaceStates::open(path).unwrap_or_else(|e| {
+            warn!(
+                "Discard unreadable reported state {}: {}",
+                path.display(),
+                e
+            );
+            IfaceStates::empty(path)
+        }),
+        None => IfaceStates::default(),
+    };
+    let sinks = sinks::create(sinks, &config);
+
+    let heartbeat =
+        (config.heartbeat_interval > 0).then(|| Duration::from_secs(config.heartbeat_interval));
+
+    Tracker::new(
+        reported,
+        sinks,
+        utils::hostname(),
+        config.device.clone(),
+        heartbeat,
+    )
+    .run(source.as_mut(), &mut scanner)
+    .map_err(|e| {
+        error!("Stop tracking, no sink accepts its token: {}", e);
+        AppError::TokenRejected
+    })
 }
 
-fn send_report(
-    client: reqwest::blocking::Client,
-    iface: &IfaceInfo,
-    config: &TrackerConfig,
-) -> reqwest::Result<()> {
-    let data = iface.ip.to_string();
-    let headers = prepare_headers(config.token.clone());
-    let url = config.report_url.clone();
-
-    client
-        .post(url)
-        .headers(headers)
-        .body(data)
-        .send()?
-        .error_for_status()?;
-
-    Ok(())
+/// Scanner of the VPN interfaces available in the system
+struct SystemScanner<'a> {
+    matcher: IfaceMatcher,
+    sysfs: SysfsNet,
+    procfs: ProcNet,
+    egress: Option<EgressProbe>,
+    config: &'a TrackerConfig,
 }
 
-fn prepare_headers(token: String) -> reqwest::header::HeaderMap {
-    let mut header = reqwest::header::HeaderMap::new();
-    let mut token = reqwest::header::HeaderValue::from_str(&token).unwrap();
+impl IfaceScanner for SystemScanner<'_> {
+    /// Collect all tracked VPN interfaces that have at least one tracked address
+    fn scan(&mut self) -> Vec<IfaceInfo> {
+        let net = IfCfg::get().expect("Unable to get network interface info");
+        let inet6 = self.procfs.inet6_addrs();
+        // The route table is read at most once per scan, only to select the interfaces by route
+        // or to find the tunnel mode of a tracked interface
+        let mut routes = (self.config.iface_match.mode == MatchMode::Route)
+            .then(|| RouteTable::read(&self.procfs));
+        let candidates: Vec<IfCfg> = match &routes {
+            Some(routes) => {
+                let route_ifaces = match self.matcher.route_prefix() {
+                    Some(prefix) => routes.route_to(&prefix).into_iter().collect(),
+                    None => routes.default_routes(self.config.addresses.family),
+                };
+
+                net.into_iter()
+                    .filter(|it_iface| route_ifaces.contains(&it_iface.name.as_str()))
+                    .collect()
+            }
+            None => net
+                .into_iter()
+                .filter(|it_iface| vpn_iface_name_check(&self.matcher, &self.sysfs, it_iface))
+                .collect(),
+        };
 
-    token.set_sensitive(true);
-    header.insert("Credential", token);
+        let snapshot: Vec<IfaceInfo> = candidates
+            .into_iter()
+            .filter_map(|iface| IfaceInfo::try_from(iface).ok())
+            .filter_map(|mut ser_iface| {
+                ser_iface.retain_addresses(&self.config.addresses, &inet6);
+                ser_iface.read_link_info(&self.sysfs);
+
+                if !ser_iface.up && !self.config.iface_match.include_down {
+                    debug!("Skip {} as it is not up", ser_iface.name);
+                    return None;
+                }
 
-    header
-}
+                if ser_iface.is_empty() {
+                    return None;
+                }
 
-#[cfg(unix)]
-fn vpn_iface_name_check(iface: &IfCfg) -> bool {
-    iface.name.starts_with("tun")
-}
+                let routes = routes.get_or_insert_with(|| RouteTable::read(&self.procfs));
+
+                ser_iface.tunnel = Some(routes.tunnel_mode(&ser_iface.name));
+                Some(ser_iface)
+            })
+            .collect();
+
+        match self.egress.as_mut() {
+            Some(egress) => {
+                egress.retain(&snapshot);
+                snapshot
+                    .into_iter()
+                    .map(|mut iface| {
+                        iface.egress = egress.egress_ip(&iface);
+                        iface
+                    })
+                    .collect()
+            }
+            None => snapshot,
+        }
+    }
 
-#[cfg(windows)]
-fn vpn_iface_name_check(iface: &IfCfg) -> bool {
-    iface.name.contains("OpenVPN TAP")
+    /// Egress IP addresses being probed are reported once the probes finish
+    fn rescan_delay(&self) -> Option<Duration> {
+        self.egress.as_ref().and_then(EgressProbe::poll_delay)
+    }
 }
 
-fn vpn_iface_ipv4_check(iface: &IfCfg) -> bool {
-    iface
-        .addresses
-        .iter()
-        .any(|addr| matches!(addr.address_family, ifcfg::AddressFamily::IPv4))
+fn vpn_iface_name_check(matcher: &IfaceMatcher, sysfs: &SysfsNet, iface: &IfCfg) -> bool {
+    matcher.is_match(
+        &iface.name,
+        utils::iface_index(&iface.name),
+        sysfs.link_kind(&iface.name),
+    )
 }
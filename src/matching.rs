@@ -0,0 +1,416 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Interface matching rules
+//!
+//! Rules are evaluated in the order they are listed in the configuration. The first rule that
+//! matches an interface decides whether the interface is tracked, interfaces that match no rule
+//! are ignored.
+use std::{net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Errors that can occur while compiling interface matching rules
+#[derive(Debug, Error)]
+pub enum MatchError {
+    #[error("invalid glob pattern")]
+    Glob(#[from] glob::PatternError),
+    #[error("invalid regular expression")]
+    Regex(#[from] regex::Error),
+    #[error("invalid route prefix")]
+    Prefix,
+}
+
+/// Way the tracked VPN interfaces are selected
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Interfaces are selected by the matching rules
+    #[default]
+    Rules,
+    /// Interface that holds the route to `route_prefix` or the default route is selected
+    Route,
+}
+
+/// IP network prefix, e.g. `10.20.0.0/16`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl IpPrefix {
+    /// Check whether `addr` belongs to the prefix
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = MatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s.split_once('/').ok_or(MatchError::Prefix)?;
+        let addr: IpAddr = addr.parse().map_err(|_| MatchError::Prefix)?;
+        let len: u8 = len.parse().map_err(|_| MatchError::Prefix)?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        if len > max_len {
+            return Err(MatchError::Prefix);
+        }
+
+        Ok(Self { addr, len })
+    }
+}
+
+/// Action applied to an interface matched by a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Include,
+    Exclude,
+}
+
+/// Kind of the network link as detected from the OS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    /// Layer 3 TUN device
+    Tun,
+    /// Layer 2 TAP device
+    Tap,
+    /// WireGuard device
+    Wireguard,
+    /// Point-to-point protocol device
+    Ppp,
+    /// IP-in-IP, GRE or SIT tunnel
+    IpTunnel,
+    /// Plain ethernet device
+    Ethernet,
+    /// Loopback device
+    Loopback,
+    /// Any other link
+    Other,
+}
+
+impl LinkKind {
+    /// Check whether the link kind is a tunnel device
+    pub fn is_tunnel(&self) -> bool {
+        matches!(
+            self,
+            LinkKind::Tun
+                | LinkKind::Tap
+                | LinkKind::Wireguard
+                | LinkKind::Ppp
+                | LinkKind::IpTunnel
+        )
+    }
+}
+
+/// Interface pattern a rule is matched against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IfacePattern {
+    /// Exact interface name
+    Name(String),
+    /// Shell-style glob over the interface name, e.g. `tun*`
+    Glob(String),
+    /// Regular expression over the interface name
+    Regex(String),
+    /// Interface index
+    Index(u32),
+    /// Detected link kind
+    Kind(LinkKind),
+    /// Any tunnel device if `true`, any non-tunnel device if `false`
+    Tunnel(bool),
+}
+
+/// Single interface matching rule
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchRule {
+    /// What to do with an interface matched by this rule
+    pub action: RuleAction,
+    /// Pattern the interface is matched against
+    #[serde(flatten)]
+    pub pattern: IfacePattern,
+}
+
+impl MatchRule {
+    /// Create new rule that includes interfaces matched by `pattern`
+    pub fn include(pattern: IfacePattern) -> Self {
+        Self {
+            action: RuleAction::Include,
+            pattern,
+        }
+    }
+
+    /// Create new rule that excludes interfaces matched by `pattern`
+    pub fn exclude(pattern: IfacePattern) -> Self {
+        Self {
+            action: RuleAction::Exclude,
+            pattern,
+        }
+    }
+}
+
+/// `match` section of the tracker configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchConfig {
+    /// Track interfaces that are configured but down or have no carrier
+    #[serde(default)]
+    pub include_down: bool,
+    /// Interface selection mode
+    #[serde(default)]
+    pub mode: MatchMode,
+    /// Destination prefix the route mode looks for, the default route is used if it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route_prefix: Option<String>,
+    /// Ordered list of matching rules
+    #[serde(default = "default_rules")]
+    pub rules: Vec<MatchRule>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self {
+            include_down: false,
+            mode: MatchMode::Rules,
+            route_prefix: None,
+            rules: default_rules(),
+        }
+    }
+}
+
+/// Built-in rules that select the interfaces of the common VPN clients
+#[cfg(unix)]
+fn default_rules() -> Vec<MatchRule> {
+    vec![MatchRule::include(IfacePattern::Glob("tun*".into()))]
+}
+
+/// Built-in rules that select the interfaces of the common VPN clients
+#[cfg(windows)]
+fn default_rules() -> Vec<MatchRule> {
+    vec![MatchRule::include(IfacePattern::Glob(
+        "*OpenVPN TAP*".into(),
+    ))]
+}
+
+enum CompiledPattern {
+    Name(String),
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+    Index(u32),
+    Kind(LinkKind),
+    Tunnel(bool),
+}
+
+impl CompiledPattern {
+    fn matches(&self, name: &str, index: Option<u32>, kind: Option<LinkKind>) -> bool {
+        match self {
+            CompiledPattern::Name(exp_name) => exp_name == name,
+            CompiledPattern::Glob(pattern) => pattern.matches(name),
+            CompiledPattern::Regex(regex) => regex.is_match(name),
+            CompiledPattern::Index(exp_index) => index == Some(*exp_index),
+            CompiledPattern::Kind(exp_kind) => kind == Some(*exp_kind),
+            CompiledPattern::Tunnel(tunnel) => kind.is_some_and(|kind| kind.is_tunnel() == *tunnel),
+        }
+    }
+}
+
+impl TryFrom<&IfacePattern> for CompiledPattern {
+    type Error = MatchError;
+
+    fn try_from(value: &IfacePattern) -> Result<Self, Self::Error> {
+        Ok(match value {
+            IfacePattern::Name(name) => CompiledPattern::Name(name.clone()),
+            IfacePattern::Glob(pattern) => CompiledPattern::Glob(glob::Pattern::new(pattern)?),
+            IfacePattern::Regex(regex) => CompiledPattern::Regex(regex::Regex::new(regex)?),
+            IfacePattern::Index(index) => CompiledPattern::Index(*index),
+            IfacePattern::Kind(kind) => CompiledPattern::Kind(*kind),
+            IfacePattern::Tunnel(tunnel) => CompiledPattern::Tunnel(*tunnel),
+        })
+    }
+}
+
+/// Compiled set of interface matching rules
+pub struct IfaceMatcher {
+    rules: Vec<(RuleAction, CompiledPattern)>,
+    route_prefix: Option<IpPrefix>,
+}
+
+impl IfaceMatcher {
+    /// Compile matching rules from the configuration
+    pub fn new(config: &MatchConfig) -> Result<Self, MatchError> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| Ok((rule.action, CompiledPattern::try_from(&rule.pattern)?)))
+            .collect::<Result<_, MatchError>>()?;
+        let route_prefix = config
+            .route_prefix
+            .as_deref()
+            .map(IpPrefix::from_str)
+            .transpose()?;
+
+        Ok(Self {
+            rules,
+            route_prefix,
+        })
+    }
+
+    /// Destination prefix used by the route mode
+    pub fn route_prefix(&self) -> Option<IpPrefix> {
+        self.route_prefix
+    }
+
+    /// Check whether interface with `name` and `index` should be tracked
+    /// Arguments:
+    /// - `name` - interface name
+    /// - `index` - interface index if it is known
+    /// - `kind` - link kind if it could be detected
+    pub fn is_match(&self, name: &str, index: Option<u32>, kind: Option<LinkKind>) -> bool {
+        self.rules
+            .iter()
+            .find(|(_, pattern)| pattern.matches(name, index, kind))
+            .is_some_and(|(action, _)| *action == RuleAction::Include)
+    }
+}
+
+#[cfg(test)]
+mod matching_tests {
+    use crate::config_tests::assert_round_trip;
+    use crate::matching::{
+        IfaceMatcher, IfacePattern, IpPrefix, LinkKind, MatchConfig, MatchMode, MatchRule,
+    };
+    use crate::TrackerConfig;
+
+    fn matcher(rules: Vec<MatchRule>) -> IfaceMatcher {
+        IfaceMatcher::new(&MatchConfig {
+            rules,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_default_rules() {
+        let matcher = IfaceMatcher::new(&MatchConfig::default()).unwrap();
+
+        assert!(matcher.is_match("tun0", None, None));
+        assert!(matcher.is_match("tun12", Some(5), None));
+        assert!(!matcher.is_match("eth0", None, None));
+        assert!(!matcher.is_match("wg0", None, None));
+    }
+
+    #[test]
+    fn test_rule_kinds() {
+        let matcher = matcher(vec![
+            MatchRule::include(IfacePattern::Name("corp-vpn".into())),
+            MatchRule::include(IfacePattern::Glob("wg*".into())),
+            MatchRule::include(IfacePattern::Regex("^(tap|ppp)[0-9]+$".into())),
+            MatchRule::include(IfacePattern::Index(42)),
+        ]);
+
+        assert!(matcher.is_match("corp-vpn", None, None));
+        assert!(!matcher.is_match("corp-vpn2", None, None));
+        assert!(matcher.is_match("wg0", None, None));
+        assert!(matcher.is_match("tap1", None, None));
+        assert!(matcher.is_match("ppp0", None, None));
+        assert!(!matcher.is_match("ppp", None, None));
+        assert!(matcher.is_match("eth7", Some(42), None));
+        assert!(!matcher.is_match("eth7", Some(7), None));
+    }
+
+    #[test]
+    fn test_first_rule_wins() {
+        let matcher = matcher(vec![
+            MatchRule::exclude(IfacePattern::Name("tun9".into())),
+            MatchRule::include(IfacePattern::Glob("tun*".into())),
+            MatchRule::exclude(IfacePattern::Name("tun0".into())),
+        ]);
+
+        assert!(matcher.is_match("tun0", None, None));
+        assert!(!matcher.is_match("tun9", None, None));
+    }
+
+    #[test]
+    fn test_link_kind_rules() {
+        let matcher = matcher(vec![
+            MatchRule::exclude(IfacePattern::Kind(LinkKind::Tap)),
+            MatchRule::include(IfacePattern::Tunnel(true)),
+        ]);
+
+        assert!(matcher.is_match("corp-vpn", None, Some(LinkKind::Tun)));
+        assert!(matcher.is_match("wg0", None, Some(LinkKind::Wireguard)));
+        assert!(matcher.is_match("gre1", None, Some(LinkKind::IpTunnel)));
+        assert!(!matcher.is_match("tap0", None, Some(LinkKind::Tap)));
+        assert!(!matcher.is_match("eth0", None, Some(LinkKind::Ethernet)));
+        assert!(!matcher.is_match("tun0", None, None));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let invalid_glob = MatchConfig {
+            rules: vec![MatchRule::include(IfacePattern::Glob("tun[".into()))],
+            ..Default::default()
+        };
+        let invalid_regex = MatchConfig {
+            rules: vec![MatchRule::include(IfacePattern::Regex("tun(".into()))],
+            ..Default::default()
+        };
+
+        assert!(IfaceMatcher::new(&invalid_glob).is_err());
+        assert!(IfaceMatcher::new(&invalid_regex).is_err());
+    }
+
+    #[test]
+    fn test_ip_prefix() {
+        let v4: IpPrefix = "10.20.0.0/16".parse().unwrap();
+        let v6: IpPrefix = "fd00:1::/32".parse().unwrap();
+        let any: IpPrefix = "0.0.0.0/0".parse().unwrap();
+
+        assert!(v4.contains(&"10.20.3.4".parse().unwrap()));
+        assert!(!v4.contains(&"10.21.3.4".parse().unwrap()));
+        assert!(!v4.contains(&"fd00:1::1".parse().unwrap()));
+        assert!(v6.contains(&"fd00:1:0:5::1".parse().unwrap()));
+        assert!(!v6.contains(&"fd00:2::1".parse().unwrap()));
+        assert!(any.contains(&"192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+        assert!("10.0.0.0".parse::<IpPrefix>().is_err());
+        assert!(IfaceMatcher::new(&MatchConfig {
+            route_prefix: Some("10.0.0/8".into()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_load_match_section() {
+        assert_round_trip(
+            "match",
+            TrackerConfig {
+                iface_match: MatchConfig {
+                    include_down: true,
+                    mode: MatchMode::Route,
+                    route_prefix: Some("10.20.0.0/16".into()),
+                    rules: vec![
+                        MatchRule::exclude(IfacePattern::Name("tun9".into())),
+                        MatchRule::include(IfacePattern::Regex("^(wg|tun)[0-9]+$".into())),
+                        MatchRule::include(IfacePattern::Index(7)),
+                    ],
+                },
+                ..Default::default()
+            },
+        );
+    }
+}
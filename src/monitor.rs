@@ -0,0 +1,221 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::time::Duration;
+
+use log::warn;
+
+use vpn_ip_tracker::{MonitorConfig, MonitorMode};
+
+/// Reason the event source woke the tracker up
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Wakeup {
+    /// Network configuration changed
+    Changed,
+    /// Nothing happened during the poll interval
+    Timeout,
+}
+
+/// Source of the network configuration change notifications
+pub(crate) trait EventSource {
+    /// Block until the network configuration may have changed, but not longer than `limit`
+    /// Returns `None` if no more notifications will be delivered
+    fn wait(&mut self, limit: Option<Duration>) -> Option<Wakeup>;
+}
+
+/// Event source that wakes up after a fixed interval
+pub(crate) struct PollingSource {
+    interval: Duration,
+}
+
+impl PollingSource {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self { interval }
+    }
+}
+
+impl EventSource for PollingSource {
+    fn wait(&mut self, limit: Option<Duration>) -> Option<Wakeup> {
+        std::thread::sleep(timeout(self.interval, limit));
+        Some(Wakeup::Timeout)
+    }
+}
+
+/// Time to wait for the next wakeup, the poll `interval` shortened to `limit`
+fn timeout(interval: Duration, limit: Option<Duration>) -> Duration {
+    limit.map_or(interval, |limit| limit.min(interval))
+}
+
+/// Create event source according to the configuration
+/// Arguments:
+/// - `config` - monitoring configuration
+/// - `routes` - route changes select the interfaces too, so they wake the tracker up
+pub(crate) fn event_source(config: &MonitorConfig, routes: bool) -> Box<dyn EventSource> {
+    let interval = Duration::from_secs(config.poll_interval);
+
+    if config.mode == MonitorMode::Netlink {
+        #[cfg(target_os = "linux")]
+        match netlink::NetlinkSource::new(interval, routes) {
+            Ok(source) => return Box::new(source),
+            Err(e) => warn!(
+                "Netlink monitoring is not available, fall back to polling: {}",
+                e
+            ),
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        warn!("Netlink monitoring is not supported, fall back to polling");
+    }
+
+    Box::new(PollingSource::new(interval))
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::{
+        io,
+        os::fd::{AsRawFd, OwnedFd},
+        time::Duration,
+    };
+
+    use log::{debug, warn};
+
+    use super::{timeout, EventSource, Wakeup};
+    use crate::netlink;
+
+    /// Event source that listens to the rtnetlink link, address and optionally route
+    /// notifications
+    pub(crate) struct NetlinkSource {
+        fd: OwnedFd,
+        interval: Duration,
+    }
+
+    impl NetlinkSource {
+        /// Subscribe to the link and address notifications
+        /// Arguments:
+        /// - `interval` - the longest time to wait for a notification
+        /// - `routes` - subscribe to the route notifications as well
+        pub(crate) fn new(interval: Duration, routes: bool) -> io::Result<Self> {
+            let mut groups =
+                libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR;
+
+            if routes {
+                groups |= libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE;
+            }
+
+            let fd = netlink::socket(groups as u32)?;
+
+            Ok(Self { fd, interval })
+        }
+
+        /// Read all pending notifications and count the link and address changes
+        fn drain(&self) -> usize {
+            let mut buf = [0u8; 8192];
+            let mut changes = 0;
+
+            loop {
+                // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+                let len = unsafe {
+                    libc::recv(
+                        self.fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        libc::MSG_DONTWAIT,
+                    )
+                };
+
+                if len <= 0 {
+                    break;
+                }
+
+                changes += count_changes(&buf[..len as usize]);
+            }
+
+            changes
+        }
+    }
+
+    impl EventSource for NetlinkSource {
+        fn wait(&mut self, limit: Option<Duration>) -> Option<Wakeup> {
+            let interval = timeout(self.interval, limit);
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = interval.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            // SAFETY: `pollfd` is a single valid entry
+            let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+
+            match ret {
+                0 => Some(Wakeup::Timeout),
+                ret if ret < 0 => {
+                    let e = io::Error::last_os_error();
+
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        warn!("Failed to wait for netlink notifications: {}", e);
+                        std::thread::sleep(interval);
+                    }
+
+                    Some(Wakeup::Timeout)
+                }
+                _ => {
+                    let changes = self.drain();
+
+                    debug!("Received {} netlink notifications", changes);
+                    Some(if changes > 0 {
+                        Wakeup::Changed
+                    } else {
+                        Wakeup::Timeout
+                    })
+                }
+            }
+        }
+    }
+
+    /// Count link, address and route notifications in the netlink datagram
+    /// Route notifications arrive only if the source subscribed to them
+    fn count_changes(buf: &[u8]) -> usize {
+        netlink::messages(buf)
+            .into_iter()
+            .filter(|(msg_type, _)| {
+                matches!(
+                    *msg_type,
+                    libc::RTM_NEWLINK
+                        | libc::RTM_DELLINK
+                        | libc::RTM_NEWADDR
+                        | libc::RTM_DELADDR
+                        | libc::RTM_NEWROUTE
+                        | libc::RTM_DELROUTE
+                )
+            })
+            .count()
+    }
+
+    #[cfg(test)]
+    mod netlink_tests {
+        use super::count_changes;
+
+        fn message(msg_type: u16, payload_len: usize) -> Vec<u8> {
+            let len = (16 + payload_len) as u32;
+            let mut msg = Vec::new();
+
+            msg.extend_from_slice(&len.to_ne_bytes());
+            msg.extend_from_slice(&msg_type.to_ne_bytes());
+            msg.extend_from_slice(&[0u8; 10]);
+            msg.resize(((len as usize) + 3) & !3, 0);
+
+            msg
+        }
+
+        #[test]
+        fn test_count_changes() {
+            let mut buf = message(libc::RTM_NEWADDR, 6);
+
+            buf.extend(message(libc::RTM_NEWROUTE, 12));
+            buf.extend(message(libc::RTM_NEWNEIGH, 8));
+            buf.extend(message(libc::RTM_DELLINK, 16));
+
+            assert_eq!(count_changes(&buf), 3);
+            assert_eq!(count_changes(&buf[..10]), 0);
+        }
+    }
+}
@@ -0,0 +1,531 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Minimal MQTT client (3.1.1 and 5) that publishes messages without subscribing
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use vpn_ip_tracker::sink::MqttVersion;
+
+pub(crate) const CONNECT: u8 = 1;
+pub(crate) const CONNACK: u8 = 2;
+pub(crate) const PUBLISH: u8 = 3;
+pub(crate) const PUBACK: u8 = 4;
+pub(crate) const PUBREC: u8 = 5;
+pub(crate) const PUBREL: u8 = 6;
+pub(crate) const PUBCOMP: u8 = 7;
+pub(crate) const PINGREQ: u8 = 12;
+pub(crate) const PINGRESP: u8 = 13;
+pub(crate) const DISCONNECT: u8 = 14;
+/// Clean session flag of the connect packet
+const FLAG_CLEAN: u8 = 0x02;
+const FLAG_WILL: u8 = 0x04;
+const FLAG_WILL_RETAIN: u8 = 0x20;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_USERNAME: u8 = 0x80;
+/// Time to wait for the broker to respond
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Errors that can occur while talking to the MQTT broker
+#[derive(Debug, Error)]
+pub(crate) enum MqttError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid MQTT broker URL '{0}'")]
+    Url(String),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("malformed MQTT packet")]
+    Malformed,
+    #[error("connection refused by MQTT broker with code {0:#04x}")]
+    Refused(u8),
+    #[error("message rejected by MQTT broker with code {0:#04x}")]
+    Rejected(u8),
+    #[error("disconnected by MQTT broker")]
+    Disconnected,
+}
+
+/// Application message
+#[derive(Debug, Clone)]
+pub(crate) struct Message {
+    pub(crate) topic: String,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) qos: u8,
+    pub(crate) retain: bool,
+}
+
+/// Settings of the broker connection
+pub(crate) struct Options<'a> {
+    pub(crate) version: MqttVersion,
+    pub(crate) client_id: &'a str,
+    pub(crate) username: Option<&'a str>,
+    pub(crate) password: Option<&'a str>,
+    /// PEM file with the CA certificate trusted in addition to the system ones
+    pub(crate) ca_cert: Option<&'a Path>,
+    /// Longest silence of the client the broker tolerates, zero disables the check
+    pub(crate) keep_alive: Duration,
+    /// Message the broker publishes when the connection is lost
+    pub(crate) will: Option<&'a Message>,
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Open session with the broker
+pub(crate) struct Connection {
+    stream: Box<dyn Stream>,
+    version: MqttVersion,
+    packet_id: u16,
+}
+
+impl Connection {
+    /// Connect to the broker
+    /// Arguments:
+    /// - `url` - broker URL, `mqtt://host:port` or `mqtts://host:port` for TLS
+    /// - `options` - connection settings
+    pub(crate) fn open(url: &str, options: &Options) -> Result<Self, MqttError> {
+        let (host, port, tls) = parse_url(url).ok_or_else(|| MqttError::Url(url.into()))?;
+        let addr = (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| MqttError::Url(url.into()))?;
+        let tcp = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+
+        let stream: Box<dyn Stream> = if tls {
+            Box::new(tls_stream(&host, tcp, options.ca_cert)?)
+        } else {
+            Box::new(tcp)
+        };
+        let mut connection = Self {
+            stream,
+            version: options.version,
+            packet_id: 0,
+        };
+
+        write_packet(&mut connection.stream, CONNECT << 4, &connect(options))?;
+
+        match connection.expect(CONNACK)?.get(1) {
+            Some(0) => Ok(connection),
+            Some(code) => Err(MqttError::Refused(*code)),
+            None => Err(MqttError::Malformed),
+        }
+    }
+
+    /// Publish `message` and wait for its acknowledgement as required by its QoS
+    pub(crate) fn publish(&mut self, message: &Message) -> Result<(), MqttError> {
+        let mut packet = Vec::new();
+        let id = self.next_packet_id();
+
+        put_bytes(&mut packet, message.topic.as_bytes());
+
+        if message.qos > 0 {
+            packet.extend_from_slice(&id.to_be_bytes());
+        }
+
+        if self.version == MqttVersion::V5 {
+            // No properties
+            packet.push(0);
+        }
+
+        packet.extend_from_slice(&message.payload);
+
+        let header = PUBLISH << 4 | message.qos << 1 | u8::from(message.retain);
+
+        write_packet(&mut self.stream, header, &packet)?;
+
+        match message.qos {
+            0 => Ok(()),
+            1 => self.acknowledged(PUBACK, id),
+            _ => {
+                self.acknowledged(PUBREC, id)?;
+                write_packet(&mut self.stream, PUBREL << 4 | 0x02, &id.to_be_bytes())?;
+                self.acknowledged(PUBCOMP, id)
+            }
+        }
+    }
+
+    /// Tell the broker the client is still alive
+    pub(crate) fn ping(&mut self) -> Result<(), MqttError> {
+        write_packet(&mut self.stream, PINGREQ << 4, &[])?;
+        self.expect(PINGRESP).map(|_| ())
+    }
+
+    /// Close the session, the broker discards the last will
+    pub(crate) fn disconnect(&mut self) -> Result<(), MqttError> {
+        write_packet(&mut self.stream, DISCONNECT << 4, &[])
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        // Zero is not a valid packet id
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        self.packet_id
+    }
+
+    /// Wait for the packet of `kind`, other packets are skipped
+    fn expect(&mut self, kind: u8) -> Result<Vec<u8>, MqttError> {
+        loop {
+            match read_packet(&mut self.stream)? {
+                (header, body) if header >> 4 == kind => return Ok(body),
+                (header, _) if header >> 4 == DISCONNECT => return Err(MqttError::Disconnected),
+                _ => (),
+            }
+        }
+    }
+
+    /// Wait for the acknowledgement of `kind` of the packet `id`
+    /// MQTT 5 brokers may add a reason code, the failure ones start at 0x80
+    fn acknowledged(&mut self, kind: u8, id: u16) -> Result<(), MqttError> {
+        loop {
+            let body = self.expect(kind)?;
+
+            if body.len() < 2 {
+                return Err(MqttError::Malformed);
+            }
+
+            if body[..2] != id.to_be_bytes() {
+                continue;
+            }
+
+            return match body.get(2) {
+                Some(code) if *code >= 0x80 => Err(MqttError::Rejected(*code)),
+                _ => Ok(()),
+            };
+        }
+    }
+}
+
+/// Get host, port and TLS flag of the broker `url`
+fn parse_url(url: &str) -> Option<(String, u16, bool)> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let tls = match url.scheme() {
+        "mqtt" | "tcp" => false,
+        "mqtts" | "ssl" => true,
+        _ => return None,
+    };
+    let host = url
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+
+    Some((host.to_string(), port, tls))
+}
+
+fn tls_stream(
+    host: &str,
+    tcp: TcpStream,
+    ca_cert: Option<&Path>,
+) -> Result<native_tls::TlsStream<TcpStream>, MqttError> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(path) = ca_cert {
+        let cert = native_tls::Certificate::from_pem(&fs::read(path)?)
+            .map_err(|e| MqttError::Tls(e.to_string()))?;
+
+        builder.add_root_certificate(cert);
+    }
+
+    builder
+        .build()
+        .map_err(|e| MqttError::Tls(e.to_string()))?
+        .connect(host, tcp)
+        .map_err(handshake_error)
+}
+
+/// Error of a failed TLS handshake, I/O failures are kept apart from the certificate errors so
+/// that they are retried
+/// A failure without a cause is the connection closed by the broker, protocol and certificate
+/// errors always carry the error of the TLS library
+fn handshake_error(e: native_tls::HandshakeError<TcpStream>) -> MqttError {
+    let e = match e {
+        // The socket read timeout elapsed
+        native_tls::HandshakeError::WouldBlock(_) => {
+            return io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out").into()
+        }
+        native_tls::HandshakeError::Failure(e) => e,
+    };
+    let mut source = std::error::Error::source(&e);
+
+    if source.is_none() {
+        return io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string()).into();
+    }
+
+    while let Some(cause) = source {
+        if let Some(io) = cause.downcast_ref::<io::Error>() {
+            return io::Error::new(io.kind(), io.to_string()).into();
+        }
+
+        source = cause.source();
+    }
+
+    MqttError::Tls(e.to_string())
+}
+
+/// Build body of the connect packet
+fn connect(options: &Options) -> Vec<u8> {
+    let v5 = options.version == MqttVersion::V5;
+    let mut flags = FLAG_CLEAN;
+    let mut packet = Vec::new();
+
+    put_bytes(&mut packet, b"MQTT");
+    packet.push(if v5 { 5 } else { 4 });
+
+    if options.username.is_some() {
+        flags |= FLAG_USERNAME;
+    }
+
+    if options.password.is_some() {
+        flags |= FLAG_PASSWORD;
+    }
+
+    if let Some(will) = options.will {
+        flags |= FLAG_WILL | will.qos << 3;
+
+        if will.retain {
+            flags |= FLAG_WILL_RETAIN;
+        }
+    }
+
+    packet.push(flags);
+    packet.extend_from_slice(
+        &(options.keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes(),
+    );
+
+    if v5 {
+        packet.push(0);
+    }
+
+    put_bytes(&mut packet, options.client_id.as_bytes());
+
+    if let Some(will) = options.will {
+        if v5 {
+            packet.push(0);
+        }
+
+        put_bytes(&mut packet, will.topic.as_bytes());
+        put_bytes(&mut packet, &will.payload);
+    }
+
+    for field in [options.username, options.password].into_iter().flatten() {
+        put_bytes(&mut packet, field.as_bytes());
+    }
+
+    packet
+}
+
+/// Append `data` prefixed with its length
+fn put_bytes(packet: &mut Vec<u8>, data: &[u8]) {
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+/// Write control packet with the fixed `header` byte
+pub(crate) fn write_packet(
+    stream: &mut impl Write,
+    header: u8,
+    body: &[u8],
+) -> Result<(), MqttError> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+
+    loop {
+        let byte = (len % 128) as u8;
+
+        len /= 128;
+
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+
+        packet.push(byte | 0x80);
+    }
+
+    packet.extend_from_slice(body);
+    stream.write_all(&packet)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Read control packet
+/// Returns the fixed header byte and the rest of the packet
+pub(crate) fn read_packet(stream: &mut impl Read) -> Result<(u8, Vec<u8>), MqttError> {
+    let mut byte = [0u8; 1];
+    let mut len = 0;
+
+    stream.read_exact(&mut byte)?;
+
+    let header = byte[0];
+
+    for shift in [0, 7, 14, 21] {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; len];
+
+            stream.read_exact(&mut body)?;
+            return Ok((header, body));
+        }
+    }
+
+    Err(MqttError::Malformed)
+}
+
+#[cfg(test)]
+mod mqtt_tests {
+    use std::{
+        io::{Cursor, ErrorKind},
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use vpn_ip_tracker::sink::MqttVersion;
+
+    use crate::mqtt::{
+        connect, parse_url, read_packet, tls_stream, write_packet, Connection, Message, MqttError,
+        Options,
+    };
+    use crate::testing::MqttStandIn;
+
+    fn options(version: MqttVersion) -> Options<'static> {
+        Options {
+            version,
+            client_id: "tracker",
+            username: None,
+            password: None,
+            ca_cert: None,
+            keep_alive: Duration::from_secs(60),
+            will: None,
+        }
+    }
+
+    #[test]
+    fn test_tls_handshake_failures() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // The broker either never answers the handshake or closes the connection
+        let handshake = |close: bool| {
+            let tcp = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let server = listener.accept().unwrap();
+
+            if close {
+                drop(server);
+            }
+
+            tcp.set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            tls_stream("localhost", tcp, None).unwrap_err()
+        };
+
+        assert!(matches!(handshake(false), MqttError::Io(e) if e.kind() == ErrorKind::TimedOut));
+        assert!(matches!(handshake(true), MqttError::Io(_)));
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("mqtt://broker.lan"),
+            Some(("broker.lan".into(), 1883, false))
+        );
+        assert_eq!(
+            parse_url("mqtts://[::1]:8884"),
+            Some(("::1".into(), 8884, true))
+        );
+        assert_eq!(parse_url("http://broker.lan"), None);
+    }
+
+    #[test]
+    fn test_packet_length() {
+        let mut packet = Vec::new();
+
+        write_packet(&mut packet, 0x30, &[7u8; 321]).unwrap();
+
+        assert_eq!(packet[..3], [0x30, 0xc1, 0x02]);
+        assert_eq!(
+            read_packet(&mut Cursor::new(packet)).unwrap(),
+            (0x30, vec![7u8; 321])
+        );
+    }
+
+    #[test]
+    fn test_connect_packet() {
+        let will = Message {
+            topic: "t".into(),
+            payload: b"offline".to_vec(),
+            qos: 1,
+            retain: true,
+        };
+        let packet = connect(&Options {
+            username: Some("user"),
+            password: Some("pass"),
+            will: Some(&will),
+            ..options(MqttVersion::V311)
+        });
+
+        assert_eq!(packet[..10], [0, 4, b'M', b'Q', b'T', b'T', 4, 0xee, 0, 60]);
+        assert_eq!(
+            packet[10..],
+            *b"\0\x07tracker\0\x01t\0\x07offline\0\x04user\0\x04pass"
+        );
+        assert_eq!(
+            connect(&options(MqttVersion::V5))[6..11],
+            [5, 0x02, 0, 60, 0]
+        );
+    }
+
+    #[test]
+    fn test_publish() {
+        for version in [MqttVersion::V311, MqttVersion::V5] {
+            let broker = MqttStandIn::start(&[]);
+            let mut connection = Connection::open(&broker.url(), &options(version)).unwrap();
+
+            for qos in 0..3 {
+                connection
+                    .publish(&Message {
+                        topic: format!("state/{qos}"),
+                        payload: b"up".to_vec(),
+                        qos,
+                        retain: qos == 1,
+                    })
+                    .unwrap();
+            }
+
+            connection.ping().unwrap();
+            connection.disconnect().unwrap();
+
+            let record = broker.wait(|record| record.disconnects == 1);
+            let topics: Vec<&str> = record.messages.iter().map(|it| it.topic.as_str()).collect();
+
+            assert_eq!(
+                record.connects[0].level,
+                if version == MqttVersion::V5 { 5 } else { 4 }
+            );
+            assert_eq!(topics, ["state/0", "state/1", "state/2"]);
+            assert!(record.messages[1].retain && !record.messages[2].retain);
+            assert_eq!(record.messages[2].qos, 2);
+            assert_eq!(record.messages[2].payload, "up");
+            assert_eq!(record.pings, 1);
+        }
+    }
+
+    #[test]
+    fn test_refused() {
+        let broker = MqttStandIn::start(&[5, 0x86]);
+
+        for code in [5, 0x86] {
+            let error = Connection::open(&broker.url(), &options(MqttVersion::V5)).err();
+
+            assert!(matches!(error, Some(MqttError::Refused(refused)) if refused == code));
+        }
+    }
+}
@@ -0,0 +1,526 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Minimal rtnetlink client: notifications and dumps of the routes and routing rules
+use std::{
+    ffi::CStr,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use vpn_ip_tracker::matching::IpPrefix;
+
+use crate::procfs::Route;
+use crate::routes::RouteRule;
+
+pub(crate) const NLMSG_HDR_LEN: usize = mem::size_of::<libc::nlmsghdr>();
+/// Length of `rtmsg` and `fib_rule_hdr` that share the layout
+const RTMSG_LEN: usize = 12;
+const RTA_HDR_LEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+/// Receive buffer that fits the largest dump message the kernel sends
+const DUMP_BUF_LEN: usize = 64 * 1024;
+
+const FRA_DST: u16 = 1;
+const FRA_SRC: u16 = 2;
+const FRA_IIFNAME: u16 = 3;
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FRA_OIFNAME: u16 = 17;
+const FRA_L3MDEV: u16 = 19;
+const FRA_UID_RANGE: u16 = 20;
+const FRA_IP_PROTO: u16 = 22;
+const FRA_SPORT_RANGE: u16 = 23;
+const FRA_DPORT_RANGE: u16 = 24;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x0000_0002;
+
+/// Open rtnetlink socket subscribed to the notification `groups`
+pub(crate) fn socket(groups: u32) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket creation, the result is checked below
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: `fd` is a freshly created socket that is owned by nobody else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: all-zero `sockaddr_nl` is a valid value
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = groups;
+
+    // SAFETY: `addr` is a valid `sockaddr_nl` and its size is passed along
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+/// Split netlink datagram into the types and payloads of its messages
+pub(crate) fn messages(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut messages = Vec::new();
+
+    while buf.len() >= NLMSG_HDR_LEN {
+        let msg_len = u32::from_ne_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let msg_type = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+
+        if msg_len < NLMSG_HDR_LEN || msg_len > buf.len() {
+            break;
+        }
+
+        messages.push((msg_type, &buf[NLMSG_HDR_LEN..msg_len]));
+
+        let aligned_len = (msg_len + 3) & !3;
+        buf = &buf[aligned_len.min(buf.len())..];
+    }
+
+    messages
+}
+
+/// Read unicast routes of all routing tables
+pub(crate) fn routes() -> io::Result<Vec<Route>> {
+    Ok(dump(libc::RTM_GETROUTE)?
+        .iter()
+        .filter_map(|payload| parse_route(payload, iface_name))
+        .collect())
+}
+
+/// Read routing policy rules that select a table
+pub(crate) fn rules() -> io::Result<Vec<RouteRule>> {
+    Ok(dump(libc::RTM_GETRULE)?
+        .iter()
+        .filter_map(|payload| parse_rule(payload))
+        .collect())
+}
+
+/// Request all objects of `msg_type` of all address families
+/// Returns payloads of the received messages
+fn dump(msg_type: u16) -> io::Result<Vec<Vec<u8>>> {
+    let fd = socket(0)?;
+    let len = NLMSG_HDR_LEN + RTMSG_LEN;
+    let mut request = Vec::with_capacity(len);
+
+    request.extend_from_slice(&(len as u32).to_ne_bytes());
+    request.extend_from_slice(&msg_type.to_ne_bytes());
+    request.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    // Sequence number and port id, the kernel fills in the latter
+    request.extend_from_slice(&1u32.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    // `AF_UNSPEC` header selects all families
+    request.resize(len, 0);
+
+    // SAFETY: `request` is valid for reads of `request.len()` bytes
+    let sent = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+        )
+    };
+
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut buf = vec![0u8; DUMP_BUF_LEN];
+    let mut payloads = Vec::new();
+
+    loop {
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+        let len = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        for (msg_type, payload) in messages(&buf[..len as usize]) {
+            match msg_type as libc::c_int {
+                libc::NLMSG_DONE => return Ok(payloads),
+                libc::NLMSG_ERROR => {
+                    let errno = payload
+                        .get(0..4)
+                        .map(|errno| i32::from_ne_bytes(errno.try_into().unwrap()))
+                        .unwrap_or_default();
+
+                    if errno != 0 {
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+                }
+                _ => payloads.push(payload.to_vec()),
+            }
+        }
+    }
+}
+
+/// Split attributes of a netlink message into their types and values
+fn attributes(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attributes = Vec::new();
+
+    while buf.len() >= RTA_HDR_LEN {
+        let attr_len = u16::from_ne_bytes(buf[0..2].try_into().unwrap()) as usize;
+        let attr_type = u16::from_ne_bytes(buf[2..4].try_into().unwrap());
+
+        if attr_len < RTA_HDR_LEN || attr_len > buf.len() {
+            break;
+        }
+
+        attributes.push((attr_type & NLA_TYPE_MASK, &buf[RTA_HDR_LEN..attr_len]));
+
+        let aligned_len = (attr_len + 3) & !3;
+        buf = &buf[aligned_len.min(buf.len())..];
+    }
+
+    attributes
+}
+
+fn attr_u32(value: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(value.get(0..4)?.try_into().ok()?))
+}
+
+fn attr_addr(family: u8, value: &[u8]) -> Option<IpAddr> {
+    match family as libc::c_int {
+        libc::AF_INET => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(value).ok()?))),
+        libc::AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(value).ok()?,
+        ))),
+        _ => None,
+    }
+}
+
+fn unspecified(family: u8) -> Option<IpAddr> {
+    match family as libc::c_int {
+        libc::AF_INET => Some(Ipv4Addr::UNSPECIFIED.into()),
+        libc::AF_INET6 => Some(Ipv6Addr::UNSPECIFIED.into()),
+        _ => None,
+    }
+}
+
+/// Parse `RTM_NEWROUTE` payload, only unicast routes through an interface are kept
+/// Arguments:
+/// - `payload` - `rtmsg` with the route attributes
+/// - `name` - lookup of the interface name by its index
+fn parse_route(payload: &[u8], name: impl Fn(u32) -> Option<String>) -> Option<Route> {
+    let header = payload.get(..RTMSG_LEN)?;
+    let family = header[0];
+    let flags = u32::from_ne_bytes(header[8..12].try_into().unwrap());
+
+    if header[7] != libc::RTN_UNICAST || flags & libc::RTM_F_CLONED != 0 {
+        return None;
+    }
+
+    let mut dest = unspecified(family)?;
+    let mut table = u32::from(header[4]);
+    let mut metric = 0;
+    let mut oif = None;
+
+    for (attr_type, value) in attributes(&payload[RTMSG_LEN..]) {
+        match attr_type {
+            libc::RTA_DST => dest = attr_addr(family, value)?,
+            libc::RTA_OIF => oif = attr_u32(value),
+            libc::RTA_PRIORITY => metric = attr_u32(value)?,
+            libc::RTA_TABLE => table = attr_u32(value)?,
+            _ => (),
+        }
+    }
+
+    let name = name(oif?).filter(|name| name != "lo")?;
+
+    Some(Route {
+        name,
+        dest,
+        prefix_len: header[1],
+        metric,
+        table,
+    })
+}
+
+/// Parse `RTM_NEWRULE` payload
+/// Only the rules that look up a table are kept; rules with source, interface, user or port
+/// selectors are dropped as the tracker cannot tell whether the traffic matches them
+fn parse_rule(payload: &[u8]) -> Option<RouteRule> {
+    let header = payload.get(..RTMSG_LEN)?;
+    let family = header[0];
+    let flags = u32::from_ne_bytes(header[8..12].try_into().unwrap());
+
+    // Source length and TOS
+    if header[7] != FR_ACT_TO_TBL || header[2] != 0 || header[3] != 0 {
+        return None;
+    }
+
+    unspecified(family)?;
+
+    let mut rule = RouteRule {
+        invert: flags & FIB_RULE_INVERT != 0,
+        ..RouteRule::lookup(
+            0,
+            family as libc::c_int == libc::AF_INET,
+            u32::from(header[4]),
+        )
+    };
+
+    for (attr_type, value) in attributes(&payload[RTMSG_LEN..]) {
+        match attr_type {
+            FRA_DST => {
+                rule.dest = Some(IpPrefix {
+                    addr: attr_addr(family, value)?,
+                    len: header[1],
+                })
+            }
+            FRA_PRIORITY => rule.priority = attr_u32(value)?,
+            FRA_TABLE => rule.table = attr_u32(value)?,
+            FRA_FWMARK => rule.fwmark = attr_u32(value),
+            // -1 means no suppression
+            FRA_SUPPRESS_PREFIXLEN => rule.suppress_prefix_len = attr_u32(value)?.try_into().ok(),
+            FRA_SRC | FRA_IIFNAME | FRA_OIFNAME | FRA_L3MDEV | FRA_UID_RANGE | FRA_IP_PROTO
+            | FRA_SPORT_RANGE | FRA_DPORT_RANGE => return None,
+            _ => (),
+        }
+    }
+
+    Some(rule)
+}
+
+/// Get name of the interface with `index`
+fn iface_name(index: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IFNAMSIZ];
+
+    // SAFETY: `buf` holds `IFNAMSIZ` bytes as required by `if_indextoname`
+    let name = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+
+    if name.is_null() {
+        return None;
+    }
+
+    // SAFETY: `if_indextoname` wrote a NUL-terminated string into `buf`
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod netlink_tests {
+    use crate::netlink::{messages, parse_route, parse_rule, FRA_FWMARK, FRA_PRIORITY};
+    use crate::netlink::{FRA_SRC, FRA_SUPPRESS_PREFIXLEN, FRA_TABLE, NLMSG_HDR_LEN};
+    use crate::procfs::Route;
+    use crate::routes::RouteRule;
+
+    fn message(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let len = (NLMSG_HDR_LEN + payload.len()) as u32;
+        let mut msg = Vec::new();
+
+        msg.extend_from_slice(&len.to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.extend_from_slice(&[0u8; 10]);
+        msg.extend_from_slice(payload);
+        msg.resize(((len as usize) + 3) & !3, 0);
+
+        msg
+    }
+
+    /// `rtmsg` or `fib_rule_hdr` followed by the attributes
+    fn payload(header: [u8; 8], flags: u32, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut payload = header.to_vec();
+
+        payload.extend_from_slice(&flags.to_ne_bytes());
+
+        for (attr_type, value) in attrs {
+            payload.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+            payload.extend_from_slice(&attr_type.to_ne_bytes());
+            payload.extend_from_slice(value);
+            payload.resize((payload.len() + 3) & !3, 0);
+        }
+
+        payload
+    }
+
+    fn name(index: u32) -> Option<String> {
+        match index {
+            1 => Some("lo".into()),
+            2 => Some("eth0".into()),
+            5 => Some("wg0".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut buf = message(libc::RTM_NEWADDR, &[1, 2, 3]);
+
+        buf.extend(message(libc::RTM_NEWROUTE, &[4; 12]));
+
+        let parsed = messages(&buf);
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0], (libc::RTM_NEWADDR, &[1, 2, 3][..]));
+        assert_eq!(parsed[1], (libc::RTM_NEWROUTE, &[4; 12][..]));
+        assert!(messages(&buf[..10]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let table = 51820u32.to_ne_bytes();
+        let wg_default = payload(
+            [libc::AF_INET as u8, 0, 0, 0, 252, 3, 0, libc::RTN_UNICAST],
+            0,
+            &[
+                (libc::RTA_TABLE, &table),
+                (libc::RTA_OIF, &5u32.to_ne_bytes()),
+            ],
+        );
+        let lan = payload(
+            [
+                libc::AF_INET as u8,
+                24,
+                0,
+                0,
+                254,
+                2,
+                253,
+                libc::RTN_UNICAST,
+            ],
+            0,
+            &[
+                (libc::RTA_DST, &[192, 168, 0, 0]),
+                (libc::RTA_OIF, &2u32.to_ne_bytes()),
+                (libc::RTA_PRIORITY, &100u32.to_ne_bytes()),
+            ],
+        );
+        let local = payload(
+            [libc::AF_INET as u8, 32, 0, 0, 255, 2, 254, libc::RTN_LOCAL],
+            0,
+            &[
+                (libc::RTA_DST, &[192, 168, 0, 2]),
+                (libc::RTA_OIF, &2u32.to_ne_bytes()),
+            ],
+        );
+        let loopback = payload(
+            [
+                libc::AF_INET6 as u8,
+                128,
+                0,
+                0,
+                254,
+                2,
+                0,
+                libc::RTN_UNICAST,
+            ],
+            0,
+            &[
+                (
+                    libc::RTA_DST,
+                    &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                ),
+                (libc::RTA_OIF, &1u32.to_ne_bytes()),
+            ],
+        );
+
+        assert_eq!(
+            parse_route(&wg_default, name),
+            Some(Route {
+                name: "wg0".into(),
+                dest: "0.0.0.0".parse().unwrap(),
+                prefix_len: 0,
+                metric: 0,
+                table: 51820,
+            })
+        );
+        assert_eq!(
+            parse_route(&lan, name),
+            Some(Route {
+                name: "eth0".into(),
+                dest: "192.168.0.0".parse().unwrap(),
+                prefix_len: 24,
+                metric: 100,
+                table: 254,
+            })
+        );
+        assert_eq!(parse_route(&local, name), None);
+        assert_eq!(parse_route(&loopback, name), None);
+        assert_eq!(parse_route(&lan[..8], name), None);
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let wg_marked = payload(
+            [libc::AF_INET as u8, 0, 0, 0, 252, 0, 0, 1],
+            0x2,
+            &[
+                (FRA_PRIORITY, &32765u32.to_ne_bytes()),
+                (FRA_TABLE, &51820u32.to_ne_bytes()),
+                (FRA_FWMARK, &0xca6cu32.to_ne_bytes()),
+            ],
+        );
+        let wg_suppress = payload(
+            [libc::AF_INET6 as u8, 0, 0, 0, 254, 0, 0, 1],
+            0,
+            &[
+                (FRA_PRIORITY, &32764u32.to_ne_bytes()),
+                (FRA_SUPPRESS_PREFIXLEN, &0u32.to_ne_bytes()),
+            ],
+        );
+        let main = payload(
+            [libc::AF_INET as u8, 0, 0, 0, 254, 0, 0, 1],
+            0,
+            &[
+                (FRA_PRIORITY, &32766u32.to_ne_bytes()),
+                (FRA_SUPPRESS_PREFIXLEN, &u32::MAX.to_ne_bytes()),
+            ],
+        );
+        let from_source = payload(
+            [libc::AF_INET as u8, 0, 24, 0, 100, 0, 0, 1],
+            0,
+            &[(FRA_SRC, &[10, 8, 0, 0])],
+        );
+        let unreachable = payload([libc::AF_INET as u8, 0, 0, 0, 0, 0, 0, 6], 0, &[]);
+
+        assert_eq!(
+            parse_rule(&wg_marked),
+            Some(RouteRule {
+                fwmark: Some(0xca6c),
+                invert: true,
+                ..RouteRule::lookup(32765, true, 51820)
+            })
+        );
+        assert_eq!(
+            parse_rule(&wg_suppress),
+            Some(RouteRule {
+                suppress_prefix_len: Some(0),
+                ..RouteRule::lookup(32764, false, 254)
+            })
+        );
+        assert_eq!(parse_rule(&main), Some(RouteRule::lookup(32766, true, 254)));
+        assert_eq!(parse_rule(&from_source), None);
+        assert_eq!(parse_rule(&unreachable), None);
+    }
+}
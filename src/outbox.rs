@@ -0,0 +1,289 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Durable queue of the reports that were not delivered yet
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::report::ReportPayload;
+use crate::sink::DEFAULT_SINK;
+
+/// Version of the outbox file format
+const OUTBOX_VERSION: u32 = 1;
+/// Outbox file name of the default sink in the state directory
+const OUTBOX_FILE: &str = "outbox.json";
+
+/// `outbox` section of the tracker configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    /// Maximum number of queued reports, the oldest ones are dropped first
+    pub max_reports: usize,
+    /// Maximum age of the queued reports in seconds
+    pub max_age: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_reports: 100,
+            max_age: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxFile {
+    version: u32,
+    reports: VecDeque<ReportPayload>,
+}
+
+/// Reports waiting for delivery in the order they were created
+/// Every change is written to the outbox file if there is one
+#[derive(Debug)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    config: OutboxConfig,
+    reports: VecDeque<ReportPayload>,
+}
+
+impl Outbox {
+    /// Outbox file location of the `sink` in the state directory
+    /// The default sink keeps the file name used before other sinks were supported
+    pub fn sink_path(sink: &str) -> Option<PathBuf> {
+        let file = match sink {
+            DEFAULT_SINK => OUTBOX_FILE.to_string(),
+            _ => format!("outbox-{sink}.json"),
+        };
+
+        crate::state_dir().map(|dir| dir.join(file))
+    }
+
+    /// Open outbox stored at `path`
+    /// A missing file is an empty outbox, an unreadable or corrupted one is an error
+    pub fn open(path: &Path, config: &OutboxConfig) -> io::Result<Self> {
+        let reports = match fs::read(path) {
+            Ok(data) => {
+                let file: OutboxFile = serde_json::from_slice(&data)?;
+
+                if file.version != OUTBOX_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported outbox version {}", file.version),
+                    ));
+                }
+
+                file.reports
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            config: config.clone(),
+            reports,
+        })
+    }
+
+    /// Create empty outbox stored at `path`, an existing file is replaced on the first change
+    pub fn empty(path: &Path, config: &OutboxConfig) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+            config: config.clone(),
+            reports: VecDeque::new(),
+        }
+    }
+
+    /// Create outbox that is not stored anywhere
+    pub fn in_memory(config: &OutboxConfig) -> Self {
+        Self {
+            path: None,
+            config: config.clone(),
+            reports: VecDeque::new(),
+        }
+    }
+
+    /// Queued reports, the oldest one goes first
+    pub fn reports(&self) -> impl Iterator<Item = &ReportPayload> {
+        self.reports.iter()
+    }
+
+    /// Oldest queued report
+    pub fn front(&self) -> Option<&ReportPayload> {
+        self.reports.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /// Queue `report`
+    /// Returns number of the oldest reports dropped to stay within the size limit
+    pub fn push(&mut self, report: ReportPayload) -> io::Result<usize> {
+        self.reports.push_back(report);
+
+        let dropped = self.reports.len().saturating_sub(self.config.max_reports);
+
+        self.reports.drain(..dropped);
+        self.store()?;
+        Ok(dropped)
+    }
+
+    /// Remove the oldest queued report
+    pub fn pop_front(&mut self) -> io::Result<Option<ReportPayload>> {
+        let report = self.reports.pop_front();
+
+        if report.is_some() {
+            self.store()?;
+        }
+
+        Ok(report)
+    }
+
+    /// Drop reports created more than the maximum age before `now`
+    /// Returns number of the dropped reports
+    pub fn expire(&mut self, now: SystemTime) -> io::Result<usize> {
+        let oldest = now
+            .checked_sub(Duration::from_secs(self.config.max_age))
+            .and_then(|oldest| oldest.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|oldest| oldest.as_secs())
+            .unwrap_or_default();
+        let count = self.reports.len();
+
+        self.reports.retain(|report| report.timestamp >= oldest);
+
+        let dropped = count - self.reports.len();
+
+        if dropped > 0 {
+            self.store()?;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Drop all queued reports
+    pub fn purge(&mut self) -> io::Result<()> {
+        self.reports.clear();
+        self.store()
+    }
+
+    /// Replace the outbox file atomically so that a crash never leaves a partial one
+    fn store(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec(&OutboxFile {
+            version: OUTBOX_VERSION,
+            reports: self.reports.clone(),
+        })?;
+
+        crate::write_atomic(path, &data, None)
+    }
+}
+
+#[cfg(test)]
+mod outbox_tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::config_tests::assert_round_trip;
+    use crate::outbox::{Outbox, OutboxConfig};
+    use crate::report::{EventKind, ReportInterface, ReportPayload, REPORT_SCHEMA_VERSION};
+    use crate::TrackerConfig;
+
+    fn report(name: &str, timestamp: u64) -> ReportPayload {
+        ReportPayload {
+            version: REPORT_SCHEMA_VERSION,
+            tracker_version: "0.1.0".into(),
+            hostname: "laptop".into(),
+            device: None,
+            event: EventKind::Up,
+            timestamp,
+            interface: ReportInterface {
+                name: name.into(),
+                index: None,
+                kind: None,
+                mtu: None,
+                oper_state: None,
+                peer: None,
+                tunnel: None,
+            },
+            addresses: Vec::new(),
+            previous_addresses: Vec::new(),
+            egress: None,
+            session_duration: None,
+            uptime: None,
+        }
+    }
+
+    fn names(outbox: &Outbox) -> Vec<&str> {
+        outbox
+            .reports()
+            .map(|report| report.interface.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_persistent_outbox() {
+        let path = std::env::temp_dir()
+            .join(format!("vpn-ip-tracker-outbox-{}", std::process::id()))
+            .join("outbox.json");
+        let config = OutboxConfig::default();
+        let mut outbox = Outbox::open(&path, &config).unwrap();
+
+        assert!(outbox.is_empty());
+        outbox.push(report("tun0", 1)).unwrap();
+        outbox.push(report("tun1", 2)).unwrap();
+        outbox.push(report("tun2", 3)).unwrap();
+        assert_eq!(outbox.pop_front().unwrap().unwrap().interface.name, "tun0");
+
+        let mut reopened = Outbox::open(&path, &config).unwrap();
+
+        assert_eq!(names(&reopened), ["tun1", "tun2"]);
+        reopened.purge().unwrap();
+        assert!(Outbox::open(&path, &config).unwrap().is_empty());
+
+        std::fs::write(&path, "{\"version\": 1, \"reports\": [").unwrap();
+        assert!(Outbox::open(&path, &config).is_err());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_outbox_limits() {
+        let mut outbox = Outbox::in_memory(&OutboxConfig {
+            max_reports: 2,
+            max_age: 60,
+        });
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+
+        assert_eq!(outbox.push(report("tun0", 900)).unwrap(), 0);
+        assert_eq!(outbox.push(report("tun1", 930)).unwrap(), 0);
+        assert_eq!(outbox.push(report("tun2", 990)).unwrap(), 1);
+        assert_eq!(names(&outbox), ["tun1", "tun2"]);
+        assert_eq!(outbox.expire(now).unwrap(), 1);
+        assert_eq!(names(&outbox), ["tun2"]);
+    }
+
+    #[test]
+    fn test_load_outbox_section() {
+        assert_round_trip(
+            "outbox",
+            TrackerConfig {
+                outbox: OutboxConfig {
+                    max_reports: 10,
+                    max_age: 3600,
+                },
+                ..Default::default()
+            },
+        );
+    }
+}
@@ -0,0 +1,242 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::routes::MAIN_TABLE;
+
+/// Default location of the network information in procfs
+const PROCFS_NET_ROOT: &str = "/proc/net";
+
+const IFA_F_TEMPORARY: u32 = 0x01;
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+const RTF_LOCAL: u32 = 0x8000_0000;
+
+/// Routing table entry as reported by `/proc/net/route` and `/proc/net/ipv6_route` or netlink
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Route {
+    pub(crate) name: String,
+    pub(crate) dest: IpAddr,
+    pub(crate) prefix_len: u8,
+    pub(crate) metric: u32,
+    /// Routing table holding the route
+    pub(crate) table: u32,
+}
+
+/// IPv6 interface address as reported by `/proc/net/if_inet6`
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Inet6Addr {
+    pub(crate) name: String,
+    pub(crate) addr: Ipv6Addr,
+    flags: u32,
+}
+
+impl Inet6Addr {
+    /// Check whether the address is a temporary (privacy extension) or deprecated address
+    pub(crate) fn is_temporary(&self) -> bool {
+        self.flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED) != 0
+    }
+}
+
+/// Network information reader backed by `/proc/net`
+pub(crate) struct ProcNet {
+    root: PathBuf,
+}
+
+impl Default for ProcNet {
+    fn default() -> Self {
+        Self::new(PROCFS_NET_ROOT)
+    }
+}
+
+impl ProcNet {
+    /// Create new reader with the `root` directory that holds the network information
+    pub(crate) fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Read all IPv6 interface addresses
+    /// Returns an empty list if IPv6 information is not available
+    pub(crate) fn inet6_addrs(&self) -> Vec<Inet6Addr> {
+        std::fs::read_to_string(self.root.join("if_inet6"))
+            .map(|content| content.lines().filter_map(parse_inet6_line).collect())
+            .unwrap_or_default()
+    }
+
+    /// Read all usable IPv4 and IPv6 routes of the main routing table
+    /// Returns an empty list if the routing information is not available
+    pub(crate) fn routes(&self) -> Vec<Route> {
+        let ipv4 = std::fs::read_to_string(self.root.join("route"))
+            .map(|content| {
+                content
+                    .lines()
+                    .skip(1)
+                    .filter_map(parse_route_line)
+                    .collect()
+            })
+            .unwrap_or_else(|_| Vec::new());
+        let ipv6 = std::fs::read_to_string(self.root.join("ipv6_route"))
+            .map(|content| content.lines().filter_map(parse_ipv6_route_line).collect())
+            .unwrap_or_else(|_| Vec::new());
+
+        ipv4.into_iter().chain(ipv6).collect()
+    }
+}
+
+fn is_usable_route(name: &str, flags: u32) -> bool {
+    name != "lo" && flags & RTF_UP != 0 && flags & (RTF_REJECT | RTF_LOCAL) == 0
+}
+
+fn parse_route_line(line: &str) -> Option<Route> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let name = fields.first()?;
+    let dest = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+    let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+    let metric = fields.get(6)?.parse().ok()?;
+    let mask = u32::from_str_radix(fields.get(7)?, 16).ok()?;
+
+    is_usable_route(name, flags).then(|| Route {
+        name: name.to_string(),
+        dest: IpAddr::V4(Ipv4Addr::from(u32::from_be(dest))),
+        prefix_len: mask.count_ones() as u8,
+        metric,
+        table: MAIN_TABLE,
+    })
+}
+
+fn parse_ipv6_route_line(line: &str) -> Option<Route> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let dest = u128::from_str_radix(fields.first()?, 16).ok()?;
+    let prefix_len = u8::from_str_radix(fields.get(1)?, 16).ok()?;
+    let metric = u32::from_str_radix(fields.get(5)?, 16).ok()?;
+    let flags = u32::from_str_radix(fields.get(8)?, 16).ok()?;
+    let name = fields.get(9)?;
+
+    is_usable_route(name, flags).then(|| Route {
+        name: name.to_string(),
+        dest: IpAddr::V6(Ipv6Addr::from(dest)),
+        prefix_len,
+        metric,
+        table: MAIN_TABLE,
+    })
+}
+
+fn parse_inet6_line(line: &str) -> Option<Inet6Addr> {
+    let mut fields = line.split_whitespace();
+    let addr = u128::from_str_radix(fields.next()?, 16).ok()?;
+    let _index = fields.next()?;
+    let _prefix_len = fields.next()?;
+    let _scope = fields.next()?;
+    let flags = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let name = fields.next()?.to_string();
+
+    Some(Inet6Addr {
+        name,
+        addr: Ipv6Addr::from(addr),
+        flags,
+    })
+}
+
+#[cfg(test)]
+mod procfs_tests {
+    use std::net::Ipv6Addr;
+
+    use crate::procfs::{ProcNet, Route};
+
+    #[test]
+    fn test_inet6_addrs() {
+        let root = std::env::temp_dir().join(format!(
+            "vpn-ip-tracker-procfs-{}-inet6",
+            std::process::id()
+        ));
+
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("if_inet6"),
+            "fe8000000000000000fc00fffe000001 04 40 20 80     eth0\n\
+             fd000000000000000000000000000002 05 40 00 80     tun0\n\
+             20010db8000000001c2a3b4c5d6e7f80 05 40 00 01     tun0\n\
+             20010db8000000000000000000000010 05 40 00 20     tun0\n\
+             malformed line\n",
+        )
+        .unwrap();
+        let addrs = ProcNet::new(&root).inet6_addrs();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(addrs.len(), 4);
+        assert_eq!(addrs[1].name, "tun0");
+        assert_eq!(addrs[1].addr, "fd00::2".parse::<Ipv6Addr>().unwrap());
+        assert!(!addrs[1].is_temporary());
+        assert!(addrs[2].is_temporary());
+        assert!(addrs[3].is_temporary());
+    }
+
+    #[test]
+    fn test_routes() {
+        let root = std::env::temp_dir().join(format!(
+            "vpn-ip-tracker-procfs-{}-routes",
+            std::process::id()
+        ));
+
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("route"),
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+             tun0\t00000000\t0100080A\t0003\t0\t0\t0\t00000080\t0\t0\t0\n\
+             tun0\t0000140A\t00000000\t0000\t0\t0\t0\t0000FFFF\t0\t0\t0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("ipv6_route"),
+            "fd000001000000000000000000000000 20 00000000000000000000000000000000 00 \
+             00000000000000000000000000000000 00000100 00000001 00000000 00000001     wg0\n\
+             00000000000000000000000000000001 80 00000000000000000000000000000000 00 \
+             00000000000000000000000000000000 00000000 00000002 00000000 80200001       lo\n\
+             00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+             00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n",
+        )
+        .unwrap();
+        let routes = ProcNet::new(&root).routes();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            routes,
+            [
+                Route {
+                    name: "eth0".into(),
+                    dest: "0.0.0.0".parse().unwrap(),
+                    prefix_len: 0,
+                    metric: 100,
+                    table: 254,
+                },
+                Route {
+                    name: "tun0".into(),
+                    dest: "0.0.0.0".parse().unwrap(),
+                    prefix_len: 1,
+                    metric: 0,
+                    table: 254,
+                },
+                Route {
+                    name: "wg0".into(),
+                    dest: "fd00:1::".parse().unwrap(),
+                    prefix_len: 32,
+                    metric: 256,
+                    table: 254,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_files() {
+        assert!(ProcNet::new("/nonexistent").inet6_addrs().is_empty());
+        assert!(ProcNet::new("/nonexistent").routes().is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Report data shared between the tracker and the report receivers
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::matching::LinkKind;
+
+/// Version of the JSON report schema, bumped on incompatible changes
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Encoding of the report body
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    /// Comma-separated interface addresses
+    #[default]
+    Plain,
+    /// [`ReportPayload`] serialized to JSON
+    Json,
+}
+
+/// Kind of the interface event that caused a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    /// VPN interface appeared
+    Up,
+    /// Addresses of the VPN interface changed
+    Changed,
+    /// Previously reported VPN interface disappeared
+    Down,
+    /// Periodic liveness report sent even if nothing changed
+    Heartbeat,
+}
+
+impl EventKind {
+    /// Event name as used in the reports
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Up => "up",
+            EventKind::Changed => "changed",
+            EventKind::Down => "down",
+            EventKind::Heartbeat => "heartbeat",
+        }
+    }
+}
+
+/// Operational state of the interface as defined by RFC 2863
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperState {
+    Unknown,
+    NotPresent,
+    Down,
+    LowerLayerDown,
+    Testing,
+    Dormant,
+    Up,
+}
+
+/// Share of the traffic carried by the VPN interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelMode {
+    /// Interface holds the default route
+    Full,
+    /// Interface carries only some destinations
+    Split,
+}
+
+impl TunnelMode {
+    /// Tunnel mode name as used in the reports
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelMode::Full => "full",
+            TunnelMode::Split => "split",
+        }
+    }
+}
+
+/// Address assigned to the reported interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportAddress {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+/// Reported VPN interface
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportInterface {
+    pub name: String,
+    /// Kernel index of the interface
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<LinkKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oper_state: Option<OperState>,
+    /// Remote end of a point-to-point link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<TunnelMode>,
+}
+
+/// Public address the VPN traffic leaves with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportEgress {
+    pub addr: IpAddr,
+    /// Disagreement of the egress IP providers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+/// Body of the JSON report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportPayload {
+    /// Schema version, see [`REPORT_SCHEMA_VERSION`]
+    pub version: u32,
+    /// Version of the tracker that sent the report
+    pub tracker_version: String,
+    pub hostname: String,
+    /// Device label from the tracker configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    pub event: EventKind,
+    /// Unix time in seconds the report was created
+    pub timestamp: u64,
+    pub interface: ReportInterface,
+    /// Current addresses, empty if the interface is down
+    #[serde(default)]
+    pub addresses: Vec<ReportAddress>,
+    /// Previously reported addresses, empty if the interface just came up
+    #[serde(default)]
+    pub previous_addresses: Vec<ReportAddress>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress: Option<ReportEgress>,
+    /// Seconds the interface was up, set only for down and heartbeat events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_duration: Option<u64>,
+    /// Seconds the tracker has been running, set only for heartbeat events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime: Option<u64>,
+}
+
+#[cfg(test)]
+mod report_tests {
+    use crate::matching::LinkKind;
+    use crate::report::{EventKind, OperState, ReportPayload, REPORT_SCHEMA_VERSION};
+
+    #[test]
+    fn test_payload_schema() {
+        let payload: ReportPayload = serde_json::from_str(
+            r#"{
+                "version": 1,
+                "tracker_version": "0.1.0",
+                "hostname": "laptop",
+                "event": "changed",
+                "timestamp": 1700000000,
+                "interface": {
+                    "name": "wg0",
+                    "index": 7,
+                    "kind": "wireguard",
+                    "oper_state": "unknown"
+                },
+                "addresses": [{"addr": "10.8.0.3", "prefix_len": 24}],
+                "previous_addresses": [{"addr": "10.8.0.2", "prefix_len": 24}]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(payload.version, REPORT_SCHEMA_VERSION);
+        assert_eq!(payload.event, EventKind::Changed);
+        assert_eq!(payload.interface.index, Some(7));
+        assert_eq!(payload.interface.kind, Some(LinkKind::Wireguard));
+        assert_eq!(payload.interface.oper_state, Some(OperState::Unknown));
+        assert_eq!(payload.addresses[0].addr.to_string(), "10.8.0.3");
+        assert_eq!(payload.egress, None);
+        assert_eq!(
+            serde_json::from_str::<ReportPayload>(&serde_json::to_string(&payload).unwrap())
+                .unwrap(),
+            payload
+        );
+        assert_eq!(
+            serde_json::to_value(&payload.interface).unwrap(),
+            serde_json::json!({
+                "name": "wg0",
+                "index": 7,
+                "kind": "wireguard",
+                "oper_state": "unknown"
+            })
+        );
+    }
+}
@@ -0,0 +1,251 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use vpn_ip_tracker::{report::ReportPayload, RetryConfig};
+
+use crate::tracker::{ReportError, Reporter};
+use crate::utils;
+
+/// Reporter that repeats failed deliveries of `inner` with exponential backoff
+/// The reporter never waits itself, it tells when the next attempt is due and the tracker keeps
+/// checking the interfaces and serving the other sinks meanwhile
+pub(crate) struct RetryingReporter<R> {
+    inner: R,
+    policy: RetryConfig,
+    /// Failed attempts of the current report
+    attempt: u32,
+    /// Time the current report is attempted again
+    retry_at: Option<Instant>,
+}
+
+impl<R: Reporter> RetryingReporter<R> {
+    pub(crate) fn new(inner: R, policy: &RetryConfig) -> Self {
+        Self {
+            inner,
+            policy: policy.clone(),
+            attempt: 0,
+            retry_at: None,
+        }
+    }
+}
+
+impl<R: Reporter> Reporter for RetryingReporter<R> {
+    /// Deliver `report`, a retryable error schedules the next attempt until the attempts run out
+    /// The report is postponed to the next check once the attempts run out or if `Retry-After`
+    /// is longer than the maximum delay
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        let max_delay = Duration::from_millis(self.policy.max_delay_ms);
+
+        self.retry_at = None;
+
+        let e = match self.inner.report(report) {
+            Ok(_) => {
+                self.attempt = 0;
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        self.attempt += 1;
+
+        if !e.is_retryable() || self.attempt >= self.policy.max_attempts {
+            self.attempt = 0;
+            return Err(e);
+        }
+
+        let delay = match e.retry_after() {
+            Some(delay) if delay > max_delay => {
+                self.attempt = 0;
+                return Err(e);
+            }
+            Some(delay) => delay,
+            None => backoff_delay(&self.policy, self.attempt),
+        };
+
+        debug!(
+            "Retry report for {} in {:?} after attempt {}: {}",
+            report.interface.name, delay, self.attempt, e
+        );
+        self.retry_at = Some(Instant::now() + delay);
+
+        Err(e)
+    }
+
+    fn next_attempt(&self) -> Option<Instant> {
+        self.retry_at
+    }
+}
+
+/// Delay before the attempt that follows the failed `attempt`
+/// The base delay is doubled with every attempt up to the maximum and randomly deviated by the
+/// jitter fraction so that trackers do not retry in lockstep
+fn backoff_delay(policy: &RetryConfig, attempt: u32) -> Duration {
+    let max_delay = policy.max_delay_ms as f64;
+    let delay = (policy.base_delay_ms as f64 * 2f64.powi(attempt as i32 - 1)).min(max_delay);
+    let mut random = [0u8; 4];
+
+    utils::fill_random(&mut random);
+
+    let deviation = u32::from_be_bytes(random) as f64 / u32::MAX as f64 * 2.0 - 1.0;
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+
+    Duration::from_millis((delay * (1.0 + jitter * deviation)).min(max_delay) as u64)
+}
+
+#[cfg(test)]
+mod retry_tests {
+    use std::{
+        collections::VecDeque,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use vpn_ip_tracker::{report::ReportPayload, RetryConfig};
+
+    use crate::events::IfaceEvent;
+    use crate::retry::{backoff_delay, RetryingReporter};
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::tracker_tests::RecordingReporter;
+    use crate::tracker::{ReportError, Reporter};
+
+    fn policy() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 1_000,
+            max_delay_ms: 10_000,
+            jitter: 0.0,
+        }
+    }
+
+    fn reporter(failures: &[u16], policy: &RetryConfig) -> RetryingReporter<RecordingReporter> {
+        RetryingReporter::new(
+            RecordingReporter {
+                failures: VecDeque::from(failures.to_vec()),
+                ..Default::default()
+            },
+            policy,
+        )
+    }
+
+    fn up() -> ReportPayload {
+        IfaceEvent::Up {
+            iface: iface("tun0", "10.8.0.2"),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    /// Deliver `up` report once and get the delay before the next attempt
+    fn attempt<R: Reporter>(
+        reporter: &mut RetryingReporter<R>,
+    ) -> (Result<(), ReportError>, Option<Duration>) {
+        let started = Instant::now();
+        let result = reporter.report(&up());
+
+        (
+            result,
+            reporter
+                .next_attempt()
+                .map(|retry_at| retry_at.duration_since(started)),
+        )
+    }
+
+    fn assert_delay(delay: Option<Duration>, expected_ms: u64) {
+        let delay = delay.unwrap();
+
+        assert!(delay >= Duration::from_millis(expected_ms));
+        assert!(delay < Duration::from_millis(expected_ms + 500));
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let mut policy = RetryConfig {
+            base_delay_ms: 100,
+            max_delay_ms: 1_000,
+            ..policy()
+        };
+
+        assert_eq!(backoff_delay(&policy, 1), Duration::from_millis(100));
+        assert_eq!(backoff_delay(&policy, 3), Duration::from_millis(400));
+        assert_eq!(backoff_delay(&policy, 10), Duration::from_millis(1_000));
+
+        policy.jitter = 0.5;
+
+        for _ in 0..100 {
+            let delay = backoff_delay(&policy, 2);
+
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn test_retry_until_delivered() {
+        let mut reporter = reporter(&[503, 502], &policy());
+
+        let (result, delay) = attempt(&mut reporter);
+
+        assert!(result.unwrap_err().is_retryable());
+        assert_delay(delay, 1_000);
+
+        let (result, delay) = attempt(&mut reporter);
+
+        assert!(result.is_err());
+        assert_delay(delay, 2_000);
+
+        let (result, delay) = attempt(&mut reporter);
+
+        assert!(result.is_ok());
+        assert_eq!(delay, None);
+        assert_eq!(reporter.inner.events.len(), 1);
+        assert_eq!(reporter.attempt, 0);
+    }
+
+    #[test]
+    fn test_attempts_run_out() {
+        let mut reporter = reporter(&[503, 503, 503, 503], &policy());
+
+        assert!(attempt(&mut reporter).1.is_some());
+        assert!(attempt(&mut reporter).1.is_some());
+
+        let (result, delay) = attempt(&mut reporter);
+
+        assert!(result.unwrap_err().is_retryable());
+        assert_eq!(delay, None);
+        assert_eq!(reporter.inner.failures.len(), 1);
+
+        // The next check starts over
+        assert_delay(attempt(&mut reporter).1, 1_000);
+    }
+
+    #[test]
+    fn test_permanent_error_is_not_retried() {
+        let mut reporter = reporter(&[401, 503], &policy());
+        let (result, delay) = attempt(&mut reporter);
+
+        assert!(result.unwrap_err().is_unauthorized());
+        assert_eq!(delay, None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        struct RateLimited(VecDeque<u64>);
+
+        impl Reporter for RateLimited {
+            fn report(&mut self, _report: &ReportPayload) -> Result<(), ReportError> {
+                match self.0.pop_front() {
+                    Some(secs) => Err(ReportError::Status {
+                        status: 429,
+                        retry_after: Some(Duration::from_secs(secs)),
+                    }),
+                    None => Ok(()),
+                }
+            }
+        }
+
+        let mut reporter = RetryingReporter::new(RateLimited(VecDeque::from([5, 20])), &policy());
+
+        assert_delay(attempt(&mut reporter).1, 5_000);
+        assert_eq!(attempt(&mut reporter).1, None);
+        assert!(reporter.inner.0.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use log::debug;
+
+use vpn_ip_tracker::{matching::IpPrefix, report::TunnelMode, IpFamily};
+
+use crate::procfs::{ProcNet, Route};
+
+/// Main routing table, the only IPv4 one `/proc/net/route` shows
+pub(crate) const MAIN_TABLE: u32 = 254;
+/// Table looked up after the main one when the main one has no route
+const DEFAULT_TABLE: u32 = 253;
+
+/// Routing policy rule that selects the table to look the route up in
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct RouteRule {
+    /// Rules are tried in ascending priority order
+    pub(crate) priority: u32,
+    pub(crate) ipv4: bool,
+    /// Destination of the traffic the rule applies to, any if not set
+    pub(crate) dest: Option<IpPrefix>,
+    /// Firewall mark the traffic must carry
+    pub(crate) fwmark: Option<u32>,
+    /// Rule applies to the traffic its selectors do not match
+    pub(crate) invert: bool,
+    pub(crate) table: u32,
+    /// Routes with prefixes not longer than this are ignored, wg-quick uses it to skip the
+    /// default route of the main table
+    pub(crate) suppress_prefix_len: Option<u8>,
+}
+
+impl RouteRule {
+    /// Create rule that looks up `table` for all traffic of the address family
+    pub(crate) fn lookup(priority: u32, ipv4: bool, table: u32) -> Self {
+        Self {
+            priority,
+            ipv4,
+            dest: None,
+            fwmark: None,
+            invert: false,
+            table,
+            suppress_prefix_len: None,
+        }
+    }
+
+    /// Rules of a system without policy routing
+    fn defaults() -> Vec<Self> {
+        [true, false]
+            .into_iter()
+            .flat_map(|ipv4| {
+                [
+                    RouteRule::lookup(32766, ipv4, MAIN_TABLE),
+                    RouteRule::lookup(32767, ipv4, DEFAULT_TABLE),
+                ]
+            })
+            .collect()
+    }
+
+    /// Check whether the rule applies to the traffic of the tracker to `dest`
+    /// The traffic carries no firewall mark, `None` stands for any destination
+    fn matches(&self, dest: Option<&IpPrefix>) -> bool {
+        let to = self.dest.as_ref().is_none_or(|to| match dest {
+            Some(dest) => to.len <= dest.len && to.contains(&dest.addr),
+            None => to.len == 0,
+        });
+        let mark = self.fwmark.is_none_or(|mark| mark == 0);
+
+        (to && mark) != self.invert
+    }
+}
+
+/// Routing tables snapshot used to find the interface that carries the traffic
+/// The tables are selected by the routing policy rules like the kernel does for the traffic of
+/// the host itself
+pub(crate) struct RouteTable {
+    routes: Vec<Route>,
+    rules: Vec<RouteRule>,
+}
+
+impl RouteTable {
+    /// Create snapshot of the `routes` of all tables, the main table is used without `rules`
+    pub(crate) fn new(routes: Vec<Route>, mut rules: Vec<RouteRule>) -> Self {
+        if rules.is_empty() {
+            rules = RouteRule::defaults();
+        }
+
+        rules.sort_by_key(|rule| rule.priority);
+        Self { routes, rules }
+    }
+
+    /// Read routes of all tables with the routing rules
+    /// Falls back to the main table from procfs if netlink is not available
+    pub(crate) fn read(procfs: &ProcNet) -> Self {
+        #[cfg(target_os = "linux")]
+        match crate::netlink::routes().and_then(|routes| Ok((routes, crate::netlink::rules()?))) {
+            Ok((routes, rules)) => return Self::new(routes, rules),
+            Err(e) => debug!("Failed to read routes from netlink, use procfs: {}", e),
+        }
+
+        Self::new(procfs.routes(), Vec::new())
+    }
+
+    /// Find interface that holds the route to `prefix`
+    /// The most specific route of the selected table wins, routes with equal prefix length are
+    /// ordered by metric
+    pub(crate) fn route_to(&self, prefix: &IpPrefix) -> Option<&str> {
+        self.lookup(prefix.addr.is_ipv4(), Some(prefix))
+            .map(|route| route.name.as_str())
+    }
+
+    /// Find interfaces that hold the default route of the tracked address families
+    /// Routes that cover half of the address space, like `0.0.0.0/1` and `128.0.0.0/1` used by
+    /// OpenVPN, are treated as the default route as well
+    pub(crate) fn default_routes(&self, family: IpFamily) -> Vec<&str> {
+        let mut names: Vec<&str> = [
+            family.has_ipv4().then(|| self.default_route(true)),
+            family.has_ipv6().then(|| self.default_route(false)),
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+
+        names.dedup();
+        names
+    }
+
+    /// Classify traffic share carried by the interface `name`
+    /// The interface carries all traffic if it holds the default route of any address family
+    pub(crate) fn tunnel_mode(&self, name: &str) -> TunnelMode {
+        if [true, false]
+            .into_iter()
+            .any(|ipv4| self.default_route(ipv4) == Some(name))
+        {
+            TunnelMode::Full
+        } else {
+            TunnelMode::Split
+        }
+    }
+
+    fn default_route(&self, ipv4: bool) -> Option<&str> {
+        self.lookup(ipv4, None).map(|route| route.name.as_str())
+    }
+
+    /// Find route of the traffic to `dest` trying the tables selected by the rules in order
+    /// `None` looks for the default route
+    fn lookup(&self, ipv4: bool, dest: Option<&IpPrefix>) -> Option<&Route> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.ipv4 == ipv4 && rule.matches(dest))
+            .find_map(|rule| {
+                let route = self.best_route(rule.table, ipv4, dest)?;
+
+                match rule.suppress_prefix_len {
+                    Some(len) if route.prefix_len <= len => None,
+                    _ => Some(route),
+                }
+            })
+    }
+
+    /// Most specific route to `dest` in `table`, routes with equal prefix length are ordered by
+    /// metric
+    fn best_route(&self, table: u32, ipv4: bool, dest: Option<&IpPrefix>) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.table == table && route.dest.is_ipv4() == ipv4)
+            .filter(|route| match dest {
+                Some(dest) => {
+                    route.prefix_len <= dest.len
+                        && IpPrefix {
+                            addr: route.dest,
+                            len: route.prefix_len,
+                        }
+                        .contains(&dest.addr)
+                }
+                None => route.prefix_len <= 1,
+            })
+            .max_by(|lhs, rhs| {
+                lhs.prefix_len
+                    .cmp(&rhs.prefix_len)
+                    .then(rhs.metric.cmp(&lhs.metric))
+            })
+    }
+}
+
+#[cfg(test)]
+mod routes_tests {
+    use vpn_ip_tracker::{report::TunnelMode, IpFamily};
+
+    use crate::procfs::Route;
+    use crate::routes::{RouteRule, RouteTable, MAIN_TABLE};
+
+    fn route(name: &str, dest: &str, prefix_len: u8, metric: u32) -> Route {
+        Route {
+            name: name.into(),
+            dest: dest.parse().unwrap(),
+            prefix_len,
+            metric,
+            table: MAIN_TABLE,
+        }
+    }
+
+    #[test]
+    fn test_full_tunnel() {
+        let table = RouteTable::new(
+            vec![
+                route("eth0", "0.0.0.0", 0, 100),
+                route("eth0", "192.168.0.0", 24, 100),
+                route("tun0", "0.0.0.0", 1, 0),
+                route("tun0", "128.0.0.0", 1, 0),
+                route("eth0", "::", 0, 1024),
+            ],
+            Vec::new(),
+        );
+
+        assert_eq!(table.default_routes(IpFamily::Ipv4), ["tun0"]);
+        assert_eq!(table.default_routes(IpFamily::Both), ["tun0", "eth0"]);
+        assert_eq!(table.tunnel_mode("tun0"), TunnelMode::Full);
+        assert_eq!(
+            table.route_to(&"192.168.0.0/24".parse().unwrap()),
+            Some("eth0")
+        );
+    }
+
+    #[test]
+    fn test_split_tunnel() {
+        let table = RouteTable::new(
+            vec![
+                route("eth0", "0.0.0.0", 0, 100),
+                route("wlan0", "0.0.0.0", 0, 600),
+                route("tun0", "10.20.0.0", 16, 0),
+                route("wg0", "10.20.5.0", 24, 0),
+                route("wg0", "fd00:1::", 32, 256),
+            ],
+            Vec::new(),
+        );
+
+        assert_eq!(table.default_routes(IpFamily::Ipv4), ["eth0"]);
+        assert_eq!(
+            table.route_to(&"10.20.0.0/16".parse().unwrap()),
+            Some("tun0")
+        );
+        assert_eq!(
+            table.route_to(&"10.20.5.0/24".parse().unwrap()),
+            Some("wg0")
+        );
+        assert_eq!(
+            table.route_to(&"10.20.5.7/32".parse().unwrap()),
+            Some("wg0")
+        );
+        assert_eq!(
+            table.route_to(&"fd00:1:2::/48".parse().unwrap()),
+            Some("wg0")
+        );
+        assert_eq!(table.route_to(&"10.0.0.0/8".parse().unwrap()), Some("eth0"));
+        assert_eq!(table.tunnel_mode("tun0"), TunnelMode::Split);
+    }
+
+    #[test]
+    fn test_wg_quick() {
+        let wg = |dest: &str, prefix_len: u8| Route {
+            table: 51820,
+            ..route("wg0", dest, prefix_len, 0)
+        };
+        // `wg-quick up` with `AllowedIPs = 0.0.0.0/0, ::/0`
+        let table = RouteTable::new(
+            vec![
+                route("eth0", "0.0.0.0", 0, 100),
+                route("eth0", "192.168.0.0", 24, 100),
+                route("eth0", "::", 0, 1024),
+                wg("0.0.0.0", 0),
+                wg("::", 0),
+            ],
+            [true, false]
+                .into_iter()
+                .flat_map(|ipv4| {
+                    [
+                        RouteRule {
+                            suppress_prefix_len: Some(0),
+                            ..RouteRule::lookup(32764, ipv4, MAIN_TABLE)
+                        },
+                        RouteRule {
+                            fwmark: Some(0xca6c),
+                            invert: true,
+                            ..RouteRule::lookup(32765, ipv4, 51820)
+                        },
+                        RouteRule::lookup(32766, ipv4, MAIN_TABLE),
+                    ]
+                })
+                .collect(),
+        );
+
+        assert_eq!(table.default_routes(IpFamily::Both), ["wg0"]);
+        assert_eq!(table.tunnel_mode("wg0"), TunnelMode::Full);
+        assert_eq!(table.tunnel_mode("eth0"), TunnelMode::Split);
+        assert_eq!(
+            table.route_to(&"192.168.0.7/32".parse().unwrap()),
+            Some("eth0")
+        );
+        assert_eq!(table.route_to(&"10.0.0.0/8".parse().unwrap()), Some("wg0"));
+    }
+
+    #[test]
+    fn test_rule_selectors() {
+        let table = RouteTable::new(
+            vec![
+                route("eth0", "0.0.0.0", 0, 100),
+                Route {
+                    table: 100,
+                    ..route("tun0", "0.0.0.0", 0, 0)
+                },
+            ],
+            vec![
+                RouteRule {
+                    dest: Some("10.20.0.0/16".parse().unwrap()),
+                    ..RouteRule::lookup(100, true, 100)
+                },
+                RouteRule {
+                    fwmark: Some(0x1),
+                    ..RouteRule::lookup(200, true, 100)
+                },
+                RouteRule::lookup(32766, true, MAIN_TABLE),
+            ],
+        );
+
+        assert_eq!(table.default_routes(IpFamily::Ipv4), ["eth0"]);
+        assert_eq!(
+            table.route_to(&"10.20.1.0/24".parse().unwrap()),
+            Some("tun0")
+        );
+        assert_eq!(table.route_to(&"10.0.0.0/8".parse().unwrap()), Some("eth0"));
+    }
+}
@@ -0,0 +1,331 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! HMAC-SHA256 signatures of the report requests
+//!
+//! A signed request carries no application token. The tracker signs
+//! `<timestamp>\n<nonce>\n<method>\n<path>\n<body>` with the token and sends the signature with
+//! its timestamp and nonce in the [`TIMESTAMP_HEADER`], [`NONCE_HEADER`] and
+//! [`SIGNATURE_HEADER`] headers, [`KEY_ID_HEADER`] tells the receiver which token to verify with.
+//! Receivers check the requests with [`Verifier`] that also rejects replayed ones.
+//! The other headers are not signed, the JSON report format carries all report fields in the
+//! body.
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Header with the id of the signing token, see [`key_id`]
+pub const KEY_ID_HEADER: &str = "Key-Id";
+/// Header with the Unix time in seconds the request was signed
+pub const TIMESTAMP_HEADER: &str = "Timestamp";
+/// Header with the random string unique for every request
+pub const NONCE_HEADER: &str = "Nonce";
+/// Header with the hex encoded HMAC-SHA256 of the request
+pub const SIGNATURE_HEADER: &str = "Signature";
+/// Default allowed difference between the signing time and the receiver clock
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Errors that can occur while verifying a signed request
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("missing header {0}")]
+    Missing(&'static str),
+    #[error("malformed header {0}")]
+    Malformed(&'static str),
+    #[error("request signed too long ago or in the future")]
+    Expired,
+    #[error("request was already received")]
+    Replayed,
+    #[error("signature does not match")]
+    Mismatch,
+}
+
+/// Signature of a request with its parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// Unix time in seconds the request was signed
+    pub timestamp: u64,
+    pub nonce: String,
+    /// Hex encoded HMAC-SHA256
+    pub mac: String,
+}
+
+impl Signature {
+    /// Sign request
+    /// Arguments:
+    /// - `token` - application token
+    /// - `timestamp` - Unix time in seconds
+    /// - `nonce` - random string unique for every request
+    /// - `method` - HTTP method
+    /// - `path` - request path with the query if any
+    /// - `body` - request body
+    pub fn new(
+        token: &str,
+        timestamp: u64,
+        nonce: &str,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Self {
+        let mac = mac(token, timestamp, nonce, method, path, body).finalize();
+
+        Self {
+            timestamp,
+            nonce: nonce.into(),
+            mac: to_hex(&mac.into_bytes()),
+        }
+    }
+
+    /// Read signature from the request headers
+    /// Arguments:
+    /// - `header` - lookup of the header value by its name
+    pub fn from_headers<'a>(
+        header: impl Fn(&str) -> Option<&'a str>,
+    ) -> Result<Self, SignatureError> {
+        let value = |name: &'static str| header(name).ok_or(SignatureError::Missing(name));
+        let timestamp = value(TIMESTAMP_HEADER)?
+            .trim()
+            .parse()
+            .map_err(|_| SignatureError::Malformed(TIMESTAMP_HEADER))?;
+
+        Ok(Self {
+            timestamp,
+            nonce: value(NONCE_HEADER)?.trim().into(),
+            mac: value(SIGNATURE_HEADER)?.trim().into(),
+        })
+    }
+
+    /// Headers that carry the signature
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            (TIMESTAMP_HEADER, self.timestamp.to_string()),
+            (NONCE_HEADER, self.nonce.clone()),
+            (SIGNATURE_HEADER, self.mac.clone()),
+        ]
+    }
+}
+
+/// Public id of `token` that lets the receiver find the token without seeing it
+/// First 8 bytes of the token SHA-256 hex encoded
+pub fn key_id(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes())[..8])
+}
+
+/// Checker of the signed requests of a single token
+/// Nonces of the accepted requests are remembered while their timestamps are acceptable
+pub struct Verifier {
+    token: String,
+    max_age: Duration,
+    /// Accepted nonces with their timestamps
+    seen: HashMap<String, u64>,
+}
+
+impl Verifier {
+    /// Create verifier
+    /// Arguments:
+    /// - `token` - application token the requests are signed with
+    /// - `max_age` - allowed difference between the signing time and the receiver clock
+    pub fn new(token: &str, max_age: Duration) -> Self {
+        Self {
+            token: token.into(),
+            max_age,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Verify `signature` of the request, the request is remembered if it is valid
+    /// Arguments:
+    /// - `signature` - signature sent with the request
+    /// - `method` - HTTP method
+    /// - `path` - request path with the query if any
+    /// - `body` - request body
+    /// - `now` - current time
+    pub fn verify(
+        &mut self,
+        signature: &Signature,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Result<(), SignatureError> {
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let max_age = self.max_age.as_secs();
+
+        if signature.timestamp.abs_diff(now) > max_age {
+            return Err(SignatureError::Expired);
+        }
+
+        let expected =
+            from_hex(&signature.mac).ok_or(SignatureError::Malformed(SIGNATURE_HEADER))?;
+
+        // Constant time comparison
+        mac(
+            &self.token,
+            signature.timestamp,
+            &signature.nonce,
+            method,
+            path,
+            body,
+        )
+        .verify_slice(&expected)
+        .map_err(|_| SignatureError::Mismatch)?;
+
+        // Timestamps outside of the window are rejected anyway, so are their nonces
+        self.seen
+            .retain(|_, timestamp| timestamp.abs_diff(now) <= max_age);
+
+        if self.seen.contains_key(&signature.nonce) {
+            return Err(SignatureError::Replayed);
+        }
+
+        self.seen
+            .insert(signature.nonce.clone(), signature.timestamp);
+        Ok(())
+    }
+}
+
+fn mac(
+    token: &str,
+    timestamp: u64,
+    nonce: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).unwrap();
+
+    mac.update(format!("{timestamp}\n{nonce}\n{}\n{path}\n", method.to_uppercase()).as_bytes());
+    mac.update(body);
+    mac
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let pairs = hex.as_bytes().chunks_exact(2);
+
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod signature_tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::signature::{
+        key_id, Signature, SignatureError, Verifier, NONCE_HEADER, SIGNATURE_HEADER,
+        TIMESTAMP_HEADER,
+    };
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn signed(nonce: &str, timestamp: u64) -> Signature {
+        Signature::new("secret", timestamp, nonce, "POST", "/report", b"10.8.0.2")
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = signed("abc", NOW);
+        let headers = signature.headers();
+        let parsed = Signature::from_headers(|name| {
+            headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| value.as_str())
+        })
+        .unwrap();
+
+        assert_eq!(signature.mac.len(), 64);
+        assert_eq!(parsed, signature);
+        assert_ne!(signed("abd", NOW).mac, signature.mac);
+        assert_eq!(key_id("secret"), "2bb80d537b1da3e3");
+        assert_eq!(
+            Signature::from_headers(|name| (name != NONCE_HEADER).then_some("1")),
+            Err(SignatureError::Missing(NONCE_HEADER))
+        );
+        assert_eq!(
+            Signature::from_headers(|name| (name != SIGNATURE_HEADER).then_some("now")),
+            Err(SignatureError::Malformed(TIMESTAMP_HEADER))
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut verifier = Verifier::new("secret", Duration::from_secs(300));
+        let verify = |verifier: &mut Verifier, signature: &Signature, body: &[u8], now| {
+            verifier.verify(signature, "post", "/report", body, at(now))
+        };
+
+        assert_eq!(
+            verify(&mut verifier, &signed("a", NOW), b"10.8.0.2", NOW),
+            Ok(())
+        );
+        assert_eq!(
+            verify(&mut verifier, &signed("a", NOW), b"10.8.0.2", NOW + 1),
+            Err(SignatureError::Replayed)
+        );
+        assert_eq!(
+            verify(&mut verifier, &signed("b", NOW), b"10.8.0.3", NOW),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify(&mut verifier, &signed("c", NOW - 301), b"10.8.0.2", NOW),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            verify(
+                &mut verifier,
+                &Signature {
+                    mac: "not hex".into(),
+                    ..signed("d", NOW)
+                },
+                b"10.8.0.2",
+                NOW
+            ),
+            Err(SignatureError::Malformed(SIGNATURE_HEADER))
+        );
+        assert_eq!(
+            Verifier::new("other", Duration::from_secs(300)).verify(
+                &signed("e", NOW),
+                "POST",
+                "/report",
+                b"10.8.0.2",
+                at(NOW)
+            ),
+            Err(SignatureError::Mismatch)
+        );
+
+        // Forgotten nonce is still rejected by its timestamp
+        assert_eq!(
+            verify(
+                &mut verifier,
+                &signed("f", NOW + 400),
+                b"10.8.0.2",
+                NOW + 400
+            ),
+            Ok(())
+        );
+        assert_eq!(verifier.seen.len(), 1);
+        assert_eq!(
+            verify(&mut verifier, &signed("a", NOW), b"10.8.0.2", NOW + 400),
+            Err(SignatureError::Expired)
+        );
+    }
+}
@@ -0,0 +1,513 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Destinations the tracker delivers its reports to
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::report::{EventKind, ReportFormat};
+use crate::RetryConfig;
+
+/// Name of the sink built from the top-level `token` and `report_url`
+pub const DEFAULT_SINK: &str = "default";
+
+/// Errors that can occur while checking the sink configuration
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("invalid sink name '{0}'")]
+    InvalidName(String),
+    #[error("duplicate sink name '{0}'")]
+    DuplicateName(String),
+}
+
+/// Entry of the `sinks` list in the tracker configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SinkConfig {
+    /// Unique sink name, also names the sink outbox
+    pub name: String,
+    /// Reported events, all events are reported if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventKind>,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Retry policy of the sink, the top-level `retry` section applies if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+}
+
+/// Sink protocol with its settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    /// Report service that accepts the tracker reports
+    Http(HttpSinkConfig),
+    /// Telegram chat messages sent by a bot
+    Telegram(TelegramSinkConfig),
+    /// HTTP requests rendered from templates, e.g. chat incoming webhooks
+    Webhook(WebhookSinkConfig),
+    /// Dynamic DNS update over the DynDNS2 HTTP protocol
+    #[serde(rename = "dyndns2")]
+    DynDns2(DynDns2SinkConfig),
+    /// Dynamic DNS update (RFC 2136) sent to the authoritative server
+    Rfc2136(Rfc2136SinkConfig),
+    /// Retained state messages published to an MQTT broker
+    Mqtt(MqttSinkConfig),
+    /// Local file with the current state of the interfaces
+    File(FileSinkConfig),
+    /// User command run for every report except heartbeats
+    Exec(ExecSinkConfig),
+}
+
+/// Settings of the report service sink
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpSinkConfig {
+    /// Report URL
+    pub url: String,
+    /// Application token
+    pub token: String,
+    /// Report body format
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// Sign the requests with the token instead of sending it, see [`crate::signature`]
+    #[serde(default)]
+    pub sign: bool,
+}
+
+/// Markup of the Telegram message template
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TelegramParseMode {
+    MarkdownV2,
+    #[serde(rename = "HTML")]
+    Html,
+    /// Legacy Markdown
+    Markdown,
+}
+
+impl TelegramParseMode {
+    /// Parse mode name as used by the Bot API
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TelegramParseMode::MarkdownV2 => "MarkdownV2",
+            TelegramParseMode::Html => "HTML",
+            TelegramParseMode::Markdown => "Markdown",
+        }
+    }
+}
+
+/// Settings of the Telegram Bot API sink
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelegramSinkConfig {
+    /// Bot API server, a self-hosted one can be used instead of the public one
+    #[serde(default = "default_telegram_api_url")]
+    pub api_url: String,
+    /// Bot token issued by BotFather
+    pub bot_token: String,
+    /// Chat id or `@channel` username
+    pub chat_id: String,
+    /// Message template with `{host}`, `{device}`, `{iface}`, `{event}`, `{ip}`, `{previous_ip}`
+    /// and `{egress}` variables
+    #[serde(default = "default_message_template")]
+    pub template: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<TelegramParseMode>,
+    /// Edit the last message sent about the interface instead of sending a new one for every
+    /// report, the messages are remembered in the state directory across restarts
+    #[serde(default)]
+    pub edit: bool,
+}
+
+/// Built-in request templates of the chat incoming webhooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookPreset {
+    Slack,
+    Discord,
+    /// Matrix client-server API, the URL should end with the `{txn}` transaction id
+    Matrix,
+    Mattermost,
+}
+
+/// Settings of the webhook sink
+/// The URL, header values and body are templates with the report variables, the `{text}`
+/// variable holding the rendered message text and the unique `{txn}` variable
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookSinkConfig {
+    /// Request URL, the substituted variables are percent-encoded
+    pub url: String,
+    /// Request template to start with, the options below override it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<WebhookPreset>,
+    /// HTTP method, POST if neither set nor given by the preset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Message text template
+    #[serde(default = "default_message_template")]
+    pub text: String,
+    /// Request body template, the message text if neither set nor given by the preset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Request headers, the variables are substituted in the values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Address published by the DDNS sinks
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DdnsAddress {
+    /// Addresses of the VPN interface
+    #[default]
+    Interface,
+    /// Public egress address of the VPN traffic, requires egress discovery
+    Egress,
+}
+
+/// Settings of the DynDNS2 sink
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DynDns2SinkConfig {
+    /// Update server URL, e.g. `https://members.dyndns.org`
+    pub server: String,
+    /// Updated host name
+    pub hostname: String,
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub address: DdnsAddress,
+    /// Interface whose addresses are published, the first reported interface is followed until
+    /// it goes down if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+/// Settings of the RFC 2136 sink, the updates are signed with TSIG HMAC-SHA256
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rfc2136SinkConfig {
+    /// Authoritative DNS server as `host:port`
+    pub server: String,
+    /// Updated zone
+    pub zone: String,
+    /// Updated domain name, completed with the zone if it is not within the zone
+    pub record: String,
+    /// TTL of the added records in seconds
+    #[serde(default = "default_ddns_ttl")]
+    pub ttl: u32,
+    /// TSIG key name
+    pub key_name: String,
+    /// Base64 encoded TSIG key
+    pub key_secret: String,
+    #[serde(default)]
+    pub address: DdnsAddress,
+    /// Interface whose addresses are published, the first reported interface is followed until
+    /// it goes down if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+}
+
+/// MQTT protocol version spoken to the broker
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MqttVersion {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+/// Settings of the MQTT sink
+/// The topics and the client id are templates with the report variables
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MqttSinkConfig {
+    /// Broker URL, `mqtt://host:port` or `mqtts://host:port` for TLS
+    pub broker: String,
+    #[serde(default)]
+    pub protocol: MqttVersion,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// PEM file with the CA certificate trusted in addition to the system ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// Topic of the retained interface state, the report JSON
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    /// Topic of the retained `online` message, the broker publishes `offline` as the last will
+    #[serde(default = "default_mqtt_availability_topic")]
+    pub availability_topic: String,
+    /// Quality of service of the published messages
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+    /// Keep alive interval in seconds, zero disables it
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u64,
+    /// Home Assistant discovery prefix, usually `homeassistant`, no discovery if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovery_prefix: Option<String>,
+}
+
+/// Format of the state file
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFileFormat {
+    #[default]
+    Json,
+    /// Line `<iface> <event> <timestamp> <addresses...>` per interface, the fields are separated
+    /// by tabs as interface names may contain spaces
+    Plain,
+}
+
+/// Settings of the state file sink
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileSinkConfig {
+    /// State file, `state.json` or `state` in the runtime directory if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub format: StateFileFormat,
+}
+
+impl FileSinkConfig {
+    /// Get path of the state file
+    pub fn state_path(&self) -> Option<PathBuf> {
+        let name = match self.format {
+            StateFileFormat::Json => "state.json",
+            StateFileFormat::Plain => "state",
+        };
+
+        self.path
+            .clone()
+            .or_else(|| crate::runtime_dir().map(|dir| dir.join(name)))
+    }
+}
+
+/// Settings of the exec sink
+/// The command gets the report in the `VPN_IP_TRACKER_*` environment variables: `EVENT`, `IFACE`,
+/// `IP`, `PREVIOUS_IP`, `EGRESS`, `HOST`, `DEVICE` and `TIMESTAMP`, the addresses are separated
+/// by spaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecSinkConfig {
+    /// Program and its arguments, run without a shell
+    pub command: Vec<String>,
+    /// Write the report JSON to the standard input of the command
+    #[serde(default)]
+    pub stdin: bool,
+    /// Seconds the command may run before it is killed
+    #[serde(default = "default_exec_timeout")]
+    pub timeout: u64,
+}
+
+fn default_exec_timeout() -> u64 {
+    30
+}
+
+fn default_mqtt_client_id() -> String {
+    "vpn-ip-tracker-{host}".into()
+}
+
+fn default_mqtt_topic() -> String {
+    "vpn-ip-tracker/{host}/{iface}".into()
+}
+
+fn default_mqtt_availability_topic() -> String {
+    "vpn-ip-tracker/{host}/availability".into()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_keep_alive() -> u64 {
+    60
+}
+
+fn default_ddns_ttl() -> u32 {
+    300
+}
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".into()
+}
+
+fn default_message_template() -> String {
+    "{host} {iface} {event} {ip}".into()
+}
+
+impl SinkConfig {
+    /// Check whether the sink reports events of `kind`
+    pub fn accepts(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// Check that the sink names are unique and usable in file names
+pub fn check_names(sinks: &[SinkConfig]) -> Result<(), SinkError> {
+    for (i, sink) in sinks.iter().enumerate() {
+        let valid = !sink.name.is_empty()
+            && sink
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !valid {
+            return Err(SinkError::InvalidName(sink.name.clone()));
+        }
+
+        if sinks[..i].iter().any(|it| it.name == sink.name) {
+            return Err(SinkError::DuplicateName(sink.name.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod sink_tests {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use crate::config_tests::assert_round_trip;
+    use crate::report::{EventKind, ReportFormat};
+    use crate::sink::{
+        check_names, DdnsAddress, HttpSinkConfig, MqttSinkConfig, MqttVersion, Rfc2136SinkConfig,
+        SinkConfig, SinkError, SinkKind, TelegramParseMode, TelegramSinkConfig, WebhookPreset,
+        WebhookSinkConfig,
+    };
+    use crate::{RetryConfig, TrackerConfig};
+
+    fn sink(name: &str) -> SinkConfig {
+        SinkConfig {
+            name: name.into(),
+            events: Vec::new(),
+            kind: SinkKind::Http(HttpSinkConfig {
+                url: "https://report.example".into(),
+                token: "secret".into(),
+                format: ReportFormat::Json,
+                sign: false,
+            }),
+            retry: None,
+        }
+    }
+
+    #[test]
+    fn test_event_filter() {
+        let mut sink = sink("audit");
+
+        assert!(sink.accepts(EventKind::Heartbeat));
+
+        sink.events = vec![EventKind::Up, EventKind::Down];
+
+        assert!(sink.accepts(EventKind::Down));
+        assert!(!sink.accepts(EventKind::Changed));
+        assert!(!sink.accepts(EventKind::Heartbeat));
+    }
+
+    #[test]
+    fn test_check_names() {
+        assert!(check_names(&[sink("default"), sink("audit_2")]).is_ok());
+        assert!(matches!(
+            check_names(&[sink("../audit")]),
+            Err(SinkError::InvalidName(_))
+        ));
+        assert!(matches!(
+            check_names(&[sink("audit"), sink("backup"), sink("audit")]),
+            Err(SinkError::DuplicateName(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_sinks_section() {
+        assert_round_trip(
+            "sinks",
+            TrackerConfig {
+                sinks: vec![
+                    SinkConfig {
+                        name: "audit".into(),
+                        events: vec![EventKind::Up, EventKind::Down],
+                        kind: SinkKind::Http(HttpSinkConfig {
+                            url: "https://audit.example/report".into(),
+                            token: "audit-token".into(),
+                            format: ReportFormat::Json,
+                            sign: false,
+                        }),
+                        retry: Some(RetryConfig {
+                            max_attempts: 1,
+                            ..Default::default()
+                        }),
+                    },
+                    SinkConfig {
+                        name: "backup".into(),
+                        events: Vec::new(),
+                        kind: SinkKind::Http(HttpSinkConfig {
+                            url: "https://backup.example/report".into(),
+                            token: "backup-token".into(),
+                            format: ReportFormat::Plain,
+                            sign: true,
+                        }),
+                        retry: None,
+                    },
+                    SinkConfig {
+                        name: "chat".into(),
+                        events: Vec::new(),
+                        kind: SinkKind::Telegram(TelegramSinkConfig {
+                            api_url: "http://127.0.0.1:8081".into(),
+                            bot_token: "123:abc".into(),
+                            chat_id: "@vpn_ips".into(),
+                            template: "<b>{iface}</b> {ip}".into(),
+                            parse_mode: Some(TelegramParseMode::Html),
+                            edit: true,
+                        }),
+                        retry: None,
+                    },
+                    SinkConfig {
+                        name: "matrix".into(),
+                        events: vec![EventKind::Heartbeat],
+                        kind: SinkKind::Webhook(WebhookSinkConfig {
+                            url: "https://matrix.example/_matrix/client/v3/rooms/!r/send/m.room.message/{txn}".into(),
+                            preset: Some(WebhookPreset::Matrix),
+                            method: None,
+                            text: "{host} is alive".into(),
+                            body: None,
+                            headers: BTreeMap::from([("Authorization".into(), "Bearer secret".into())]),
+                        }),
+                        retry: None,
+                    },
+                    SinkConfig {
+                        name: "dns".into(),
+                        events: Vec::new(),
+                        kind: SinkKind::Rfc2136(Rfc2136SinkConfig {
+                            server: "ns1.example.com:53".into(),
+                            zone: "vpn.example.com".into(),
+                            record: "alice".into(),
+                            ttl: 60,
+                            key_name: "tracker-key".into(),
+                            key_secret: "c2VjcmV0".into(),
+                            address: DdnsAddress::Egress,
+                            interface: Some("wg0".into()),
+                        }),
+                        retry: None,
+                    },
+                    SinkConfig {
+                        name: "mqtt".into(),
+                        events: Vec::new(),
+                        kind: SinkKind::Mqtt(MqttSinkConfig {
+                            broker: "mqtts://broker.lan".into(),
+                            protocol: MqttVersion::V5,
+                            client_id: "tracker".into(),
+                            username: Some("tracker".into()),
+                            password: Some("secret".into()),
+                            ca_cert: Some(PathBuf::from("/etc/ssl/broker-ca.pem")),
+                            topic: "vpn/{host}/{iface}".into(),
+                            availability_topic: "vpn/{host}/status".into(),
+                            qos: 2,
+                            keep_alive: 30,
+                            discovery_prefix: Some("homeassistant".into()),
+                        }),
+                        retry: None,
+                    },
+                ],
+                ..Default::default()
+            },
+        );
+    }
+}
@@ -0,0 +1,581 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Dynamic DNS sinks: DynDNS2 over HTTP and RFC 2136 updates signed with TSIG (RFC 8945)
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs},
+    time::{Duration, SystemTime},
+};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::debug;
+use sha2::Sha256;
+
+use vpn_ip_tracker::{
+    report::{EventKind, ReportPayload},
+    sink::{DdnsAddress, DynDns2SinkConfig, Rfc2136SinkConfig},
+    DnsRecord,
+};
+
+use crate::dns::{self, DnsError, CLASS_IN, HEADER_LEN};
+use crate::sinks::http;
+use crate::tracker::{ReportError, Reporter};
+
+/// UPDATE opcode in the header flags
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TYPE_SOA: u16 = 6;
+const TYPE_TSIG: u16 = 250;
+const CLASS_ANY: u16 = 255;
+const TSIG_ALGORITHM: &str = "hmac-sha256";
+/// Allowed clock difference between the tracker and the server in seconds
+const TSIG_FUDGE: u16 = 300;
+/// Time to wait for the first response of the DNS server
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Addresses of `report` published as configured by `address`
+fn published(report: &ReportPayload, address: DdnsAddress) -> Vec<IpAddr> {
+    match address {
+        DdnsAddress::Interface => report.addresses.iter().map(|addr| addr.addr).collect(),
+        DdnsAddress::Egress => report.egress.iter().map(|egress| egress.addr).collect(),
+    }
+}
+
+/// Interface whose addresses a DNS sink publishes, a name can only follow one interface
+struct Followed {
+    /// Configured interface, the first reported interface is followed if unset
+    configured: Option<String>,
+    current: Option<String>,
+}
+
+impl Followed {
+    fn new(configured: Option<&str>) -> Self {
+        Self {
+            configured: configured.map(str::to_string),
+            current: None,
+        }
+    }
+
+    /// Whether `report` is about the followed interface, the first interface that is not going
+    /// down is followed if none is
+    fn accepts(&mut self, report: &ReportPayload) -> bool {
+        let name = report.interface.name.as_str();
+
+        match self.configured.as_deref().or(self.current.as_deref()) {
+            _ if name.is_empty() => false,
+            Some(followed) => followed == name,
+            None if report.event == EventKind::Down => false,
+            None => {
+                self.current = Some(name.to_string());
+                true
+            }
+        }
+    }
+
+    /// Stop following the interface that went down, so the next reported one is picked up
+    fn release(&mut self) {
+        self.current = None;
+    }
+}
+
+/// Reporter that updates a host name over the DynDNS2 protocol
+/// The protocol has no way to remove an address, so interfaces going down are not reported
+pub(crate) struct DynDns2Reporter {
+    client: reqwest::blocking::Client,
+    config: DynDns2SinkConfig,
+    followed: Followed,
+    /// Last successfully published address, providers treat repeated updates as abuse
+    updated: Option<IpAddr>,
+}
+
+impl DynDns2Reporter {
+    pub(crate) fn new(client: reqwest::blocking::Client, config: &DynDns2SinkConfig) -> Self {
+        Self {
+            client,
+            config: config.clone(),
+            followed: Followed::new(config.interface.as_deref()),
+            updated: None,
+        }
+    }
+}
+
+impl Reporter for DynDns2Reporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        if !self.followed.accepts(report) {
+            return Ok(());
+        }
+
+        if report.event == EventKind::Down {
+            self.followed.release();
+            self.updated = None;
+            return Ok(());
+        }
+
+        let addresses = published(report, self.config.address);
+        let Some(addr) = addresses
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or(addresses.first())
+            .copied()
+        else {
+            debug!(
+                "Skip DynDNS2 update of {}, no address",
+                self.config.hostname
+            );
+            return Ok(());
+        };
+
+        if self.updated == Some(addr) {
+            return Ok(());
+        }
+
+        let url = format!("{}/nic/update", self.config.server.trim_end_matches('/'));
+        let response = self
+            .client
+            .get(url)
+            .query(&[
+                ("hostname", self.config.hostname.as_str()),
+                ("myip", &addr.to_string()),
+            ])
+            .basic_auth(&self.config.username, Some(&self.config.password))
+            .header(
+                reqwest::header::USER_AGENT,
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+            )
+            .send()?;
+
+        http::check_status(&response)?;
+
+        let body = response.text()?;
+        let code = body.split_whitespace().next().unwrap_or_default();
+
+        match code {
+            "good" | "nochg" => {
+                self.updated = Some(addr);
+                Ok(())
+            }
+            "badauth" => Err(ReportError::Status {
+                status: 401,
+                retry_after: None,
+            }),
+            "911" | "dnserr" => Err(ReportError::Status {
+                status: 503,
+                retry_after: None,
+            }),
+            _ => Err(ReportError::Invalid(format!(
+                "DynDNS2 update rejected: {code}"
+            ))),
+        }
+    }
+}
+
+/// Reporter that replaces the address records of a domain name with RFC 2136 updates
+/// The records are deleted when the interface goes down
+pub(crate) struct Rfc2136Reporter {
+    config: Rfc2136SinkConfig,
+    followed: Followed,
+    /// Last successfully published addresses, heartbeats are only sent if they differ
+    updated: Option<Vec<IpAddr>>,
+}
+
+impl Rfc2136Reporter {
+    pub(crate) fn new(config: &Rfc2136SinkConfig) -> Self {
+        Self {
+            config: config.clone(),
+            followed: Followed::new(config.interface.as_deref()),
+            updated: None,
+        }
+    }
+
+    /// Fully qualified updated domain name
+    fn fqdn(&self) -> String {
+        let name = self.config.record.trim_end_matches('.');
+        let zone = self.config.zone.trim_end_matches('.');
+
+        if name.eq_ignore_ascii_case(zone)
+            || name
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", zone.to_ascii_lowercase()))
+        {
+            name.to_string()
+        } else {
+            format!("{name}.{zone}")
+        }
+    }
+}
+
+impl Reporter for Rfc2136Reporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        if !self.followed.accepts(report) {
+            return Ok(());
+        }
+
+        let addresses = match report.event {
+            EventKind::Down => Vec::new(),
+            _ => published(report, self.config.address),
+        };
+
+        if addresses.is_empty() && report.event != EventKind::Down {
+            debug!("Skip DNS update of {}, no address", self.config.record);
+            return Ok(());
+        }
+
+        if report.event == EventKind::Heartbeat && self.updated.as_ref() == Some(&addresses) {
+            return Ok(());
+        }
+
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(self.config.key_secret.trim())
+            .map_err(|_| ReportError::Invalid("TSIG key secret is not base64".into()))?;
+        let id = dns::query_id();
+        let mut msg = update_message(
+            id,
+            &self.config.zone,
+            &self.fqdn(),
+            self.config.ttl,
+            &addresses,
+        )?;
+
+        sign(&mut msg, &self.config.key_name, &secret, SystemTime::now())?;
+
+        let local = local_addr(&self.config.server)?;
+        let response = dns::exchange(&self.config.server, local, None, &msg, UPDATE_TIMEOUT)?;
+
+        match dns::response_code(&response, id)? {
+            0 if report.event == EventKind::Down => {
+                self.followed.release();
+                self.updated = None;
+                Ok(())
+            }
+            0 => {
+                self.updated = Some(addresses);
+                Ok(())
+            }
+            rcode => Err(DnsError::Rcode(rcode).into()),
+        }
+    }
+}
+
+/// Unspecified local address of the family of the `server` address
+fn local_addr(server: &str) -> Result<IpAddr, DnsError> {
+    let server_addr = server
+        .to_socket_addrs()
+        .map_err(|_| DnsError::Resolve(server.into()))?
+        .next()
+        .ok_or_else(|| DnsError::Resolve(server.into()))?;
+
+    Ok(match server_addr {
+        addr if addr.is_ipv4() => Ipv4Addr::UNSPECIFIED.into(),
+        _ => Ipv6Addr::UNSPECIFIED.into(),
+    })
+}
+
+/// Build UPDATE message that replaces the A and AAAA records of `name` with `addresses`
+/// Arguments:
+/// - `id` - message id
+/// - `zone` - updated zone
+/// - `name` - fully qualified updated domain name
+/// - `ttl` - TTL of the added records
+/// - `addresses` - new addresses, the records are only deleted if empty
+fn update_message(
+    id: u16,
+    zone: &str,
+    name: &str,
+    ttl: u32,
+    addresses: &[IpAddr],
+) -> Result<Vec<u8>, DnsError> {
+    let mut msg = Vec::with_capacity(512);
+    let mut updates: u16 = 0;
+
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&OPCODE_UPDATE.to_be_bytes());
+    // Zone, prerequisite, update and additional record counts
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    dns::encode_name(&mut msg, zone)?;
+    msg.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    for record in [DnsRecord::A, DnsRecord::Aaaa] {
+        let kind = dns::record_type(record);
+
+        // Delete the whole record set before adding the current addresses
+        dns::encode_name(&mut msg, name)?;
+        msg.extend_from_slice(&kind.to_be_bytes());
+        msg.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        msg.extend_from_slice(&[0; 6]);
+        updates += 1;
+
+        for addr in addresses {
+            let data = match (record, addr) {
+                (DnsRecord::A, IpAddr::V4(ip)) => ip.octets().to_vec(),
+                (DnsRecord::Aaaa, IpAddr::V6(ip)) => ip.octets().to_vec(),
+                _ => continue,
+            };
+
+            dns::encode_name(&mut msg, name)?;
+            msg.extend_from_slice(&kind.to_be_bytes());
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&ttl.to_be_bytes());
+            msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&data);
+            updates += 1;
+        }
+    }
+
+    msg[8..10].copy_from_slice(&updates.to_be_bytes());
+    Ok(msg)
+}
+
+/// Append TSIG record signing `msg` with HMAC-SHA256
+/// Arguments:
+/// - `msg` - complete message without additional records
+/// - `key_name` - TSIG key name
+/// - `secret` - TSIG key
+/// - `now` - signing time
+fn sign(msg: &mut Vec<u8>, key_name: &str, secret: &[u8], now: SystemTime) -> Result<(), DnsError> {
+    let time = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_be_bytes();
+    let mut algorithm = Vec::new();
+    let mut variables = Vec::new();
+
+    dns::encode_name(&mut algorithm, TSIG_ALGORITHM)?;
+    dns::encode_name(&mut variables, &key_name.to_ascii_lowercase())?;
+    variables.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    variables.extend_from_slice(&[0; 4]);
+    variables.extend_from_slice(&algorithm);
+    // Time signed is a 48-bit number
+    variables.extend_from_slice(&time[2..]);
+    variables.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    // No error and no other data
+    variables.extend_from_slice(&[0; 4]);
+
+    let mut hmac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key size");
+
+    hmac.update(msg);
+    hmac.update(&variables);
+
+    let mac = hmac.finalize().into_bytes();
+    let mut data = algorithm;
+
+    data.extend_from_slice(&time[2..]);
+    data.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    data.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    data.extend_from_slice(&mac);
+    data.extend_from_slice(&msg[..2]);
+    data.extend_from_slice(&[0; 4]);
+
+    dns::encode_name(msg, key_name)?;
+    msg.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+    msg.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    msg.extend_from_slice(&[0; 4]);
+    msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&data);
+
+    let additional = u16::from_be_bytes([msg[10], msg[11]]) + 1;
+
+    msg[10..HEADER_LEN].copy_from_slice(&additional.to_be_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod ddns_tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, SystemTime},
+    };
+
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use vpn_ip_tracker::{
+        report::ReportPayload,
+        sink::{DdnsAddress, DynDns2SinkConfig, Rfc2136SinkConfig},
+    };
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::ddns::{sign, update_message, DynDns2Reporter, Rfc2136Reporter};
+    use crate::testing::{DnsUpdateStandIn, HttpStandIn, Response};
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::Reporter;
+
+    fn up(addr: &str) -> ReportPayload {
+        IfaceEvent::Up {
+            iface: iface("tun0", addr),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    fn heartbeat(name: &str, addr: &str) -> ReportPayload {
+        IfaceEvent::Heartbeat {
+            iface: iface(name, addr),
+            session: Duration::from_secs(60),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    fn down(name: &str, addr: &str) -> ReportPayload {
+        IfaceEvent::Down {
+            previous: iface(name, addr),
+            session: Duration::from_secs(60),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    fn rfc2136(server: String) -> Rfc2136SinkConfig {
+        Rfc2136SinkConfig {
+            server,
+            zone: "vpn.example.com".into(),
+            record: "alice".into(),
+            ttl: 60,
+            key_name: "tracker-key".into(),
+            key_secret: "c2VjcmV0".into(),
+            address: DdnsAddress::Interface,
+            interface: None,
+        }
+    }
+
+    #[test]
+    fn test_dyndns2() {
+        let server = HttpStandIn::start(vec![
+            Response::new(200, "good 10.8.0.2"),
+            Response::new(200, "badauth"),
+        ]);
+        let mut reporter = DynDns2Reporter::new(
+            reqwest::blocking::Client::new(),
+            &DynDns2SinkConfig {
+                server: server.url("/"),
+                hostname: "alice.vpn.example.com".into(),
+                username: "alice".into(),
+                password: "secret".into(),
+                address: DdnsAddress::Interface,
+                interface: None,
+            },
+        );
+
+        reporter.report(&up("10.8.0.2")).unwrap();
+        reporter.report(&up("10.8.0.2")).unwrap();
+
+        let rejected = reporter.report(&up("10.8.0.3")).unwrap_err();
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[0].path,
+            "/nic/update?hostname=alice.vpn.example.com&myip=10.8.0.2"
+        );
+        assert_eq!(
+            requests[0].header("Authorization"),
+            Some("Basic YWxpY2U6c2VjcmV0")
+        );
+        assert!(requests[0]
+            .header("User-Agent")
+            .unwrap()
+            .starts_with("vpn-ip-tracker/"));
+        assert!(rejected.is_unauthorized());
+    }
+
+    #[test]
+    fn test_update_message() {
+        let addresses: Vec<IpAddr> = vec!["10.8.0.2".parse().unwrap(), "fd00::2".parse().unwrap()];
+        let msg = update_message(
+            0x1234,
+            "vpn.example.com",
+            "alice.vpn.example.com",
+            60,
+            &addresses,
+        )
+        .unwrap();
+
+        assert_eq!(msg[..12], [0x12, 0x34, 0x28, 0x00, 0, 1, 0, 0, 0, 4, 0, 0]);
+        assert_eq!(msg[12..29], *b"\x03vpn\x07example\x03com\x00");
+        // SOA of the zone, then the deletion of the A record set
+        assert_eq!(msg[29..33], [0, 6, 0, 1]);
+        assert_eq!(
+            msg[33..60],
+            *b"\x05alice\x03vpn\x07example\x03com\x00\x00\x01\x00\xff"
+        );
+        assert_eq!(msg[60..66], [0; 6]);
+        assert!(msg.ends_with(&"fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets()));
+    }
+
+    #[test]
+    fn test_sign() {
+        let mut msg =
+            update_message(7, "vpn.example.com", "alice.vpn.example.com", 60, &[]).unwrap();
+        let unsigned = msg.clone();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        sign(&mut msg, "Tracker-Key", b"secret", now).unwrap();
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+
+        hmac.update(&unsigned);
+        hmac.update(b"\x0btracker-key\x00\x00\xff\x00\x00\x00\x00");
+        hmac.update(b"\x0bhmac-sha256\x00\x00\x00\x65\x53\xf1\x00\x01\x2c\x00\x00\x00\x00");
+
+        let mac = hmac.finalize().into_bytes();
+        let record = &msg[unsigned.len()..];
+
+        assert_eq!(msg[10..12], [0, 1]);
+        assert_eq!(record[..13], *b"\x0bTracker-Key\x00");
+        assert_eq!(record[13..19], [0, 250, 0, 255, 0, 0]);
+        assert_eq!(record[23..36], *b"\x0bhmac-sha256\x00");
+        assert_eq!(record[36..44], [0, 0, 0x65, 0x53, 0xf1, 0, 1, 0x2c]);
+        assert_eq!(record[44..46], [0, 32]);
+        assert_eq!(record[46..78], mac[..]);
+        assert_eq!(record[78..], [0, 7, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_rfc2136() {
+        let server = DnsUpdateStandIn::start(&[0, 9]);
+        let mut reporter = Rfc2136Reporter::new(&rfc2136(server.addr().to_string()));
+
+        reporter.report(&up("10.8.0.2")).unwrap();
+
+        let refused = reporter.report(&up("10.8.0.3")).unwrap_err();
+        let messages = server.messages();
+
+        assert_eq!(reporter.fqdn(), "alice.vpn.example.com");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0][2..4], [0x28, 0x00]);
+        assert_eq!(messages[0][10..12], [0, 1]);
+        assert!(refused.is_unauthorized());
+    }
+
+    #[test]
+    fn test_followed_interface() {
+        let server = DnsUpdateStandIn::start(&[0, 0, 0, 0]);
+        let mut reporter = Rfc2136Reporter::new(&rfc2136(server.addr().to_string()));
+
+        // The first interface is followed, unchanged heartbeats and other interfaces are ignored
+        reporter.report(&up("10.8.0.2")).unwrap();
+        reporter.report(&heartbeat("tun0", "10.8.0.2")).unwrap();
+        reporter.report(&heartbeat("wg0", "10.9.0.2")).unwrap();
+        reporter.report(&down("wg0", "10.9.0.2")).unwrap();
+        assert_eq!(server.messages().len(), 1);
+
+        // After the followed interface went down, the next reported one is published
+        reporter.report(&down("tun0", "10.8.0.2")).unwrap();
+        reporter.report(&heartbeat("wg0", "10.9.0.2")).unwrap();
+        reporter.report(&heartbeat("wg0", "10.9.0.2")).unwrap();
+
+        let messages = server.messages();
+
+        assert_eq!(messages.len(), 3);
+        // No records added by the deletion
+        assert_eq!(messages[1][8..10], [0, 2]);
+        assert!(messages[2].windows(4).any(|data| data == [10, 9, 0, 2]));
+
+        let mut config = rfc2136(server.addr().to_string());
+
+        config.interface = Some("wg0".into());
+
+        let mut reporter = Rfc2136Reporter::new(&config);
+
+        reporter.report(&up("10.8.0.2")).unwrap();
+        reporter.report(&heartbeat("wg0", "10.9.0.2")).unwrap();
+        assert_eq!(server.messages().len(), 4);
+    }
+}
@@ -0,0 +1,278 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    io::{self, Read, Write},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use vpn_ip_tracker::{
+    report::{EventKind, ReportAddress, ReportPayload},
+    sink::ExecSinkConfig,
+};
+
+use crate::tracker::{ReportError, Reporter};
+
+/// Interval of checking whether the command exited
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Time to wait for the output of the command once it exited, its children may keep it open
+const OUTPUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reporter that runs a user command for every report except heartbeats
+/// The standard output of the command is logged as debug messages, the error output as warnings
+pub(crate) struct ExecReporter {
+    config: ExecSinkConfig,
+}
+
+impl ExecReporter {
+    pub(crate) fn new(config: &ExecSinkConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl Reporter for ExecReporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        if report.event == EventKind::Heartbeat {
+            return Ok(());
+        }
+
+        let Some((program, args)) = self.config.command.split_first() else {
+            return Err(ReportError::Invalid("empty command".into()));
+        };
+        let input = if self.config.stdin {
+            Some(serde_json::to_vec(report)?)
+        } else {
+            None
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .envs(environment(report))
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (sender, receiver) = mpsc::channel();
+
+        // Feed and drain the pipes in the background so that a chatty command cannot block
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            std::thread::spawn(move || stdin.write_all(&input));
+        }
+
+        if let Some(stdout) = child.stdout.take() {
+            let sender = sender.clone();
+
+            std::thread::spawn(move || sender.send((false, read_all(stdout))));
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            std::thread::spawn(move || sender.send((true, read_all(stderr))));
+        }
+
+        let status = wait(&mut child, Duration::from_secs(self.config.timeout))?;
+
+        while let Ok((is_error, output)) = receiver.recv_timeout(OUTPUT_TIMEOUT) {
+            for line in output.lines().filter(|line| !line.trim().is_empty()) {
+                if is_error {
+                    warn!("{}: {}", program, line);
+                } else {
+                    debug!("{}: {}", program, line);
+                }
+            }
+        }
+
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(ReportError::Exit(status)),
+            None => Err(ReportError::CommandTimeout),
+        }
+    }
+}
+
+/// Environment variables describing `report`
+fn environment(report: &ReportPayload) -> Vec<(&'static str, String)> {
+    vec![
+        ("VPN_IP_TRACKER_EVENT", report.event.as_str().into()),
+        ("VPN_IP_TRACKER_IFACE", report.interface.name.clone()),
+        ("VPN_IP_TRACKER_IP", join_addresses(&report.addresses)),
+        (
+            "VPN_IP_TRACKER_PREVIOUS_IP",
+            join_addresses(&report.previous_addresses),
+        ),
+        (
+            "VPN_IP_TRACKER_EGRESS",
+            report
+                .egress
+                .as_ref()
+                .map(|egress| egress.addr.to_string())
+                .unwrap_or_default(),
+        ),
+        ("VPN_IP_TRACKER_HOST", report.hostname.clone()),
+        (
+            "VPN_IP_TRACKER_DEVICE",
+            report.device.clone().unwrap_or_default(),
+        ),
+        ("VPN_IP_TRACKER_TIMESTAMP", report.timestamp.to_string()),
+    ]
+}
+
+fn join_addresses(addresses: &[ReportAddress]) -> String {
+    addresses
+        .iter()
+        .map(|addr| addr.addr.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn read_all(mut pipe: impl Read) -> String {
+    let mut output = Vec::new();
+    let _ = pipe.read_to_end(&mut output);
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Wait for `child` to exit, it is killed once `timeout` elapses
+/// Returns exit status of the command, none if it was killed
+fn wait(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if Instant::now() >= deadline {
+            // Kill fails only if the command exited meanwhile
+            let _ = child.kill();
+            child.wait()?;
+            return Ok(None);
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod exec_tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use vpn_ip_tracker::{
+        report::{EventKind, ReportPayload},
+        sink::ExecSinkConfig,
+    };
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::exec::ExecReporter;
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::{ReportError, Reporter};
+
+    fn output(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vpn-ip-tracker-exec-{}-{name}", std::process::id()))
+    }
+
+    fn changed() -> ReportPayload {
+        IfaceEvent::Changed {
+            previous: iface("tun0", "10.8.0.2"),
+            iface: iface("tun0", "10.8.0.3"),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    fn run(script: &str, stdin: bool, timeout: u64) -> Result<(), ReportError> {
+        let config = ExecSinkConfig {
+            command: vec!["sh".into(), "-c".into(), script.into()],
+            stdin,
+            timeout,
+        };
+
+        ExecReporter::new(&config).report(&changed())
+    }
+
+    #[test]
+    fn test_environment() {
+        let path = output("env");
+
+        run(
+            &format!(
+                "echo \"$VPN_IP_TRACKER_EVENT $VPN_IP_TRACKER_IFACE $VPN_IP_TRACKER_PREVIOUS_IP \
+                 $VPN_IP_TRACKER_IP $VPN_IP_TRACKER_HOST\" > {}",
+                path.display()
+            ),
+            false,
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "changed tun0 10.8.0.2 10.8.0.3 laptop\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stdin() {
+        let path = output("stdin");
+
+        run(&format!("cat > {}", path.display()), true, 10).unwrap();
+
+        let report: ReportPayload =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(report.event, EventKind::Changed);
+        assert_eq!(report.interface.name, "tun0");
+        assert_eq!(report.addresses[0].addr.to_string(), "10.8.0.3");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let path = output("heartbeat");
+        let config = ExecSinkConfig {
+            command: vec!["touch".into(), path.display().to_string()],
+            stdin: false,
+            timeout: 10,
+        };
+        let heartbeat = IfaceEvent::Heartbeat {
+            iface: iface("tun0", "10.8.0.2"),
+            session: Duration::from_secs(60),
+        }
+        .payload("laptop", None, SystemTime::now());
+
+        ExecReporter::new(&config).report(&heartbeat).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_failures() {
+        let failed = run("echo failed >&2; exit 1", false, 10).unwrap_err();
+        let temporary = run("exit 75", false, 10).unwrap_err();
+        let timed_out = run("sleep 10", false, 1).unwrap_err();
+
+        assert!(matches!(failed, ReportError::Exit(status) if status.code() == Some(1)));
+        assert!(!failed.is_retryable());
+        assert!(temporary.is_retryable());
+        assert!(matches!(timed_out, ReportError::CommandTimeout));
+        assert!(timed_out.is_retryable());
+
+        let empty = ExecReporter::new(&ExecSinkConfig {
+            command: Vec::new(),
+            stdin: false,
+            timeout: 10,
+        })
+        .report(&changed())
+        .unwrap_err();
+
+        assert!(matches!(empty, ReportError::Invalid(_)));
+    }
+}
@@ -0,0 +1,269 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use vpn_ip_tracker::{
+    report::{EventKind, ReportPayload},
+    sink::{FileSinkConfig, StateFileFormat},
+};
+
+use crate::tracker::{ReportError, Reporter};
+
+/// Last reported state of an interface
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct IfaceState {
+    event: String,
+    /// Unix time in seconds of the last report
+    timestamp: u64,
+    /// Current addresses, empty if the interface is down
+    addresses: Vec<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    egress: Option<IpAddr>,
+}
+
+/// Content of the JSON state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    interfaces: BTreeMap<String, IfaceState>,
+}
+
+/// Reporter that keeps the current state of all reported interfaces in a local file
+/// The file is replaced atomically, so readers never see a partial one; heartbeats do not
+/// change the state and are not written
+pub(crate) struct FileReporter {
+    path: Option<PathBuf>,
+    format: StateFileFormat,
+    interfaces: BTreeMap<String, IfaceState>,
+}
+
+impl FileReporter {
+    /// Create reporter that continues with the state file left by the previous run
+    pub(crate) fn new(config: &FileSinkConfig) -> Self {
+        let path = config.state_path();
+        let interfaces = match &path {
+            Some(path) => load(path, config.format),
+            None => BTreeMap::new(),
+        };
+
+        Self {
+            path,
+            format: config.format,
+            interfaces,
+        }
+    }
+
+    fn render(&self) -> Result<String, ReportError> {
+        match self.format {
+            StateFileFormat::Json => {
+                let state = StateFile {
+                    interfaces: self.interfaces.clone(),
+                };
+
+                Ok(serde_json::to_string_pretty(&state)?)
+            }
+            StateFileFormat::Plain => Ok(self
+                .interfaces
+                .iter()
+                .map(|(name, state)| {
+                    let mut line = format!("{}\t{}\t{}", name, state.event, state.timestamp);
+
+                    for addr in &state.addresses {
+                        line += &format!("\t{addr}");
+                    }
+
+                    line + "\n"
+                })
+                .collect()),
+        }
+    }
+}
+
+impl Reporter for FileReporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        if report.event == EventKind::Heartbeat {
+            return Ok(());
+        }
+
+        let Some(path) = &self.path else {
+            return Err(ReportError::Invalid(
+                "no directory for the state file".into(),
+            ));
+        };
+        let path = path.clone();
+
+        self.interfaces.insert(
+            report.interface.name.clone(),
+            IfaceState {
+                event: report.event.as_str().into(),
+                timestamp: report.timestamp,
+                addresses: report.addresses.iter().map(|addr| addr.addr).collect(),
+                egress: report.egress.as_ref().map(|egress| egress.addr),
+            },
+        );
+        // Readable only by the user, the file tells where the user connects from
+        vpn_ip_tracker::write_atomic(&path, self.render()?.as_bytes(), Some(0o600))?;
+        debug!("Update state file {}", path.display());
+
+        Ok(())
+    }
+}
+
+/// Read interface states from the state file at `path`
+/// A missing or unreadable file gives no states, they are written again by the next reports
+fn load(path: &Path, format: StateFileFormat) -> BTreeMap<String, IfaceState> {
+    let Ok(data) = fs::read_to_string(path) else {
+        return BTreeMap::new();
+    };
+    let interfaces = match format {
+        StateFileFormat::Json => serde_json::from_str::<StateFile>(&data)
+            .ok()
+            .map(|state| state.interfaces),
+        StateFileFormat::Plain => data.lines().map(parse_line).collect(),
+    };
+
+    interfaces.unwrap_or_else(|| {
+        warn!("Discard unreadable state file {}", path.display());
+        BTreeMap::new()
+    })
+}
+
+fn parse_line(line: &str) -> Option<(String, IfaceState)> {
+    let mut fields = line.split('\t');
+    let name = fields.next()?.to_string();
+    let event = fields.next()?.to_string();
+    let timestamp = fields.next()?.parse().ok()?;
+    let addresses = fields.map(str::parse).collect::<Result<_, _>>().ok()?;
+
+    Some((
+        name,
+        IfaceState {
+            event,
+            timestamp,
+            addresses,
+            egress: None,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod file_tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use vpn_ip_tracker::sink::{FileSinkConfig, StateFileFormat};
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::file::FileReporter;
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::Reporter;
+
+    fn path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("vpn-ip-tracker-file-{}", std::process::id()))
+            .join(name)
+    }
+
+    fn report_all(reporter: &mut FileReporter) {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let events = [
+            IfaceEvent::Up {
+                iface: iface("tun0", "10.8.0.2"),
+            },
+            IfaceEvent::Up {
+                iface: iface("wg0", "10.9.0.2"),
+            },
+            IfaceEvent::Heartbeat {
+                iface: iface("wg0", "10.9.0.2"),
+                session: Duration::from_secs(60),
+            },
+            IfaceEvent::Down {
+                previous: iface("tun0", "10.8.0.2"),
+                session: Duration::from_secs(60),
+            },
+        ];
+
+        for event in events {
+            reporter
+                .report(&event.payload("laptop", None, time))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_json_state() {
+        let config = FileSinkConfig {
+            path: Some(path("state.json")),
+            format: StateFileFormat::Json,
+        };
+        let mut reporter = FileReporter::new(&config);
+
+        report_all(&mut reporter);
+
+        let data = std::fs::read_to_string(path("state.json")).unwrap();
+        let state: serde_json::Value = serde_json::from_str(&data).unwrap();
+        let restored = FileReporter::new(&config);
+
+        assert_eq!(state["interfaces"]["tun0"]["event"], "down");
+        assert_eq!(
+            state["interfaces"]["tun0"]["addresses"],
+            serde_json::json!([])
+        );
+        assert_eq!(state["interfaces"]["wg0"]["event"], "up");
+        assert_eq!(state["interfaces"]["wg0"]["timestamp"], 1_000);
+        assert_eq!(state["interfaces"]["wg0"]["addresses"][0], "10.9.0.2");
+        assert_eq!(restored.interfaces, reporter.interfaces);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(path("state.json"))
+                .unwrap()
+                .permissions()
+                .mode();
+
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_file(path("state.json")).unwrap();
+    }
+
+    #[test]
+    fn test_plain_state() {
+        let config = FileSinkConfig {
+            path: Some(path("prompt-state")),
+            format: StateFileFormat::Plain,
+        };
+        let mut reporter = FileReporter::new(&config);
+        // Windows names the interfaces like "Ethernet 2"
+        let spaced = IfaceEvent::Up {
+            iface: iface("Ethernet 2", "10.10.0.2"),
+        };
+
+        report_all(&mut reporter);
+        reporter
+            .report(&spaced.payload(
+                "laptop",
+                None,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_000),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(path("prompt-state")).unwrap(),
+            "Ethernet 2\tup\t1000\t10.10.0.2\ntun0\tdown\t1000\nwg0\tup\t1000\t10.9.0.2\n"
+        );
+        assert_eq!(FileReporter::new(&config).interfaces, reporter.interfaces);
+
+        std::fs::remove_file(path("prompt-state")).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    io,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::header::{HeaderMap, HeaderValue};
+
+use vpn_ip_tracker::{
+    report::{EventKind, ReportFormat, ReportPayload},
+    signature::{self, Signature},
+    sink::HttpSinkConfig,
+};
+
+use crate::tracker::{ReportError, Reporter};
+
+/// Reporter that posts reports to a report service
+pub(crate) struct HttpReporter {
+    client: reqwest::blocking::Client,
+    config: HttpSinkConfig,
+}
+
+impl HttpReporter {
+    pub(crate) fn new(client: reqwest::blocking::Client, config: &HttpSinkConfig) -> Self {
+        Self {
+            client,
+            config: config.clone(),
+        }
+    }
+}
+
+impl Reporter for HttpReporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        send_report(self.client.clone(), report, &self.config)
+    }
+}
+
+pub(crate) fn send_report(
+    client: reqwest::blocking::Client,
+    report: &ReportPayload,
+    config: &HttpSinkConfig,
+) -> Result<(), ReportError> {
+    let mut headers = prepare_headers(report);
+    let data = match config.format {
+        ReportFormat::Plain => report
+            .addresses
+            .iter()
+            .map(|addr| addr.addr.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        ReportFormat::Json => {
+            headers.insert(
+                reqwest::header::CONTENT_TYPE,
+                reqwest::header::HeaderValue::from_static("application/json"),
+            );
+            serde_json::to_string(report)?
+        }
+    };
+
+    if config.sign {
+        let url = reqwest::Url::parse(&config.url)
+            .map_err(|_| ReportError::Invalid(format!("URL '{}'", config.url)))?;
+
+        sign(&mut headers, &config.token, &url, data.as_bytes())?;
+    } else {
+        let mut token = HeaderValue::from_str(&config.token)
+            .map_err(|_| ReportError::Invalid("token".into()))?;
+
+        token.set_sensitive(true);
+        headers.insert("Credential", token);
+    }
+
+    send(client.post(&config.url).headers(headers).body(data))
+}
+
+/// Add headers with the signature of the POST request to `url` with `body`
+/// The nonce comes from the random generator of the operating system, so it cannot be predicted
+fn sign(
+    headers: &mut HeaderMap,
+    token: &str,
+    url: &reqwest::Url,
+    body: &[u8],
+) -> Result<(), ReportError> {
+    let mut path = url.path().to_string();
+    let mut nonce = [0u8; 16];
+
+    if let Some(query) = url.query() {
+        path = format!("{path}?{query}");
+    }
+
+    getrandom::getrandom(&mut nonce).map_err(io::Error::from)?;
+
+    let nonce: String = nonce.iter().map(|byte| format!("{byte:02x}")).collect();
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let signature = Signature::new(token, timestamp, &nonce, "POST", &path, body);
+
+    headers.insert(
+        signature::KEY_ID_HEADER,
+        HeaderValue::from_str(&signature::key_id(token)).unwrap(),
+    );
+
+    for (name, value) in signature.headers() {
+        headers.insert(name, HeaderValue::from_str(&value).unwrap());
+    }
+
+    Ok(())
+}
+
+/// Send `request`, error statuses are turned into report errors
+pub(crate) fn send(request: reqwest::blocking::RequestBuilder) -> Result<(), ReportError> {
+    check_status(&request.send()?)
+}
+
+/// Turn the error status of `response` into a report error
+pub(crate) fn check_status(response: &reqwest::blocking::Response) -> Result<(), ReportError> {
+    let status = response.status();
+
+    if status.is_client_error() || status.is_server_error() {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs);
+
+        return Err(ReportError::Status {
+            status: status.as_u16(),
+            retry_after,
+        });
+    }
+
+    Ok(())
+}
+
+fn prepare_headers(report: &ReportPayload) -> reqwest::header::HeaderMap {
+    let mut header = reqwest::header::HeaderMap::new();
+
+    header.insert(
+        "Event",
+        reqwest::header::HeaderValue::from_static(report.event.as_str()),
+    );
+
+    if !report.interface.name.is_empty() {
+        if let Ok(iface_name) = reqwest::header::HeaderValue::from_str(&report.interface.name) {
+            header.insert("Interface", iface_name);
+        }
+    }
+
+    if let Some(session) = report.session_duration {
+        header.insert("Session-Duration", session.into());
+    }
+
+    if let Some(uptime) = report.uptime {
+        header.insert("Uptime", uptime.into());
+    }
+
+    if report.event != EventKind::Down {
+        let iface = &report.interface;
+
+        if let Some(peer) = iface.peer {
+            header.insert("Peer", peer.to_string().parse().unwrap());
+        }
+
+        if let Some(mtu) = iface.mtu {
+            header.insert("Mtu", mtu.into());
+        }
+
+        if let Some(tunnel) = iface.tunnel {
+            header.insert(
+                "Tunnel",
+                reqwest::header::HeaderValue::from_static(tunnel.as_str()),
+            );
+        }
+    }
+
+    if let Some(egress) = &report.egress {
+        header.insert("Egress-Ip", egress.addr.to_string().parse().unwrap());
+
+        if let Some(warning) = egress.warning.as_ref().and_then(|w| w.parse().ok()) {
+            header.insert("Egress-Warning", warning);
+        }
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod http_tests {
+    use std::time::{Duration, SystemTime};
+
+    use vpn_ip_tracker::{
+        report::{EventKind, ReportFormat, ReportPayload},
+        signature::{key_id, Signature, SignatureError, Verifier, DEFAULT_MAX_AGE, KEY_ID_HEADER},
+        sink::HttpSinkConfig,
+    };
+
+    use crate::egress::EgressIp;
+    use crate::events::IfaceEvent;
+    use crate::sinks::http::send_report;
+    use crate::testing::{HttpStandIn, Response};
+    use crate::tracker::tracker_tests::iface;
+
+    fn config(url: String, format: ReportFormat) -> HttpSinkConfig {
+        HttpSinkConfig {
+            url,
+            token: "secret".into(),
+            format,
+            sign: false,
+        }
+    }
+
+    fn payload(event: IfaceEvent) -> ReportPayload {
+        event.payload("laptop", Some("office-laptop"), SystemTime::now())
+    }
+
+    #[test]
+    fn test_send_report() {
+        let server = HttpStandIn::start(vec![Response::new(200, "")]);
+        let config = config(server.url("/report"), ReportFormat::Plain);
+        let mut current = iface("tun0", "10.8.0.2");
+
+        current.egress = Some(EgressIp {
+            addr: "198.51.100.7".parse().unwrap(),
+            warning: Some("2 of 3 providers agree".into()),
+        });
+        send_report(
+            reqwest::blocking::Client::new(),
+            &payload(IfaceEvent::Up { iface: current }),
+            &config,
+        )
+        .unwrap();
+
+        let request = &server.requests()[0];
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/report");
+        assert_eq!(request.body, "10.8.0.2");
+        assert_eq!(request.header("Credential"), Some("secret"));
+        assert_eq!(request.header("Event"), Some("up"));
+        assert_eq!(request.header("Egress-Ip"), Some("198.51.100.7"));
+        assert_eq!(
+            request.header("Egress-Warning"),
+            Some("2 of 3 providers agree")
+        );
+    }
+
+    #[test]
+    fn test_send_json_report() {
+        let server = HttpStandIn::start(vec![Response::new(200, "")]);
+        let config = config(server.url("/report"), ReportFormat::Json);
+
+        send_report(
+            reqwest::blocking::Client::new(),
+            &payload(IfaceEvent::Down {
+                previous: iface("tun0", "10.8.0.2"),
+                session: Duration::from_secs(90),
+            }),
+            &config,
+        )
+        .unwrap();
+
+        let request = &server.requests()[0];
+        let payload: ReportPayload = serde_json::from_str(&request.body).unwrap();
+
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(payload.event, EventKind::Down);
+        assert_eq!(payload.device.as_deref(), Some("office-laptop"));
+        assert_eq!(payload.interface.name, "tun0");
+        assert!(payload.addresses.is_empty());
+        assert_eq!(payload.previous_addresses[0].addr.to_string(), "10.8.0.2");
+        assert_eq!(payload.session_duration, Some(90));
+    }
+
+    #[test]
+    fn test_signed_report() {
+        let server = HttpStandIn::start(vec![Response::new(200, "")]);
+        let config = HttpSinkConfig {
+            sign: true,
+            ..config(server.url("/report?host=laptop"), ReportFormat::Json)
+        };
+
+        send_report(
+            reqwest::blocking::Client::new(),
+            &payload(IfaceEvent::Up {
+                iface: iface("tun0", "10.8.0.2"),
+            }),
+            &config,
+        )
+        .unwrap();
+
+        let request = &server.requests()[0];
+        let signature = Signature::from_headers(|name| request.header(name)).unwrap();
+        let mut verifier = Verifier::new("secret", DEFAULT_MAX_AGE);
+        let mut verify = |path| {
+            verifier.verify(
+                &signature,
+                &request.method,
+                path,
+                request.body.as_bytes(),
+                SystemTime::now(),
+            )
+        };
+
+        assert_eq!(request.header("Credential"), None);
+        assert_eq!(
+            request.header(KEY_ID_HEADER),
+            Some(key_id("secret").as_str())
+        );
+        assert_eq!(verify("/report"), Err(SignatureError::Mismatch));
+        assert_eq!(verify("/report?host=laptop"), Ok(()));
+        assert_eq!(verify("/report?host=laptop"), Err(SignatureError::Replayed));
+    }
+
+    #[test]
+    fn test_rejected_report() {
+        let mut rate_limited = Response::new(429, "");
+
+        rate_limited
+            .headers
+            .push(("Retry-After".into(), "7".into()));
+
+        let server = HttpStandIn::start(vec![rate_limited, Response::new(401, "")]);
+        let config = config(server.url("/report"), ReportFormat::Plain);
+        let report = payload(IfaceEvent::Up {
+            iface: iface("tun0", "10.8.0.2"),
+        });
+        let client = reqwest::blocking::Client::new();
+
+        let rate_limited = send_report(client.clone(), &report, &config).unwrap_err();
+        let unauthorized = send_report(client, &report, &config).unwrap_err();
+
+        assert!(rate_limited.is_retryable());
+        assert_eq!(rate_limited.retry_after(), Some(Duration::from_secs(7)));
+        assert!(unauthorized.is_unauthorized());
+    }
+}
@@ -0,0 +1,94 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Delivery of the reports to the configured sinks
+use std::time::Duration;
+
+use log::warn;
+
+use vpn_ip_tracker::{
+    outbox::{Outbox, OutboxConfig},
+    sink::{SinkConfig, SinkKind},
+    TrackerConfig,
+};
+
+use crate::retry::RetryingReporter;
+use crate::tracker::{Reporter, Sink};
+
+pub(crate) mod ddns;
+pub(crate) mod exec;
+pub(crate) mod file;
+pub(crate) mod http;
+pub(crate) mod mqtt;
+pub(crate) mod telegram;
+pub(crate) mod template;
+pub(crate) mod webhook;
+
+/// Create sinks that deliver reports as described by `sinks`
+/// Arguments:
+/// - `sinks` - sink configurations
+/// - `config` - tracker configuration with the settings shared by the sinks
+pub(crate) fn create(sinks: Vec<SinkConfig>, config: &TrackerConfig) -> Vec<Sink> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    sinks
+        .into_iter()
+        .map(|sink| {
+            let outbox = outbox(&sink.name, &config.outbox);
+            let retry = sink.retry.as_ref().unwrap_or(&config.retry);
+            let reporter: Box<dyn Reporter> = match &sink.kind {
+                SinkKind::Http(http) => Box::new(RetryingReporter::new(
+                    http::HttpReporter::new(client.clone(), http),
+                    retry,
+                )),
+                SinkKind::Telegram(telegram) => Box::new(RetryingReporter::new(
+                    telegram::TelegramReporter::new(
+                        client.clone(),
+                        telegram,
+                        telegram::TelegramReporter::messages_path(&sink.name),
+                    ),
+                    retry,
+                )),
+                SinkKind::Webhook(webhook) => Box::new(RetryingReporter::new(
+                    webhook::WebhookReporter::new(client.clone(), webhook),
+                    retry,
+                )),
+                SinkKind::DynDns2(dyndns) => Box::new(RetryingReporter::new(
+                    ddns::DynDns2Reporter::new(client.clone(), dyndns),
+                    retry,
+                )),
+                SinkKind::Rfc2136(rfc2136) => Box::new(RetryingReporter::new(
+                    ddns::Rfc2136Reporter::new(rfc2136),
+                    retry,
+                )),
+                SinkKind::Mqtt(mqtt) => {
+                    Box::new(RetryingReporter::new(mqtt::MqttReporter::new(mqtt), retry))
+                }
+                SinkKind::File(file) => {
+                    Box::new(RetryingReporter::new(file::FileReporter::new(file), retry))
+                }
+                SinkKind::Exec(exec) => {
+                    Box::new(RetryingReporter::new(exec::ExecReporter::new(exec), retry))
+                }
+            };
+
+            Sink::new(sink, outbox, reporter)
+        })
+        .collect()
+}
+
+/// Open the queue of the undelivered reports of the sink named `name`
+/// An unreadable queue is discarded, the queue is kept in memory without a state directory
+fn outbox(name: &str, config: &OutboxConfig) -> Outbox {
+    match Outbox::sink_path(name) {
+        Some(path) => Outbox::open(&path, config).unwrap_or_else(|e| {
+            warn!("Discard unreadable outbox {}: {}", path.display(), e);
+            Outbox::empty(&path, config)
+        }),
+        None => {
+            warn!("State directory is not available, reports of {name} are kept in memory");
+            Outbox::in_memory(config)
+        }
+    }
+}
@@ -0,0 +1,453 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::Duration,
+};
+
+use log::debug;
+use serde_json::json;
+
+use vpn_ip_tracker::{report::ReportPayload, sink::MqttSinkConfig};
+
+use crate::mqtt::{Connection, Message, Options};
+use crate::sinks::template;
+use crate::tracker::{ReportError, Reporter};
+
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+/// Reporter that publishes the interface state as retained MQTT messages
+/// The connection is kept open between the reports and pinged by a background thread, so the
+/// broker publishes the `offline` last will soon after the tracker is gone. A broken connection
+/// is opened again by the next ping or report, whichever comes first
+pub(crate) struct MqttReporter {
+    config: MqttSinkConfig,
+    link: Option<Arc<Link>>,
+}
+
+/// Settings of the broker session rendered for the tracker host
+struct Session {
+    config: MqttSinkConfig,
+    client_id: String,
+    availability: String,
+}
+
+/// Broker session shared with the keep-alive thread
+struct Link {
+    session: Session,
+    state: Mutex<LinkState>,
+    /// Set once a ping or a publish fails
+    broken: AtomicBool,
+}
+
+struct LinkState {
+    connection: Connection,
+    /// Discovery topics published over the current connection
+    discovered: HashSet<String>,
+}
+
+impl MqttReporter {
+    pub(crate) fn new(config: &MqttSinkConfig) -> Self {
+        Self {
+            config: config.clone(),
+            link: None,
+        }
+    }
+
+    /// Connect to the broker and announce the tracker is online
+    /// The topics and the client id are rendered with the variables of `report`
+    fn connect(&mut self, report: &ReportPayload) -> Result<Arc<Link>, ReportError> {
+        let session = Session {
+            config: self.config.clone(),
+            client_id: template::render(&self.config.client_id, report, template::no_escape),
+            availability: template::render(
+                &self.config.availability_topic,
+                report,
+                template::no_escape,
+            ),
+        };
+        let connection = session.open()?;
+        let link = Arc::new(Link {
+            session,
+            state: Mutex::new(LinkState {
+                connection,
+                discovered: HashSet::new(),
+            }),
+            broken: AtomicBool::new(false),
+        });
+        let keep_alive = Duration::from_secs(self.config.keep_alive);
+
+        if !keep_alive.is_zero() {
+            let pinged = Arc::downgrade(&link);
+
+            std::thread::spawn(move || keep_alive_loop(pinged, keep_alive / 2));
+        }
+
+        self.link = Some(link.clone());
+
+        Ok(link)
+    }
+}
+
+impl Session {
+    fn message(&self, topic: &str, payload: &[u8]) -> Message {
+        Message {
+            topic: topic.into(),
+            payload: payload.to_vec(),
+            qos: self.config.qos,
+            retain: true,
+        }
+    }
+
+    /// Open connection to the broker and announce the tracker is online
+    fn open(&self) -> Result<Connection, ReportError> {
+        let will = self.message(&self.availability, OFFLINE);
+        let mut connection = Connection::open(
+            &self.config.broker,
+            &Options {
+                version: self.config.protocol,
+                client_id: &self.client_id,
+                username: self.config.username.as_deref(),
+                password: self.config.password.as_deref(),
+                ca_cert: self.config.ca_cert.as_deref(),
+                keep_alive: Duration::from_secs(self.config.keep_alive),
+                will: Some(&will),
+            },
+        )?;
+
+        connection.publish(&self.message(&self.availability, ONLINE))?;
+
+        Ok(connection)
+    }
+}
+
+impl Link {
+    /// Lock the session, the connection is opened again first if it is broken
+    fn lock(&self) -> Result<MutexGuard<'_, LinkState>, ReportError> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| ReportError::Invalid("MQTT session is poisoned".into()))?;
+
+        if self.broken.load(Ordering::SeqCst) {
+            state.connection = self.session.open()?;
+            state.discovered.clear();
+            self.broken.store(false, Ordering::SeqCst);
+            debug!("Reconnected to MQTT broker {}", self.session.config.broker);
+        }
+
+        Ok(state)
+    }
+
+    /// Publish state of the `report` interface with its discovery config if not published yet
+    /// Reports without an interface, i.e. heartbeats when no interface is up, publish nothing
+    fn publish(&self, report: &ReportPayload) -> Result<(), ReportError> {
+        if report.interface.name.is_empty() {
+            return Ok(());
+        }
+
+        let session = &self.session;
+        let topic = template::render(&session.config.topic, report, template::no_escape);
+        let mut state = self.lock()?;
+        let state = &mut *state;
+
+        if let Some(prefix) = &session.config.discovery_prefix {
+            let (config_topic, config) = discovery(prefix, &topic, &session.availability, report);
+
+            if !state.discovered.contains(&config_topic) {
+                state
+                    .connection
+                    .publish(&session.message(&config_topic, config.to_string().as_bytes()))?;
+                state.discovered.insert(config_topic);
+            }
+        }
+
+        state
+            .connection
+            .publish(&session.message(&topic, &serde_json::to_vec(report)?))?;
+
+        Ok(())
+    }
+}
+
+impl Reporter for MqttReporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        if self.config.qos > 2 {
+            return Err(ReportError::Invalid(format!("QoS {}", self.config.qos)));
+        }
+
+        let link = match &self.link {
+            Some(link) => link.clone(),
+            None => self.connect(report)?,
+        };
+        let result = link.publish(report);
+
+        if result.is_err() {
+            // Open a new connection for the next ping or report
+            link.broken.store(true, Ordering::SeqCst);
+        }
+
+        result
+    }
+}
+
+impl Drop for MqttReporter {
+    fn drop(&mut self) {
+        let Some(link) = self.link.take() else {
+            return;
+        };
+
+        if link.broken.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let offline = link.session.message(&link.session.availability, OFFLINE);
+        let Ok(mut state) = link.state.lock() else {
+            return;
+        };
+
+        let _ = state.connection.publish(&offline);
+        let _ = state.connection.disconnect();
+    }
+}
+
+/// Ping the broker every `interval` until the reporter is dropped
+/// A failed ping marks the connection broken, the next tick opens it again and announces the
+/// tracker is online
+fn keep_alive_loop(link: Weak<Link>, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+
+        let Some(link) = link.upgrade() else {
+            return;
+        };
+        let result = link
+            .lock()
+            .and_then(|mut state| Ok(state.connection.ping()?));
+
+        if let Err(e) = result {
+            debug!(
+                "MQTT broker {} is unreachable: {}",
+                link.session.config.broker, e
+            );
+            link.broken.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Home Assistant discovery topic and config of the sensor holding the interface addresses
+fn discovery(
+    prefix: &str,
+    state_topic: &str,
+    availability_topic: &str,
+    report: &ReportPayload,
+) -> (String, serde_json::Value) {
+    let node = object_id(&format!("vpn_ip_tracker_{}", report.hostname));
+    let object = object_id(&report.interface.name);
+    let topic = format!(
+        "{}/sensor/{node}/{object}/config",
+        prefix.trim_end_matches('/')
+    );
+    let config = json!({
+        "name": format!("{} address", report.interface.name),
+        "unique_id": format!("{node}_{object}"),
+        "state_topic": state_topic,
+        "value_template": "{{ value_json.addresses | map(attribute='addr') | join(', ') }}",
+        "json_attributes_topic": state_topic,
+        "availability_topic": availability_topic,
+        "icon": "mdi:vpn",
+        "device": {
+            "identifiers": [node],
+            "name": format!("{} VPN", report.hostname),
+            "sw_version": report.tracker_version,
+        },
+    });
+
+    (topic, config)
+}
+
+/// Replace characters not allowed in the discovery ids
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod mqtt_tests {
+    use std::time::SystemTime;
+
+    use vpn_ip_tracker::{
+        report::ReportPayload,
+        sink::{MqttSinkConfig, MqttVersion},
+    };
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::mqtt::MqttReporter;
+    use crate::testing::{MqttRecord, MqttStandIn};
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::Reporter;
+
+    fn config(broker: &MqttStandIn) -> MqttSinkConfig {
+        MqttSinkConfig {
+            broker: broker.url(),
+            protocol: MqttVersion::V311,
+            client_id: "vpn-ip-tracker-{host}".into(),
+            username: Some("tracker".into()),
+            password: Some("secret".into()),
+            ca_cert: None,
+            topic: "vpn-ip-tracker/{host}/{iface}".into(),
+            availability_topic: "vpn-ip-tracker/{host}/availability".into(),
+            qos: 1,
+            keep_alive: 60,
+            discovery_prefix: None,
+        }
+    }
+
+    fn up(name: &str, addr: &str) -> ReportPayload {
+        IfaceEvent::Up {
+            iface: iface(name, addr),
+        }
+        .payload("laptop.lan", None, SystemTime::now())
+    }
+
+    #[test]
+    fn test_retained_state() {
+        let broker = MqttStandIn::start(&[]);
+        let mut reporter = MqttReporter::new(&config(&broker));
+
+        reporter.report(&up("tun0", "10.8.0.2")).unwrap();
+        reporter.report(&up("tun0", "10.8.0.3")).unwrap();
+
+        let record = broker.record();
+        let connect = &record.connects[0];
+        let will = connect.will.as_ref().unwrap();
+        let state: serde_json::Value = serde_json::from_str(
+            &record
+                .retained("vpn-ip-tracker/laptop.lan/tun0")
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+
+        assert_eq!(record.connects.len(), 1);
+        assert_eq!(connect.client_id, "vpn-ip-tracker-laptop.lan");
+        assert_eq!(connect.username.as_deref(), Some("tracker"));
+        assert_eq!(connect.password.as_deref(), Some("secret"));
+        assert_eq!(connect.keep_alive, 60);
+        assert_eq!(will.topic, "vpn-ip-tracker/laptop.lan/availability");
+        assert_eq!(will.payload, "offline");
+        assert!(will.retain);
+        assert_eq!(record.retained(&will.topic).unwrap().payload, "online");
+        assert_eq!(state["addresses"][0]["addr"], "10.8.0.3");
+
+        drop(reporter);
+
+        let record = broker.wait(|record| record.disconnects == 1);
+
+        assert_eq!(
+            record
+                .retained("vpn-ip-tracker/laptop.lan/availability")
+                .unwrap()
+                .payload,
+            "offline"
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        let broker = MqttStandIn::start(&[]);
+        let mut reporter = MqttReporter::new(&MqttSinkConfig {
+            protocol: MqttVersion::V5,
+            discovery_prefix: Some("homeassistant".into()),
+            ..config(&broker)
+        });
+
+        reporter.report(&up("tun0", "10.8.0.2")).unwrap();
+        reporter.report(&up("tun0", "10.8.0.3")).unwrap();
+        reporter.report(&up("wg.home", "10.9.0.2")).unwrap();
+
+        let record = broker.record();
+        let configs: Vec<&str> = record
+            .messages
+            .iter()
+            .map(|it| it.topic.as_str())
+            .filter(|topic| topic.starts_with("homeassistant/"))
+            .collect();
+        let config: serde_json::Value = serde_json::from_str(
+            &record
+                .retained("homeassistant/sensor/vpn_ip_tracker_laptop_lan/wg_home/config")
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+
+        assert_eq!(record.connects[0].level, 5);
+        assert_eq!(configs.len(), 2);
+        assert_eq!(config["state_topic"], "vpn-ip-tracker/laptop.lan/wg.home");
+        assert_eq!(
+            config["availability_topic"],
+            "vpn-ip-tracker/laptop.lan/availability"
+        );
+        assert_eq!(config["unique_id"], "vpn_ip_tracker_laptop_lan_wg_home");
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let broker = MqttStandIn::start(&[]);
+        let mut reporter = MqttReporter::new(&MqttSinkConfig {
+            keep_alive: 1,
+            ..config(&broker)
+        });
+
+        reporter.report(&up("tun0", "10.8.0.2")).unwrap();
+        broker.wait(|record| record.pings >= 2);
+    }
+
+    #[test]
+    fn test_keep_alive_reconnect() {
+        let broker = MqttStandIn::start(&[]);
+        let mut reporter = MqttReporter::new(&MqttSinkConfig {
+            keep_alive: 1,
+            ..config(&broker)
+        });
+        let online = |record: &MqttRecord| {
+            record
+                .messages
+                .iter()
+                .filter(|it| it.topic.ends_with("/availability") && it.payload == "online")
+                .count()
+        };
+
+        reporter.report(&up("tun0", "10.8.0.2")).unwrap();
+        broker.wait(|record| record.pings >= 1);
+        broker.kick();
+
+        // The next ping fails and the tick after it reconnects without a report
+        let record = broker.wait(|record| record.connects.len() == 2 && online(record) == 2);
+
+        assert_eq!(record.connects[1].client_id, "vpn-ip-tracker-laptop.lan");
+        reporter.report(&up("tun0", "10.8.0.3")).unwrap();
+        assert_eq!(broker.record().connects.len(), 2);
+    }
+
+    #[test]
+    fn test_refused() {
+        let broker = MqttStandIn::start(&[3, 5]);
+        let mut reporter = MqttReporter::new(&config(&broker));
+
+        let unavailable = reporter.report(&up("tun0", "10.8.0.2")).unwrap_err();
+        let unauthorized = reporter.report(&up("tun0", "10.8.0.2")).unwrap_err();
+
+        assert!(unavailable.is_retryable());
+        assert!(unauthorized.is_unauthorized());
+        reporter.report(&up("tun0", "10.8.0.2")).unwrap();
+    }
+}
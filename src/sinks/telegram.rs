@@ -0,0 +1,411 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use vpn_ip_tracker::{
+    report::ReportPayload,
+    sink::{TelegramParseMode, TelegramSinkConfig},
+};
+
+use crate::sinks::template;
+use crate::tracker::{ReportError, Reporter};
+
+/// Bot API method response
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    #[serde(default)]
+    result: serde_json::Value,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    parameters: ResponseParameters,
+}
+
+#[derive(Default, Deserialize)]
+struct ResponseParameters {
+    /// Seconds to wait before the next request when rate limited
+    retry_after: Option<u64>,
+}
+
+/// Reporter that sends reports as messages of a Telegram bot
+pub(crate) struct TelegramReporter {
+    client: reqwest::blocking::Client,
+    config: TelegramSinkConfig,
+    /// File that keeps the edited messages across restarts
+    path: Option<PathBuf>,
+    /// Last sent message of every interface that is edited by its following reports
+    messages: BTreeMap<String, i64>,
+}
+
+impl TelegramReporter {
+    /// Create reporter that continues editing the messages left by the previous run
+    /// Arguments:
+    /// - `client` - HTTP client
+    /// - `config` - sink configuration
+    /// - `path` - file of the edited messages, they are kept in memory only if not set
+    pub(crate) fn new(
+        client: reqwest::blocking::Client,
+        config: &TelegramSinkConfig,
+        path: Option<PathBuf>,
+    ) -> Self {
+        let messages = match (&path, config.edit) {
+            (Some(path), true) => load(path),
+            _ => BTreeMap::new(),
+        };
+
+        Self {
+            client,
+            config: config.clone(),
+            path,
+            messages,
+        }
+    }
+
+    /// Location of the edited messages file of the `sink` in the state directory
+    pub(crate) fn messages_path(sink: &str) -> Option<PathBuf> {
+        vpn_ip_tracker::state_dir().map(|dir| dir.join(format!("telegram-{sink}.json")))
+    }
+
+    /// Remember `message_id` as the message of `iface` edited by its next reports
+    fn remember(&mut self, iface: &str, message_id: i64) {
+        self.messages.insert(iface.into(), message_id);
+
+        let Some(path) = &self.path else {
+            return;
+        };
+        let stored = serde_json::to_vec_pretty(&self.messages)
+            .map_err(Into::into)
+            .and_then(|data| vpn_ip_tracker::write_atomic(path, &data, None));
+
+        // The message is sent already, the next run just sends a new one
+        if let Err(e) = stored {
+            warn!(
+                "Failed to store Telegram messages {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Call the Bot API `method` with JSON `params`
+    /// Returns HTTP status and the method response
+    fn call(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<(u16, ApiResponse), ReportError> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config.api_url.trim_end_matches('/'),
+            self.config.bot_token,
+            method
+        );
+        // The bot token is part of the URL, keep it out of the errors
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(params.to_string())
+            .send()
+            .map_err(reqwest::Error::without_url)?;
+        let status = response.status().as_u16();
+        let body = response.text().map_err(reqwest::Error::without_url)?;
+
+        match serde_json::from_str(&body) {
+            Ok(response) => Ok((status, response)),
+            Err(_) if status >= 400 => Err(ReportError::Status {
+                status,
+                retry_after: None,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Reporter for TelegramReporter {
+    /// Send message about `report`, the last message is edited instead if editing is enabled
+    /// A new message is sent if the last one cannot be edited anymore, e.g. it was deleted
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        let escape = match self.config.parse_mode {
+            Some(TelegramParseMode::MarkdownV2) => escape_markdown_v2,
+            Some(TelegramParseMode::Html) => escape_html,
+            Some(TelegramParseMode::Markdown) => escape_markdown,
+            None => template::no_escape,
+        };
+        let text = template::render(&self.config.template, report, escape);
+        let mut params = json!({
+            "chat_id": self.config.chat_id,
+            "text": text.trim(),
+        });
+
+        if let Some(parse_mode) = self.config.parse_mode {
+            params["parse_mode"] = parse_mode.as_str().into();
+        }
+
+        let iface = &report.interface.name;
+
+        if let (true, Some(message_id)) = (self.config.edit, self.messages.get(iface)) {
+            let mut edit = params.clone();
+
+            edit["message_id"] = (*message_id).into();
+
+            let (status, response) = self.call("editMessageText", &edit)?;
+
+            if response.ok || response.description.contains("message is not modified") {
+                return Ok(());
+            }
+
+            if status != 400 {
+                return Err(api_error(status, &response));
+            }
+
+            debug!(
+                "Send new message, the last one cannot be edited: {}",
+                response.description
+            );
+        }
+
+        let (status, response) = self.call("sendMessage", &params)?;
+
+        if !response.ok {
+            return Err(api_error(status, &response));
+        }
+
+        if let (true, Some(message_id)) = (self.config.edit, response.result["message_id"].as_i64())
+        {
+            self.remember(iface, message_id);
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the edited messages from the file at `path`
+/// A missing or unreadable file gives no messages, the next reports send new ones
+fn load(path: &Path) -> BTreeMap<String, i64> {
+    let Ok(data) = fs::read(path) else {
+        return BTreeMap::new();
+    };
+
+    serde_json::from_slice(&data).unwrap_or_else(|_| {
+        warn!("Discard unreadable Telegram messages {}", path.display());
+        BTreeMap::new()
+    })
+}
+
+fn api_error(status: u16, response: &ApiResponse) -> ReportError {
+    debug!("Bot API error {}: {}", status, response.description);
+    ReportError::Status {
+        status,
+        retry_after: response.parameters.retry_after.map(Duration::from_secs),
+    }
+}
+
+fn escape_with(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn escape_markdown_v2(value: &str) -> String {
+    escape_with(value, "\\_*[]()~`>#+-=|{}.!")
+}
+
+fn escape_markdown(value: &str) -> String {
+    escape_with(value, "_*`[")
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod telegram_tests {
+    use std::time::{Duration, SystemTime};
+
+    use vpn_ip_tracker::{
+        report::ReportPayload,
+        sink::{TelegramParseMode, TelegramSinkConfig},
+    };
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::telegram::TelegramReporter;
+    use crate::testing::{HttpStandIn, Response};
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::Reporter;
+
+    fn config(server: &HttpStandIn) -> TelegramSinkConfig {
+        TelegramSinkConfig {
+            api_url: server.url(""),
+            bot_token: "123:abc".into(),
+            chat_id: "-10042".into(),
+            template: "*{iface}* {event} {ip}".into(),
+            parse_mode: Some(TelegramParseMode::MarkdownV2),
+            edit: false,
+        }
+    }
+
+    fn up(addr: &str) -> ReportPayload {
+        IfaceEvent::Up {
+            iface: iface("tun0", addr),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    fn sent(message_id: i64) -> Response {
+        Response::new(
+            200,
+            &format!("{{\"ok\": true, \"result\": {{\"message_id\": {message_id}}}}}"),
+        )
+    }
+
+    fn body(server: &HttpStandIn, index: usize) -> serde_json::Value {
+        serde_json::from_str(&server.requests()[index].body).unwrap()
+    }
+
+    #[test]
+    fn test_send_message() {
+        let server = HttpStandIn::start(vec![sent(1)]);
+        let mut reporter =
+            TelegramReporter::new(reqwest::blocking::Client::new(), &config(&server), None);
+
+        reporter.report(&up("10.8.0.2")).unwrap();
+
+        let body = body(&server, 0);
+
+        assert_eq!(server.requests()[0].path, "/bot123:abc/sendMessage");
+        assert_eq!(body["chat_id"], "-10042");
+        assert_eq!(body["text"], "*tun0* up 10\\.8\\.0\\.2");
+        assert_eq!(body["parse_mode"], "MarkdownV2");
+        assert!(reporter.messages.is_empty());
+    }
+
+    #[test]
+    fn test_edit_message() {
+        let server = HttpStandIn::start(vec![
+            sent(7),
+            Response::new(200, "{\"ok\": true, \"result\": true}"),
+            Response::new(
+                400,
+                "{\"ok\": false, \"error_code\": 400, \"description\": \"Bad Request: message to edit not found\"}",
+            ),
+            sent(8),
+        ]);
+        let mut reporter = TelegramReporter::new(
+            reqwest::blocking::Client::new(),
+            &TelegramSinkConfig {
+                edit: true,
+                ..config(&server)
+            },
+            None,
+        );
+
+        reporter.report(&up("10.8.0.2")).unwrap();
+        reporter.report(&up("10.8.0.3")).unwrap();
+        reporter.report(&up("10.8.0.4")).unwrap();
+
+        let paths: Vec<String> = server.requests().into_iter().map(|it| it.path).collect();
+
+        assert_eq!(
+            paths,
+            [
+                "/bot123:abc/sendMessage",
+                "/bot123:abc/editMessageText",
+                "/bot123:abc/editMessageText",
+                "/bot123:abc/sendMessage",
+            ]
+        );
+        assert_eq!(body(&server, 1)["message_id"], 7);
+        assert_eq!(body(&server, 1)["text"], "*tun0* up 10\\.8\\.0\\.3");
+        assert_eq!(reporter.messages.get("tun0"), Some(&8));
+    }
+
+    #[test]
+    fn test_edit_message_per_iface() {
+        let path = std::env::temp_dir()
+            .join(format!("vpn-ip-tracker-telegram-{}", std::process::id()))
+            .join("telegram-bot.json");
+        let server = HttpStandIn::start(vec![
+            sent(7),
+            sent(9),
+            Response::new(200, "{\"ok\": true, \"result\": true}"),
+            Response::new(200, "{\"ok\": true, \"result\": true}"),
+        ]);
+        let config = TelegramSinkConfig {
+            edit: true,
+            ..config(&server)
+        };
+        let wg = IfaceEvent::Up {
+            iface: iface("wg0", "10.9.0.2"),
+        }
+        .payload("laptop", None, SystemTime::now());
+        let mut reporter = TelegramReporter::new(
+            reqwest::blocking::Client::new(),
+            &config,
+            Some(path.clone()),
+        );
+
+        reporter.report(&up("10.8.0.2")).unwrap();
+        reporter.report(&wg).unwrap();
+        drop(reporter);
+
+        // The restarted tracker keeps editing the messages of the previous run
+        let mut reporter = TelegramReporter::new(
+            reqwest::blocking::Client::new(),
+            &config,
+            Some(path.clone()),
+        );
+
+        reporter.report(&wg).unwrap();
+        reporter.report(&up("10.8.0.3")).unwrap();
+
+        let paths: Vec<String> = server.requests().into_iter().map(|it| it.path).collect();
+
+        assert_eq!(paths[2..], ["/bot123:abc/editMessageText"; 2]);
+        assert_eq!(body(&server, 2)["message_id"], 9);
+        assert_eq!(body(&server, 3)["message_id"], 7);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_api_errors() {
+        let server = HttpStandIn::start(vec![
+            Response::new(
+                429,
+                "{\"ok\": false, \"error_code\": 429, \"parameters\": {\"retry_after\": 3}}",
+            ),
+            Response::new(401, "{\"ok\": false, \"error_code\": 401}"),
+            Response::new(502, "<html>Bad Gateway</html>"),
+        ]);
+        let mut reporter =
+            TelegramReporter::new(reqwest::blocking::Client::new(), &config(&server), None);
+
+        let rate_limited = reporter.report(&up("10.8.0.2")).unwrap_err();
+        let unauthorized = reporter.report(&up("10.8.0.2")).unwrap_err();
+        let bad_gateway = reporter.report(&up("10.8.0.2")).unwrap_err();
+
+        assert_eq!(rate_limited.retry_after(), Some(Duration::from_secs(3)));
+        assert!(unauthorized.is_unauthorized());
+        assert!(bad_gateway.is_retryable());
+    }
+}
@@ -0,0 +1,118 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use vpn_ip_tracker::report::{ReportAddress, ReportPayload};
+
+/// Render `template` with the variables of `report`
+/// Every variable value is passed through `escape` so that it cannot break the template markup;
+/// unknown variables are left as they are
+/// Variables:
+/// - `{host}` - host name
+/// - `{device}` - configured device label, empty if not set
+/// - `{iface}` - interface name
+/// - `{event}` - event kind
+/// - `{ip}` - current addresses
+/// - `{previous_ip}` - previously reported addresses
+/// - `{egress}` - public egress address, empty if unknown
+pub(crate) fn render(template: &str, report: &ReportPayload, escape: fn(&str) -> String) -> String {
+    render_with(template, report, &[], escape)
+}
+
+/// Render `template` with the variables of `report` and the `extra` variables of the sink
+pub(crate) fn render_with(
+    template: &str,
+    report: &ReportPayload,
+    extra: &[(&str, &str)],
+    escape: fn(&str) -> String,
+) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest
+            .find('}')
+            .and_then(|end| Some((end, variable(&rest[1..end], report, extra)?)));
+
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(&escape(&value));
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Keep variable values as they are
+pub(crate) fn no_escape(value: &str) -> String {
+    value.to_string()
+}
+
+fn variable(name: &str, report: &ReportPayload, extra: &[(&str, &str)]) -> Option<String> {
+    if let Some((_, value)) = extra.iter().find(|(it, _)| *it == name) {
+        return Some(value.to_string());
+    }
+
+    let value = match name {
+        "host" => report.hostname.clone(),
+        "device" => report.device.clone().unwrap_or_default(),
+        "iface" => report.interface.name.clone(),
+        "event" => report.event.as_str().to_string(),
+        "ip" => join_addresses(&report.addresses),
+        "previous_ip" => join_addresses(&report.previous_addresses),
+        "egress" => report
+            .egress
+            .as_ref()
+            .map(|egress| egress.addr.to_string())
+            .unwrap_or_default(),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+fn join_addresses(addresses: &[ReportAddress]) -> String {
+    addresses
+        .iter()
+        .map(|addr| addr.addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod template_tests {
+    use std::time::SystemTime;
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::template::{no_escape, render};
+    use crate::tracker::tracker_tests::iface;
+
+    #[test]
+    fn test_render() {
+        let report = IfaceEvent::Changed {
+            previous: iface("tun0", "10.8.0.2"),
+            iface: iface("tun0", "10.8.0.3"),
+        }
+        .payload("laptop", None, SystemTime::now());
+
+        assert_eq!(
+            render(
+                "{host}/{device} {iface} {event}: {previous_ip} -> {ip} {egress}",
+                &report,
+                no_escape
+            ),
+            "laptop/ tun0 changed: 10.8.0.2 -> 10.8.0.3 "
+        );
+        assert_eq!(
+            render("{ {unknown} {ip}}", &report, |value| format!("<{value}>")),
+            "{ {unknown} <10.8.0.3>}"
+        );
+    }
+}
@@ -0,0 +1,281 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use sha2::{Digest, Sha256};
+
+use vpn_ip_tracker::{
+    report::ReportPayload,
+    sink::{WebhookPreset, WebhookSinkConfig},
+};
+
+use crate::sinks::{http, template};
+use crate::tracker::{ReportError, Reporter};
+
+/// Request template of a webhook preset, all presets send JSON
+struct Preset {
+    method: &'static str,
+    body: &'static str,
+}
+
+impl From<WebhookPreset> for Preset {
+    fn from(preset: WebhookPreset) -> Self {
+        match preset {
+            WebhookPreset::Slack | WebhookPreset::Mattermost => Preset {
+                method: "POST",
+                body: r#"{"text": "{text}"}"#,
+            },
+            WebhookPreset::Discord => Preset {
+                method: "POST",
+                body: r#"{"content": "{text}"}"#,
+            },
+            WebhookPreset::Matrix => Preset {
+                method: "PUT",
+                body: r#"{"msgtype": "m.text", "body": "{text}"}"#,
+            },
+        }
+    }
+}
+
+/// Reporter that sends requests rendered from the configured templates
+pub(crate) struct WebhookReporter {
+    client: reqwest::blocking::Client,
+    config: WebhookSinkConfig,
+}
+
+impl WebhookReporter {
+    pub(crate) fn new(client: reqwest::blocking::Client, config: &WebhookSinkConfig) -> Self {
+        Self {
+            client,
+            config: config.clone(),
+        }
+    }
+
+    /// Render configured headers, the preset ones go first
+    fn headers(
+        &self,
+        report: &ReportPayload,
+        variables: &[(&str, &str)],
+    ) -> Result<HeaderMap, ReportError> {
+        let mut headers = HeaderMap::new();
+
+        if self.config.preset.is_some() {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+
+        for (name, value) in &self.config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| ReportError::Invalid(format!("header name '{name}'")))?;
+            let value = template::render_with(value, report, variables, template::no_escape);
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| ReportError::Invalid(format!("value of header '{name}'")))?;
+
+            headers.insert(name, value);
+        }
+
+        Ok(headers)
+    }
+}
+
+impl Reporter for WebhookReporter {
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+        let preset = self.config.preset.map(Preset::from);
+        let method = self
+            .config
+            .method
+            .as_deref()
+            .or(preset.as_ref().map(|preset| preset.method))
+            .unwrap_or("POST");
+        let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| ReportError::Invalid(format!("method '{method}'")))?;
+        let text = template::render(&self.config.text, report, template::no_escape);
+        let txn = transaction_id(report);
+        let variables = [("text", text.trim()), ("txn", txn.as_str())];
+        let headers = self.headers(report, &variables)?;
+        let escape = match headers.get(CONTENT_TYPE).and_then(|it| it.to_str().ok()) {
+            Some(content_type) if content_type.contains("json") => escape_json,
+            _ => template::no_escape,
+        };
+        let body = self
+            .config
+            .body
+            .as_deref()
+            .or(preset.as_ref().map(|preset| preset.body))
+            .unwrap_or("{text}");
+        let body = template::render_with(body, report, &variables, escape);
+        let url = template::render_with(&self.config.url, report, &variables, escape_url);
+
+        http::send(self.client.request(method, url).headers(headers).body(body))
+    }
+}
+
+/// Id of the request so that the receiver can detect repeated ones
+/// The id is derived from the report, so every retry of a report sends the same one
+fn transaction_id(report: &ReportPayload) -> String {
+    let mut hasher = Sha256::new();
+
+    hasher.update(format!(
+        "{}\n{}\n{}\n{}\n",
+        report.hostname,
+        report.timestamp,
+        report.interface.name,
+        report.event.as_str()
+    ));
+
+    for addr in &report.addresses {
+        hasher.update(format!("{}/{}\n", addr.addr, addr.prefix_len));
+    }
+
+    let digest = hasher.finalize();
+
+    format!(
+        "{}-{:016x}",
+        report.timestamp,
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    )
+}
+
+/// Escape `value` to be put inside a JSON string
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Percent-encode `value` to be put inside a URL path segment or query value
+/// Only the unreserved characters of RFC 3986 are kept
+fn escape_url(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod webhook_tests {
+    use std::{collections::BTreeMap, time::SystemTime};
+
+    use vpn_ip_tracker::{
+        report::ReportPayload,
+        sink::{WebhookPreset, WebhookSinkConfig},
+    };
+
+    use crate::events::IfaceEvent;
+    use crate::sinks::webhook::WebhookReporter;
+    use crate::testing::{HttpStandIn, Response};
+    use crate::tracker::tracker_tests::iface;
+    use crate::tracker::{ReportError, Reporter};
+
+    fn config(url: String) -> WebhookSinkConfig {
+        WebhookSinkConfig {
+            url,
+            preset: None,
+            method: None,
+            text: "{host} {iface} {event} {ip}".into(),
+            body: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    fn changed() -> ReportPayload {
+        IfaceEvent::Changed {
+            previous: iface("tun0", "10.8.0.2"),
+            iface: iface("tun0", "10.8.0.3"),
+        }
+        .payload("laptop", None, SystemTime::now())
+    }
+
+    fn send(config: WebhookSinkConfig) -> Result<(), ReportError> {
+        WebhookReporter::new(reqwest::blocking::Client::new(), &config).report(&changed())
+    }
+
+    #[test]
+    fn test_presets() {
+        let server = HttpStandIn::start(vec![
+            Response::new(200, "ok"),
+            Response::new(200, "{}"),
+            Response::new(200, "{}"),
+        ]);
+        let matrix_config = WebhookSinkConfig {
+            preset: Some(WebhookPreset::Matrix),
+            headers: BTreeMap::from([("Authorization".into(), "Bearer secret".into())]),
+            ..config(
+                server.url("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/{txn}"),
+            )
+        };
+        let mut matrix_reporter =
+            WebhookReporter::new(reqwest::blocking::Client::new(), &matrix_config);
+        let report = changed();
+
+        send(WebhookSinkConfig {
+            preset: Some(WebhookPreset::Slack),
+            ..config(server.url("/services/T0/B0/x"))
+        })
+        .unwrap();
+        matrix_reporter.report(&report).unwrap();
+        // Repeated delivery of the same report
+        matrix_reporter.report(&report).unwrap();
+
+        let requests = server.requests();
+        let slack: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        let matrix: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].header("Content-Type"), Some("application/json"));
+        assert_eq!(slack["text"], "laptop tun0 changed 10.8.0.3");
+        assert_eq!(requests[1].method, "PUT");
+        let (room, txn) = requests[1].path.rsplit_once('/').unwrap();
+
+        assert!(room.ends_with("/send/m.room.message"));
+        assert!(!txn.is_empty() && !txn.contains("txn"));
+        assert_eq!(requests[2].path, requests[1].path);
+        assert_eq!(requests[1].header("Authorization"), Some("Bearer secret"));
+        assert_eq!(matrix["msgtype"], "m.text");
+        assert_eq!(matrix["body"], "laptop tun0 changed 10.8.0.3");
+    }
+
+    #[test]
+    fn test_custom_request() {
+        let server = HttpStandIn::start(vec![Response::new(204, "")]);
+
+        send(WebhookSinkConfig {
+            method: Some("patch".into()),
+            text: "\"{iface}\" is {event}".into(),
+            body: Some(r#"{"message": "{text}", "ip": "{ip}", "was": "{previous_ip}"}"#.into()),
+            headers: BTreeMap::from([
+                ("Content-Type".into(), "application/json".into()),
+                ("X-Event".into(), "{event}".into()),
+            ]),
+            ..config(server.url("/hooks/{host}?text={text}&ip={ip}"))
+        })
+        .unwrap();
+
+        let request = &server.requests()[0];
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+
+        assert_eq!(request.method, "PATCH");
+        assert_eq!(
+            request.path,
+            "/hooks/laptop?text=%22tun0%22%20is%20changed&ip=10.8.0.3"
+        );
+        assert_eq!(request.header("X-Event"), Some("changed"));
+        assert_eq!(body["message"], "\"tun0\" is changed");
+        assert_eq!(body["ip"], "10.8.0.3");
+        assert_eq!(body["was"], "10.8.0.2");
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let error = send(WebhookSinkConfig {
+            method: Some("NOT A METHOD".into()),
+            ..config("http://127.0.0.1:9/".into())
+        })
+        .unwrap_err();
+
+        assert!(matches!(error, ReportError::Invalid(_)));
+        assert!(!error.is_retryable());
+    }
+}
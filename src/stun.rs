@@ -0,0 +1,328 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Minimal STUN client (RFC 5389) that discovers the public mapped address
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::utils;
+
+pub(crate) const MAGIC_COOKIE: u32 = 0x2112_a442;
+pub(crate) const BINDING_REQUEST: u16 = 0x0001;
+pub(crate) const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+pub(crate) const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+/// Number of Binding Requests sent before giving up on a server
+const ATTEMPTS: u32 = 3;
+
+/// Errors that can occur during a STUN transaction
+#[derive(Debug, Error)]
+pub(crate) enum StunError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("unable to resolve STUN server {0}")]
+    Resolve(String),
+    #[error("no response from STUN server")]
+    Timeout,
+    #[error("malformed STUN message")]
+    Malformed,
+    #[error("STUN error response {0}")]
+    ErrorResponse(u16),
+    #[error("no mapped address in the STUN response")]
+    NoAddress,
+}
+
+/// STUN transaction identifier
+pub(crate) type TransactionId = [u8; 12];
+
+/// Discover address mapped to `local` by the STUN `server`
+/// The request is retransmitted with doubled timeout if no response arrives
+/// Arguments:
+/// - `server` - STUN server as `host:port`
+/// - `local` - address the request is sent from
+/// - `device` - interface the request is sent through
+/// - `timeout` - time to wait for the first response
+pub(crate) fn mapped_address(
+    server: &str,
+    local: IpAddr,
+    device: Option<&str>,
+    timeout: Duration,
+) -> Result<SocketAddr, StunError> {
+    let server_addr = server
+        .to_socket_addrs()
+        .map_err(|_| StunError::Resolve(server.into()))?
+        .find(|addr| addr.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| StunError::Resolve(server.into()))?;
+    let socket = utils::udp_socket(local, device)?;
+    let transaction = transaction_id();
+    let request = binding_request(&transaction);
+    let mut buf = [0u8; 1024];
+    let mut wait = timeout;
+
+    socket.connect(server_addr)?;
+
+    for _ in 0..ATTEMPTS {
+        socket.send(&request)?;
+        let deadline = Instant::now() + wait;
+
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            // Stray datagrams of other transactions are ignored
+            if let Some(result) = parse_response(&buf[..len], &transaction) {
+                return result;
+            }
+        }
+
+        wait *= 2;
+    }
+
+    Err(StunError::Timeout)
+}
+
+/// Encode Binding Request without attributes
+pub(crate) fn binding_request(transaction: &TransactionId) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HEADER_LEN);
+
+    msg.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    msg.extend_from_slice(transaction);
+    msg
+}
+
+/// Parse Binding response to the `transaction`
+/// Returns `None` if the message is not a response to the transaction
+pub(crate) fn parse_response(
+    msg: &[u8],
+    transaction: &TransactionId,
+) -> Option<Result<SocketAddr, StunError>> {
+    if msg.len() < HEADER_LEN
+        || u32::from_be_bytes(msg[4..8].try_into().unwrap()) != MAGIC_COOKIE
+        || msg[8..HEADER_LEN] != transaction[..]
+    {
+        return None;
+    }
+
+    let kind = u16::from_be_bytes([msg[0], msg[1]]);
+    let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+    let Some(attrs) = msg.get(HEADER_LEN..HEADER_LEN + len) else {
+        return Some(Err(StunError::Malformed));
+    };
+
+    match kind {
+        BINDING_SUCCESS => Some(parse_mapped_address(attrs, transaction)),
+        BINDING_ERROR => Some(Err(parse_error_code(attrs))),
+        _ => None,
+    }
+}
+
+/// Apply the XOR-MAPPED-ADDRESS obfuscation, the operation is its own inverse
+pub(crate) fn xor_address(addr: SocketAddr, transaction: &TransactionId) -> SocketAddr {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) ^ MAGIC_COOKIE)),
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            let key = cookie.iter().chain(transaction.iter());
+
+            octets
+                .iter_mut()
+                .zip(key)
+                .for_each(|(octet, key)| *octet ^= key);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+    };
+
+    SocketAddr::new(ip, port)
+}
+
+fn parse_mapped_address(
+    attrs: &[u8],
+    transaction: &TransactionId,
+) -> Result<SocketAddr, StunError> {
+    let mut mapped = None;
+
+    for (kind, value) in attributes(attrs)? {
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => {
+                return Ok(xor_address(decode_address(value)?, transaction));
+            }
+            ATTR_MAPPED_ADDRESS => mapped = Some(decode_address(value)?),
+            _ => (),
+        }
+    }
+
+    mapped.ok_or(StunError::NoAddress)
+}
+
+fn parse_error_code(attrs: &[u8]) -> StunError {
+    attributes(attrs)
+        .ok()
+        .and_then(|attrs| {
+            attrs
+                .into_iter()
+                .find(|(kind, value)| *kind == ATTR_ERROR_CODE && value.len() >= 4)
+        })
+        .map(|(_, value)| StunError::ErrorResponse(value[2] as u16 * 100 + value[3] as u16))
+        .unwrap_or(StunError::Malformed)
+}
+
+/// Split the message attributes into type and value pairs
+fn attributes(mut attrs: &[u8]) -> Result<Vec<(u16, &[u8])>, StunError> {
+    let mut parsed = Vec::new();
+
+    while !attrs.is_empty() {
+        if attrs.len() < 4 {
+            return Err(StunError::Malformed);
+        }
+
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len).ok_or(StunError::Malformed)?;
+
+        parsed.push((kind, value));
+        // Attribute values are padded to a multiple of 4 bytes
+        attrs = attrs.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+    }
+
+    Ok(parsed)
+}
+
+fn decode_address(value: &[u8]) -> Result<SocketAddr, StunError> {
+    let port = u16::from_be_bytes(
+        value
+            .get(2..4)
+            .ok_or(StunError::Malformed)?
+            .try_into()
+            .unwrap(),
+    );
+    let ip = match (value[1], value.len()) {
+        (0x01, 8) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&value[4..8]).unwrap())),
+        (0x02, 20) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&value[4..20]).unwrap())),
+        _ => return Err(StunError::Malformed),
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn transaction_id() -> TransactionId {
+    let mut id = [0u8; 12];
+
+    utils::fill_random(&mut id);
+    id
+}
+
+#[cfg(test)]
+mod stun_tests {
+    use std::time::Duration;
+
+    use crate::stun::{binding_request, mapped_address, parse_response, StunError};
+    use crate::testing::StunStandIn;
+
+    /// Sample IPv4 response from RFC 5769
+    const RFC5769_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+    const RFC5769_TRANSACTION: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    #[test]
+    fn test_parse_response() {
+        let mapped = parse_response(&RFC5769_RESPONSE, &RFC5769_TRANSACTION);
+
+        assert_eq!(mapped.unwrap().unwrap(), "192.0.2.1:32853".parse().unwrap());
+        assert!(parse_response(&RFC5769_RESPONSE, &[0; 12]).is_none());
+        assert!(matches!(
+            parse_response(&RFC5769_RESPONSE[..40], &RFC5769_TRANSACTION),
+            Some(Err(StunError::Malformed))
+        ));
+    }
+
+    #[test]
+    fn test_binding_request() {
+        let request = binding_request(&RFC5769_TRANSACTION);
+
+        assert_eq!(request.len(), 20);
+        assert_eq!(
+            request[..8],
+            [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]
+        );
+        assert_eq!(request[8..], RFC5769_TRANSACTION);
+    }
+
+    #[test]
+    fn test_mapped_address() {
+        let v4 = StunStandIn::start("198.51.100.7:4500".parse().unwrap());
+        let v6 = StunStandIn::start("[2001:db8::7]:4500".parse().unwrap());
+        let timeout = Duration::from_millis(500);
+
+        assert_eq!(
+            mapped_address(
+                &v4.addr().to_string(),
+                "127.0.0.1".parse().unwrap(),
+                Some("lo"),
+                timeout
+            )
+            .unwrap(),
+            "198.51.100.7:4500".parse().unwrap()
+        );
+        assert_eq!(
+            mapped_address(
+                &v6.addr().to_string(),
+                "127.0.0.1".parse().unwrap(),
+                None,
+                timeout
+            )
+            .unwrap(),
+            "[2001:db8::7]:4500".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_no_response() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap().to_string();
+        let result = mapped_address(
+            &server,
+            "127.0.0.1".parse().unwrap(),
+            None,
+            Duration::from_millis(20),
+        );
+
+        assert!(matches!(result, Err(StunError::Timeout)));
+        assert!(matches!(
+            mapped_address(
+                &server,
+                "::1".parse().unwrap(),
+                None,
+                Duration::from_millis(20)
+            ),
+            Err(StunError::Resolve(_))
+        ));
+    }
+}
@@ -0,0 +1,240 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::path::{Path, PathBuf};
+
+use vpn_ip_tracker::{matching::LinkKind, report::OperState};
+
+/// Default location of the network devices in sysfs
+const SYSFS_NET_ROOT: &str = "/sys/class/net";
+
+const ARPHRD_ETHER: u32 = 1;
+const ARPHRD_PPP: u32 = 512;
+const ARPHRD_TUNNEL: u32 = 768;
+const ARPHRD_TUNNEL6: u32 = 769;
+const ARPHRD_SIT: u32 = 776;
+const ARPHRD_LOOPBACK: u32 = 772;
+const ARPHRD_IPGRE: u32 = 778;
+const ARPHRD_IP6GRE: u32 = 823;
+const ARPHRD_NONE: u32 = 65534;
+
+const IFF_UP: u32 = 0x0001;
+
+const IFF_TUN: u32 = 0x0001;
+const IFF_TAP: u32 = 0x0002;
+const IFF_POINTOPOINT: u32 = 0x0010;
+
+/// Network device information reader backed by `/sys/class/net`
+pub(crate) struct SysfsNet {
+    root: PathBuf,
+}
+
+impl Default for SysfsNet {
+    fn default() -> Self {
+        Self::new(SYSFS_NET_ROOT)
+    }
+}
+
+impl SysfsNet {
+    /// Create new reader with the `root` directory that holds the network devices
+    pub(crate) fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Detect link kind of the device `name`
+    /// Returns `None` if the device is unknown to sysfs
+    pub(crate) fn link_kind(&self, name: &str) -> Option<LinkKind> {
+        let link_type = self.read_number(name, "type", 10)?;
+        let tun_flags = self.read_number(name, "tun_flags", 16);
+        let flags = self.read_number(name, "flags", 16).unwrap_or(0);
+
+        if self.dev_type(name).as_deref() == Some("wireguard") {
+            return Some(LinkKind::Wireguard);
+        }
+
+        let kind = match (link_type, tun_flags) {
+            (_, Some(tun_flags)) if tun_flags & IFF_TAP != 0 => LinkKind::Tap,
+            (_, Some(tun_flags)) if tun_flags & IFF_TUN != 0 => LinkKind::Tun,
+            (ARPHRD_ETHER, _) => LinkKind::Ethernet,
+            (ARPHRD_PPP, _) => LinkKind::Ppp,
+            (ARPHRD_TUNNEL | ARPHRD_TUNNEL6 | ARPHRD_SIT | ARPHRD_IPGRE | ARPHRD_IP6GRE, _) => {
+                LinkKind::IpTunnel
+            }
+            (ARPHRD_LOOPBACK, _) => LinkKind::Loopback,
+            (ARPHRD_NONE, _) if flags & IFF_POINTOPOINT != 0 => LinkKind::Tun,
+            _ => LinkKind::Other,
+        };
+
+        Some(kind)
+    }
+
+    /// Check whether the device `name` is administratively up and running
+    /// `flags` holds only the administrative flags, the device is running like with
+    /// `IFF_RUNNING` if its operational state is up or unknown, or it has carrier if the
+    /// state is not available
+    pub(crate) fn is_up(&self, name: &str) -> Option<bool> {
+        let flags = self.read_number(name, "flags", 16)?;
+
+        if flags & IFF_UP == 0 {
+            return Some(false);
+        }
+
+        let running = match self.oper_state(name) {
+            Some(state) => matches!(state, OperState::Up | OperState::Unknown),
+            None => self.read_number(name, "carrier", 10) == Some(1),
+        };
+
+        Some(running)
+    }
+
+    /// Read MTU of the device `name`
+    pub(crate) fn mtu(&self, name: &str) -> Option<u32> {
+        self.read_number(name, "mtu", 10)
+    }
+
+    /// Read operational state of the device `name`
+    pub(crate) fn oper_state(&self, name: &str) -> Option<OperState> {
+        let state = match self.read(name, "operstate")?.trim() {
+            "notpresent" => OperState::NotPresent,
+            "down" => OperState::Down,
+            "lowerlayerdown" => OperState::LowerLayerDown,
+            "testing" => OperState::Testing,
+            "dormant" => OperState::Dormant,
+            "up" => OperState::Up,
+            _ => OperState::Unknown,
+        };
+
+        Some(state)
+    }
+
+    fn dev_type(&self, name: &str) -> Option<String> {
+        self.read(name, "uevent")?
+            .lines()
+            .find_map(|line| line.strip_prefix("DEVTYPE="))
+            .map(str::to_string)
+    }
+
+    fn read_number(&self, name: &str, attr: &str, radix: u32) -> Option<u32> {
+        let value = self.read(name, attr)?;
+        let value = value.trim();
+        let value = value.strip_prefix("0x").unwrap_or(value);
+
+        u32::from_str_radix(value, radix).ok()
+    }
+
+    fn read(&self, name: &str, attr: &str) -> Option<String> {
+        std::fs::read_to_string(self.root.join(name).join(attr)).ok()
+    }
+}
+
+#[cfg(test)]
+mod sysfs_tests {
+    use std::path::PathBuf;
+
+    use vpn_ip_tracker::{matching::LinkKind, report::OperState};
+
+    use crate::sysfs::SysfsNet;
+
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(test_name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "vpn-ip-tracker-sysfs-{}-{test_name}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&root);
+
+            Self { root }
+        }
+
+        fn device(self, name: &str, attrs: &[(&str, &str)]) -> Self {
+            let dev_dir = self.root.join(name);
+
+            std::fs::create_dir_all(&dev_dir).unwrap();
+            for (attr, value) in attrs {
+                std::fs::write(dev_dir.join(attr), format!("{value}\n")).unwrap();
+            }
+
+            self
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn test_link_kind() {
+        let fake = FakeSysfs::new("link_kind")
+            .device("eth0", &[("type", "1"), ("flags", "0x1003")])
+            .device("lo", &[("type", "772"), ("flags", "0x9")])
+            .device("tun0", &[("type", "65534"), ("tun_flags", "0x1001")])
+            .device("tap1", &[("type", "1"), ("tun_flags", "0x1002")])
+            .device(
+                "wg0",
+                &[
+                    ("type", "65534"),
+                    ("uevent", "DEVTYPE=wireguard\nINTERFACE=wg0"),
+                ],
+            )
+            .device("ppp0", &[("type", "512"), ("flags", "0x1091")])
+            .device("gre1", &[("type", "778")])
+            .device("ipip0", &[("type", "768")])
+            .device("corp-vpn", &[("type", "65534"), ("flags", "0x1091")])
+            .device("can0", &[("type", "280")]);
+        let sysfs = SysfsNet::new(&fake.root);
+
+        assert_eq!(sysfs.link_kind("eth0"), Some(LinkKind::Ethernet));
+        assert_eq!(sysfs.link_kind("lo"), Some(LinkKind::Loopback));
+        assert_eq!(sysfs.link_kind("tun0"), Some(LinkKind::Tun));
+        assert_eq!(sysfs.link_kind("tap1"), Some(LinkKind::Tap));
+        assert_eq!(sysfs.link_kind("wg0"), Some(LinkKind::Wireguard));
+        assert_eq!(sysfs.link_kind("ppp0"), Some(LinkKind::Ppp));
+        assert_eq!(sysfs.link_kind("gre1"), Some(LinkKind::IpTunnel));
+        assert_eq!(sysfs.link_kind("ipip0"), Some(LinkKind::IpTunnel));
+        assert_eq!(sysfs.link_kind("corp-vpn"), Some(LinkKind::Tun));
+        assert_eq!(sysfs.link_kind("can0"), Some(LinkKind::Other));
+        assert_eq!(sysfs.link_kind("missing0"), None);
+    }
+
+    #[test]
+    fn test_link_attributes() {
+        let fake = FakeSysfs::new("link_attributes")
+            .device("tun0", &[("mtu", "1420"), ("operstate", "unknown")])
+            .device("tun1", &[("mtu", "1500"), ("operstate", "lowerlayerdown")])
+            .device("wg0", &[("operstate", "up")]);
+        let sysfs = SysfsNet::new(&fake.root);
+
+        assert_eq!(sysfs.mtu("tun0"), Some(1420));
+        assert_eq!(sysfs.oper_state("tun0"), Some(OperState::Unknown));
+        assert_eq!(sysfs.oper_state("tun1"), Some(OperState::LowerLayerDown));
+        assert_eq!(sysfs.mtu("wg0"), None);
+        assert_eq!(sysfs.oper_state("wg0"), Some(OperState::Up));
+        assert_eq!(sysfs.oper_state("missing0"), None);
+    }
+
+    #[test]
+    fn test_is_up() {
+        let fake = FakeSysfs::new("is_up")
+            .device("eth0", &[("flags", "0x1003"), ("operstate", "up")])
+            .device("eth1", &[("flags", "0x1003"), ("operstate", "down")])
+            .device("tun0", &[("flags", "0x1091"), ("operstate", "unknown")])
+            .device("tun1", &[("flags", "0x1090"), ("operstate", "down")])
+            .device("tun2", &[("flags", "0x1091"), ("carrier", "1")])
+            .device("tun3", &[("flags", "0x1091"), ("carrier", "0")]);
+        let sysfs = SysfsNet::new(&fake.root);
+
+        assert_eq!(sysfs.is_up("eth0"), Some(true));
+        assert_eq!(sysfs.is_up("eth1"), Some(false));
+        assert_eq!(sysfs.is_up("tun0"), Some(true));
+        assert_eq!(sysfs.is_up("tun1"), Some(false));
+        assert_eq!(sysfs.is_up("tun2"), Some(true));
+        assert_eq!(sysfs.is_up("tun3"), Some(false));
+        assert_eq!(sysfs.is_up("missing0"), None);
+    }
+}
@@ -0,0 +1,564 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+//! Local stand-ins for the remote services used in tests
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::mqtt::{
+    read_packet, write_packet, CONNACK, DISCONNECT, PINGREQ, PINGRESP, PUBACK, PUBCOMP, PUBLISH,
+    PUBREC, PUBREL,
+};
+use crate::stun::{
+    xor_address, ATTR_XOR_MAPPED_ADDRESS, BINDING_REQUEST, BINDING_SUCCESS, MAGIC_COOKIE,
+};
+
+/// HTTP request received by the stand-in server
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+}
+
+impl Request {
+    /// Get value of the header `name`
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Response the stand-in server replies with
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+}
+
+/// HTTP server that serves the scripted responses one connection each and records requests
+pub(crate) struct HttpStandIn {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl HttpStandIn {
+    pub(crate) fn start(responses: Vec<Response>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        std::thread::spawn(move || {
+            for response in responses {
+                let Ok((stream, _)) = listener.accept() else {
+                    return;
+                };
+                let mut reader = BufReader::new(stream);
+
+                if let Some(request) = read_request(&mut reader) {
+                    recorded.lock().unwrap().push(request);
+                }
+
+                let mut stream = reader.into_inner();
+                let mut head = format!(
+                    "HTTP/1.1 {} Stand-In\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+
+                for (name, value) in response.headers {
+                    head += &format!("{name}: {value}\r\n");
+                }
+
+                let _ = stream.write_all(format!("{head}\r\n{}", response.body).as_bytes());
+            }
+        });
+
+        Self { addr, requests }
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    /// Requests received so far
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+
+    reader.read_line(&mut line).ok()?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let mut headers = Vec::new();
+
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let header = line.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        let (name, value) = header.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0u8; content_length];
+
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+/// STUN server that answers every Binding Request with the same mapped address
+pub(crate) struct StunStandIn {
+    addr: SocketAddr,
+}
+
+impl StunStandIn {
+    pub(crate) fn start(mapped: SocketAddr) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let request = &buf[..len];
+
+                if len < 20
+                    || request[..2] != BINDING_REQUEST.to_be_bytes()
+                    || request[4..8] != MAGIC_COOKIE.to_be_bytes()
+                {
+                    continue;
+                }
+
+                let transaction: [u8; 12] = request[8..20].try_into().unwrap();
+                let xored = xor_address(mapped, &transaction);
+                let (family, ip) = match xored.ip() {
+                    IpAddr::V4(ip) => (1u8, ip.octets().to_vec()),
+                    IpAddr::V6(ip) => (2u8, ip.octets().to_vec()),
+                };
+                let mut attr = vec![0, family];
+
+                attr.extend_from_slice(&xored.port().to_be_bytes());
+                attr.extend_from_slice(&ip);
+
+                let mut response = Vec::new();
+
+                response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+                response.extend_from_slice(&(attr.len() as u16 + 4).to_be_bytes());
+                response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+                response.extend_from_slice(&transaction);
+                response.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+                response.extend_from_slice(&(attr.len() as u16).to_be_bytes());
+                response.extend_from_slice(&attr);
+
+                let _ = socket.send_to(&response, peer);
+            }
+        });
+
+        Self { addr }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// DNS server that answers every A, AAAA or TXT query with the same address
+pub(crate) struct DnsStandIn {
+    addr: SocketAddr,
+}
+
+impl DnsStandIn {
+    pub(crate) fn start(answer: IpAddr) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let Some(question_end) = question_end(&buf[..len]) else {
+                    continue;
+                };
+                let kind = u16::from_be_bytes([buf[question_end - 4], buf[question_end - 3]]);
+                let data = match (kind, answer) {
+                    (1, IpAddr::V4(ip)) => ip.octets().to_vec(),
+                    (28, IpAddr::V6(ip)) => ip.octets().to_vec(),
+                    (16, ip) => {
+                        let text = ip.to_string();
+                        let mut data = vec![text.len() as u8];
+
+                        data.extend_from_slice(text.as_bytes());
+                        data
+                    }
+                    _ => Vec::new(),
+                };
+                let mut response = buf[..question_end].to_vec();
+
+                response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+
+                if !data.is_empty() {
+                    response[7] = 1;
+                    response.extend_from_slice(&[0xc0, 0x0c]);
+                    response.extend_from_slice(&buf[question_end - 4..question_end]);
+                    response.extend_from_slice(&60u32.to_be_bytes());
+                    response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                    response.extend_from_slice(&data);
+                }
+
+                let _ = socket.send_to(&response, peer);
+            }
+        });
+
+        Self { addr }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// DNS server that records UPDATE messages and answers them with the scripted response codes
+pub(crate) struct DnsUpdateStandIn {
+    addr: SocketAddr,
+    messages: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl DnsUpdateStandIn {
+    pub(crate) fn start(rcodes: &[u16]) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let recorded = messages.clone();
+        let rcodes = rcodes.to_vec();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+
+            for rcode in rcodes {
+                let Ok((len, peer)) = socket.recv_from(&mut buf) else {
+                    return;
+                };
+                let mut response = buf[..12].to_vec();
+
+                response[2..4].copy_from_slice(&(0xa800 | rcode).to_be_bytes());
+                response[4..].fill(0);
+                recorded.lock().unwrap().push(buf[..len].to_vec());
+
+                let _ = socket.send_to(&response, peer);
+            }
+        });
+
+        Self { addr, messages }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Messages received so far
+    pub(crate) fn messages(&self) -> Vec<Vec<u8>> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+/// MQTT message received by the stand-in broker
+#[derive(Debug, Clone)]
+pub(crate) struct MqttMessage {
+    pub(crate) topic: String,
+    pub(crate) payload: String,
+    pub(crate) qos: u8,
+    pub(crate) retain: bool,
+}
+
+/// MQTT connect packet received by the stand-in broker
+#[derive(Debug, Clone)]
+pub(crate) struct MqttConnect {
+    /// Protocol level, 4 for MQTT 3.1.1 and 5 for MQTT 5
+    pub(crate) level: u8,
+    pub(crate) client_id: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) keep_alive: u16,
+    pub(crate) will: Option<MqttMessage>,
+}
+
+/// Packets received by the stand-in broker
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MqttRecord {
+    pub(crate) connects: Vec<MqttConnect>,
+    pub(crate) messages: Vec<MqttMessage>,
+    pub(crate) pings: usize,
+    pub(crate) disconnects: usize,
+}
+
+impl MqttRecord {
+    /// Last retained message of `topic`
+    pub(crate) fn retained(&self, topic: &str) -> Option<&MqttMessage> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| message.retain && message.topic == topic)
+    }
+}
+
+/// MQTT broker that acknowledges everything it receives and records it
+/// Connections are refused with the scripted return codes first
+pub(crate) struct MqttStandIn {
+    addr: SocketAddr,
+    record: Arc<Mutex<MqttRecord>>,
+    /// Drop the connection that sends the next packet
+    kick: Arc<AtomicBool>,
+}
+
+impl MqttStandIn {
+    pub(crate) fn start(refusals: &[u8]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let record = Arc::new(Mutex::new(MqttRecord::default()));
+        let recorded = record.clone();
+        let refusals = refusals.to_vec();
+        let kick = Arc::new(AtomicBool::new(false));
+        let kicked = kick.clone();
+
+        std::thread::spawn(move || {
+            let mut refusals = refusals.into_iter();
+
+            while let Ok((stream, _)) = listener.accept() {
+                let code = refusals.next().unwrap_or(0);
+                let recorded = recorded.clone();
+                let kicked = kicked.clone();
+
+                std::thread::spawn(move || serve_mqtt(stream, code, &recorded, &kicked));
+            }
+        });
+
+        Self { addr, record, kick }
+    }
+
+    /// Drop the connection without a response to its next packet
+    pub(crate) fn kick(&self) {
+        self.kick.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn url(&self) -> String {
+        format!("mqtt://{}", self.addr)
+    }
+
+    /// Packets received so far
+    pub(crate) fn record(&self) -> MqttRecord {
+        self.record.lock().unwrap().clone()
+    }
+
+    /// Wait until the received packets satisfy `done`
+    pub(crate) fn wait(&self, done: impl Fn(&MqttRecord) -> bool) -> MqttRecord {
+        for _ in 0..100 {
+            let record = self.record();
+
+            if done(&record) {
+                return record;
+            }
+
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        panic!("Unexpected packets: {:?}", self.record())
+    }
+}
+
+fn serve_mqtt(
+    mut stream: TcpStream,
+    code: u8,
+    record: &Mutex<MqttRecord>,
+    kick: &AtomicBool,
+) -> Option<()> {
+    let (_, body) = read_packet(&mut stream).ok()?;
+    let connect = parse_connect(&body)?;
+    let v5 = connect.level == 5;
+    let connack: &[u8] = if v5 { &[0, code, 0] } else { &[0, code] };
+
+    record.lock().unwrap().connects.push(connect);
+    write_packet(&mut stream, CONNACK << 4, connack).ok()?;
+
+    if code != 0 {
+        return None;
+    }
+
+    loop {
+        let (header, body) = read_packet(&mut stream).ok()?;
+        let mut fields = Fields(&body);
+
+        if kick.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+
+        match header >> 4 {
+            PUBLISH => {
+                let qos = header >> 1 & 0x03;
+                let topic = fields.string()?;
+                let id = if qos > 0 { fields.take(2)? } else { &[] };
+
+                if v5 {
+                    fields.properties()?;
+                }
+
+                record.lock().unwrap().messages.push(MqttMessage {
+                    topic,
+                    payload: String::from_utf8_lossy(fields.0).into_owned(),
+                    qos,
+                    retain: header & 0x01 != 0,
+                });
+
+                match qos {
+                    1 => write_packet(&mut stream, PUBACK << 4, id).ok()?,
+                    2 => write_packet(&mut stream, PUBREC << 4, id).ok()?,
+                    _ => (),
+                }
+            }
+            PUBREL => write_packet(&mut stream, PUBCOMP << 4, fields.take(2)?).ok()?,
+            PINGREQ => {
+                record.lock().unwrap().pings += 1;
+                write_packet(&mut stream, PINGRESP << 4, &[]).ok()?;
+            }
+            DISCONNECT => {
+                record.lock().unwrap().disconnects += 1;
+                return Some(());
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn parse_connect(body: &[u8]) -> Option<MqttConnect> {
+    let mut fields = Fields(body);
+
+    fields.string()?;
+
+    let level = fields.take(1)?[0];
+    let flags = fields.take(1)?[0];
+    let keep_alive = u16::from_be_bytes(fields.take(2)?.try_into().ok()?);
+
+    if level == 5 {
+        fields.properties()?;
+    }
+
+    let client_id = fields.string()?;
+    let mut will = None;
+
+    if flags & 0x04 != 0 {
+        if level == 5 {
+            fields.properties()?;
+        }
+
+        will = Some(MqttMessage {
+            topic: fields.string()?,
+            payload: fields.string()?,
+            qos: flags >> 3 & 0x03,
+            retain: flags & 0x20 != 0,
+        });
+    }
+
+    let username = if flags & 0x80 != 0 {
+        Some(fields.string()?)
+    } else {
+        None
+    };
+    let password = if flags & 0x40 != 0 {
+        Some(fields.string()?)
+    } else {
+        None
+    };
+
+    Some(MqttConnect {
+        level,
+        client_id,
+        username,
+        password,
+        keep_alive,
+        will,
+    })
+}
+
+/// Reader of the MQTT packet fields
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let field = self.0.get(..len)?;
+
+        self.0 = &self.0[len..];
+        Some(field)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = u16::from_be_bytes(self.take(2)?.try_into().ok()?);
+
+        Some(String::from_utf8_lossy(self.take(len as usize)?).into_owned())
+    }
+
+    /// Skip MQTT 5 properties
+    fn properties(&mut self) -> Option<()> {
+        let mut len = 0;
+
+        for shift in [0, 7, 14, 21] {
+            let byte = self.take(1)?[0];
+
+            len |= ((byte & 0x7f) as usize) << shift;
+
+            if byte & 0x80 == 0 {
+                return self.take(len).map(|_| ());
+            }
+        }
+
+        None
+    }
+}
+
+/// Get position after the single question of the DNS query
+fn question_end(msg: &[u8]) -> Option<usize> {
+    let mut pos = 12;
+
+    while *msg.get(pos)? != 0 {
+        pos += 1 + msg[pos] as usize;
+    }
+
+    (pos + 5 <= msg.len()).then_some(pos + 5)
+}
@@ -0,0 +1,795 @@
+/* SPDX-License-Identifier: MIT OR Apache-2.0 */
+use std::{
+    io,
+    process::ExitStatus,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::{debug, error, warn};
+use thiserror::Error;
+
+use vpn_ip_tracker::{
+    outbox::Outbox,
+    report::{EventKind, ReportPayload},
+    sink::SinkConfig,
+};
+
+use crate::dns::DnsError;
+use crate::events::IfaceStates;
+use crate::monitor::{EventSource, Wakeup};
+use crate::mqtt::MqttError;
+use crate::utils::IfaceInfo;
+
+/// Errors that can occur while delivering a report
+#[derive(Debug, Error)]
+pub(crate) enum ReportError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Dns(#[from] DnsError),
+    #[error(transparent)]
+    Mqtt(#[from] MqttError),
+    #[error("invalid request: {0}")]
+    Invalid(String),
+    #[error("command failed with {0}")]
+    Exit(ExitStatus),
+    #[error("command timed out")]
+    CommandTimeout,
+    #[error("report rejected with status {status}")]
+    Status {
+        status: u16,
+        /// Delay requested by the `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+}
+
+impl ReportError {
+    /// Check whether the delivery may succeed if repeated
+    /// Timeouts, connection and I/O failures, server errors and rate limiting are retryable, so are
+    /// SERVFAIL of a DNS server, unavailable or busy MQTT brokers and commands that time out or
+    /// exit with status 75
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            ReportError::Http(e) => !e.is_builder(),
+            ReportError::Io(_) | ReportError::CommandTimeout => true,
+            // EX_TEMPFAIL of sysexits.h
+            ReportError::Exit(status) => status.code() == Some(75),
+            ReportError::Json(_) | ReportError::Invalid(_) => false,
+            ReportError::Dns(e) => matches!(
+                e,
+                DnsError::Io(_) | DnsError::Timeout | DnsError::Resolve(_) | DnsError::Rcode(2)
+            ),
+            ReportError::Mqtt(e) => matches!(
+                e,
+                MqttError::Io(_)
+                    | MqttError::Disconnected
+                    | MqttError::Refused(3 | 0x88 | 0x89 | 0x97 | 0x9f)
+                    | MqttError::Rejected(0x97)
+            ),
+            ReportError::Status { status, .. } => {
+                matches!(status, 408 | 429) || (500..600).contains(status)
+            }
+        }
+    }
+
+    /// Check whether the report service does not accept the application token anymore
+    /// DNS servers refuse the update or reject its signature with REFUSED or NOTAUTH, MQTT
+    /// brokers refuse the connection with bad credentials or not authorized codes
+    pub(crate) fn is_unauthorized(&self) -> bool {
+        matches!(
+            self,
+            ReportError::Status {
+                status: 401 | 403,
+                ..
+            } | ReportError::Dns(DnsError::Rcode(5 | 9))
+                | ReportError::Mqtt(MqttError::Refused(4 | 5 | 0x86 | 0x87))
+        )
+    }
+
+    /// Delay requested by the report service before the next attempt
+    pub(crate) fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ReportError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Provider of the current VPN interfaces
+pub(crate) trait IfaceScanner {
+    /// Collect all tracked VPN interfaces
+    fn scan(&mut self) -> Vec<IfaceInfo>;
+
+    /// Time until the interfaces should be scanned again without a network change, `None` if
+    /// only the changes matter
+    fn rescan_delay(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Destination of the interface change reports
+pub(crate) trait Reporter {
+    /// Deliver `report`
+    fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError>;
+
+    /// Time the failed delivery is due to be attempted again, `None` if the reporter is ready
+    fn next_attempt(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// Report destination with its own queue of undelivered reports
+pub(crate) struct Sink {
+    config: SinkConfig,
+    outbox: Outbox,
+    reporter: Box<dyn Reporter>,
+}
+
+impl Sink {
+    /// Create sink
+    /// Arguments:
+    /// - `config` - sink configuration
+    /// - `outbox` - queue of the sink reports that were not delivered yet
+    /// - `reporter` - delivery of the sink reports
+    pub(crate) fn new(config: SinkConfig, outbox: Outbox, reporter: Box<dyn Reporter>) -> Self {
+        Self {
+            config,
+            outbox,
+            reporter,
+        }
+    }
+
+    /// Check whether the reporter waits before attempting a failed delivery again
+    fn is_backing_off(&self) -> bool {
+        self.reporter
+            .next_attempt()
+            .is_some_and(|retry_at| retry_at > Instant::now())
+    }
+
+    /// Queue `report` if the sink accepts its event
+    /// Returns `false` if the report is queued but the outbox could not be stored
+    fn queue(&mut self, report: &ReportPayload) -> bool {
+        if !self.config.accepts(report.event) {
+            return true;
+        }
+
+        match self.outbox.push(report.clone()) {
+            Ok(0) => true,
+            Ok(dropped) => {
+                warn!(
+                    "Outbox of {} is full, drop {} oldest reports",
+                    self.config.name, dropped
+                );
+                true
+            }
+            Err(e) => {
+                warn!("Failed to store outbox of {}: {}", self.config.name, e);
+                false
+            }
+        }
+    }
+
+    /// Deliver queued reports in order
+    /// Delivery stops at the first report that failed with a retryable error, it is repeated
+    /// on the update after its retry delay; reports rejected for good are dropped
+    fn flush(&mut self, now: SystemTime) -> Result<(), ReportError> {
+        let name = &self.config.name;
+
+        match self.outbox.expire(now) {
+            Ok(0) => (),
+            Ok(dropped) => warn!("Drop {} expired reports of {}", dropped, name),
+            Err(e) => warn!("Failed to store outbox of {}: {}", name, e),
+        }
+
+        if self.is_backing_off() {
+            return Ok(());
+        }
+
+        while let Some(report) = self.outbox.front() {
+            let iface = &report.interface.name;
+
+            match self.reporter.report(report) {
+                Ok(_) => debug!("Successfully report {} to {}", iface, name),
+                Err(e) if e.is_unauthorized() => return Err(e),
+                Err(e) if e.is_retryable() => {
+                    warn!("Failed to send report for {} to {}: {}", iface, name, e);
+                    break;
+                }
+                Err(e) => error!("Drop report for {} to {}: {}", iface, name, e),
+            }
+
+            if let Err(e) = self.outbox.pop_front() {
+                warn!("Failed to store outbox of {}: {}", name, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send heartbeat `reports`
+    /// Heartbeats are not queued: they are skipped while older reports wait for delivery and
+    /// a failed one is not repeated as the next heartbeat replaces it anyway
+    fn send_heartbeats(&mut self, reports: &[ReportPayload]) -> Result<(), ReportError> {
+        if !self.outbox.is_empty()
+            || !self.config.accepts(EventKind::Heartbeat)
+            || self.is_backing_off()
+        {
+            return Ok(());
+        }
+
+        for report in reports {
+            let iface = &report.interface.name;
+
+            match self.reporter.report(report) {
+                Ok(_) => debug!(
+                    "Successfully send heartbeat for {} to {}",
+                    iface, self.config.name
+                ),
+                Err(e) if e.is_unauthorized() => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Failed to send heartbeat for {} to {}: {}",
+                        iface, self.config.name, e
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// VPN interface tracker that turns interface snapshots into reports
+pub(crate) struct Tracker {
+    reported: IfaceStates,
+    sinks: Vec<Sink>,
+    hostname: String,
+    device: Option<String>,
+    heartbeat: Option<Duration>,
+    started: SystemTime,
+    last_heartbeat: SystemTime,
+}
+
+impl Tracker {
+    /// Create tracker
+    /// Arguments:
+    /// - `reported` - last reported state of the interfaces
+    /// - `sinks` - destinations of the reports
+    /// - `hostname` - name of the host included in the reports
+    /// - `device` - device label included in the reports
+    /// - `heartbeat` - interval of the heartbeat reports, `None` disables them
+    pub(crate) fn new(
+        reported: IfaceStates,
+        sinks: Vec<Sink>,
+        hostname: String,
+        device: Option<String>,
+        heartbeat: Option<Duration>,
+    ) -> Self {
+        let started = SystemTime::now();
+
+        Self {
+            reported,
+            sinks,
+            hostname,
+            device,
+            heartbeat,
+            started,
+            last_heartbeat: started,
+        }
+    }
+
+    /// Check interfaces every time `source` wakes up until it is exhausted
+    /// Tracking stops early if none of the sinks accepts its token anymore
+    pub(crate) fn run(
+        &mut self,
+        source: &mut dyn EventSource,
+        scanner: &mut dyn IfaceScanner,
+    ) -> Result<(), ReportError> {
+        loop {
+            self.update(scanner)?;
+
+            let limit = match (self.retry_delay(), scanner.rescan_delay()) {
+                (Some(retry), Some(rescan)) => Some(retry.min(rescan)),
+                (retry, rescan) => retry.or(rescan),
+            };
+
+            match source.wait(limit) {
+                Some(Wakeup::Changed) => debug!("Network configuration changed"),
+                Some(Wakeup::Timeout) => (),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Scan interfaces once, queue reports about all changes and deliver the queued reports
+    /// The reported state is stored only once all outboxes holding the reports are stored, so
+    /// the reports are created again after a restart if they could be lost
+    /// A sink that does not accept its token anymore is dropped
+    pub(crate) fn update(&mut self, scanner: &mut dyn IfaceScanner) -> Result<(), ReportError> {
+        let snapshot = scanner.scan();
+        let now = SystemTime::now();
+
+        let events = self.reported.events(&snapshot, now);
+        let mut stored = true;
+
+        for event in &events {
+            debug!("Queue report for {}: {:?}", event.name(), event);
+
+            let report = event.payload(&self.hostname, self.device.as_deref(), now);
+
+            for sink in &mut self.sinks {
+                stored &= sink.queue(&report);
+            }
+
+            // Queued reports are delivered by this run even if their outbox is not stored
+            self.reported.apply(event, now);
+        }
+
+        if !events.is_empty() && !stored {
+            warn!("Keep stored reported state, the reports are not stored yet");
+        } else if !events.is_empty() {
+            if let Err(e) = self.reported.store() {
+                warn!("Failed to store reported state: {}", e);
+            }
+        }
+
+        let heartbeats = self.heartbeats(now);
+        let mut rejected = None;
+
+        self.sinks.retain_mut(|sink| {
+            match sink
+                .flush(now)
+                .and_then(|_| sink.send_heartbeats(&heartbeats))
+            {
+                Ok(_) => true,
+                Err(e) => {
+                    error!(
+                        "Drop sink {}, it rejects the token: {}",
+                        sink.config.name, e
+                    );
+                    rejected = Some(e);
+                    false
+                }
+            }
+        });
+
+        match rejected {
+            Some(e) if self.sinks.is_empty() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Time until the earliest retry of the undelivered reports, `None` if no retry is scheduled
+    fn retry_delay(&self) -> Option<Duration> {
+        let now = Instant::now();
+
+        self.sinks
+            .iter()
+            .filter(|sink| !sink.outbox.is_empty())
+            .filter_map(|sink| sink.reporter.next_attempt())
+            .min()
+            .map(|retry_at| retry_at.saturating_duration_since(now))
+    }
+
+    /// Heartbeat reports to send, empty if the heartbeat interval did not elapse yet
+    fn heartbeats(&mut self, now: SystemTime) -> Vec<ReportPayload> {
+        let Some(interval) = self.heartbeat else {
+            return Vec::new();
+        };
+
+        if now.duration_since(self.last_heartbeat).unwrap_or_default() < interval {
+            return Vec::new();
+        }
+
+        let uptime = now.duration_since(self.started).unwrap_or_default();
+
+        self.last_heartbeat = now;
+        self.reported
+            .heartbeats(now)
+            .iter()
+            .map(|event| ReportPayload {
+                uptime: Some(uptime.as_secs()),
+                ..event.payload(&self.hostname, self.device.as_deref(), now)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tracker_tests {
+    use std::{
+        cell::RefCell,
+        collections::{BTreeMap, VecDeque},
+        path::Path,
+        rc::Rc,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use vpn_ip_tracker::{
+        outbox::{Outbox, OutboxConfig},
+        report::{EventKind, ReportFormat, ReportPayload},
+        sink::{HttpSinkConfig, SinkConfig, SinkKind},
+        RetryConfig,
+    };
+
+    use crate::events::IfaceStates;
+    use crate::monitor::{EventSource, Wakeup};
+    use crate::retry::RetryingReporter;
+    use crate::tracker::{IfaceScanner, ReportError, Reporter, Sink, Tracker};
+    use crate::utils::IfaceInfo;
+
+    pub(crate) fn iface(name: &str, addr: &str) -> IfaceInfo {
+        IfaceInfo {
+            name: name.into(),
+            ipv4: BTreeMap::from([(addr.parse().unwrap(), 24)]),
+            ..Default::default()
+        }
+    }
+
+    /// Event source that replays the scripted wakeups
+    pub(crate) struct ScriptedSource(pub(crate) VecDeque<Wakeup>);
+
+    impl EventSource for ScriptedSource {
+        fn wait(&mut self, _limit: Option<Duration>) -> Option<Wakeup> {
+            self.0.pop_front()
+        }
+    }
+
+    /// Scanner that returns the scripted snapshots, the last one is repeated
+    pub(crate) struct ScriptedScanner(pub(crate) VecDeque<Vec<IfaceInfo>>);
+
+    impl IfaceScanner for ScriptedScanner {
+        fn scan(&mut self) -> Vec<IfaceInfo> {
+            if self.0.len() > 1 {
+                self.0.pop_front().unwrap()
+            } else {
+                self.0.front().cloned().unwrap_or_default()
+            }
+        }
+    }
+
+    /// Sink that reports the accepted `events` to `reporter`, all events if empty
+    pub(crate) fn sink(
+        name: &str,
+        events: &[EventKind],
+        reporter: &Rc<RefCell<RecordingReporter>>,
+    ) -> Sink {
+        Sink::new(
+            SinkConfig {
+                name: name.into(),
+                events: events.to_vec(),
+                kind: SinkKind::Http(HttpSinkConfig {
+                    url: "https://report.example".into(),
+                    token: "secret".into(),
+                    format: ReportFormat::Plain,
+                    sign: false,
+                }),
+                retry: None,
+            },
+            Outbox::in_memory(&OutboxConfig::default()),
+            Box::new(reporter.clone()),
+        )
+    }
+
+    pub(crate) fn tracker(sinks: Vec<Sink>) -> Tracker {
+        Tracker::new(IfaceStates::default(), sinks, "laptop".into(), None, None)
+    }
+
+    /// Tracker with a single sink that reports to a recording reporter with scripted `failures`
+    fn recorded(failures: &[u16]) -> (Tracker, Rc<RefCell<RecordingReporter>>) {
+        let reporter = Rc::new(RefCell::new(RecordingReporter {
+            failures: VecDeque::from(failures.to_vec()),
+            ..Default::default()
+        }));
+
+        (tracker(vec![sink("default", &[], &reporter)]), reporter)
+    }
+
+    pub(crate) fn status(status: u16) -> ReportError {
+        ReportError::Status {
+            status,
+            retry_after: None,
+        }
+    }
+
+    /// Reporter that records delivered events and fails with the scripted statuses first
+    #[derive(Default)]
+    pub(crate) struct RecordingReporter {
+        pub(crate) failures: VecDeque<u16>,
+        pub(crate) events: Vec<(EventKind, String)>,
+    }
+
+    impl Reporter for RecordingReporter {
+        fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+            if let Some(failure) = self.failures.pop_front() {
+                return Err(status(failure));
+            }
+
+            self.events
+                .push((report.event, report.interface.name.clone()));
+            Ok(())
+        }
+    }
+
+    impl<R: Reporter> Reporter for Rc<RefCell<R>> {
+        fn report(&mut self, report: &ReportPayload) -> Result<(), ReportError> {
+            self.borrow_mut().report(report)
+        }
+
+        fn next_attempt(&self) -> Option<Instant> {
+            self.borrow().next_attempt()
+        }
+    }
+
+    #[test]
+    fn test_run_scripted() {
+        let mut source = ScriptedSource(VecDeque::from([
+            Wakeup::Changed,
+            Wakeup::Timeout,
+            Wakeup::Changed,
+            Wakeup::Changed,
+        ]));
+        let mut scanner = ScriptedScanner(VecDeque::from([
+            vec![],
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.2"), iface("wg0", "10.9.0.2")],
+            vec![iface("wg0", "10.9.0.3")],
+        ]));
+        let (mut tracker, reporter) = recorded(&[]);
+
+        tracker.run(&mut source, &mut scanner).unwrap();
+
+        assert_eq!(
+            reporter.borrow().events,
+            [
+                (EventKind::Up, "tun0".to_string()),
+                (EventKind::Up, "wg0".to_string()),
+                (EventKind::Changed, "wg0".to_string()),
+                (EventKind::Down, "tun0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_report_is_retried() {
+        let mut source = ScriptedSource(VecDeque::from([Wakeup::Timeout, Wakeup::Timeout]));
+        let mut scanner = ScriptedScanner(VecDeque::from([vec![iface("tun0", "10.8.0.2")]]));
+        let (mut tracker, reporter) = recorded(&[503]);
+
+        tracker.run(&mut source, &mut scanner).unwrap();
+
+        assert_eq!(
+            reporter.borrow().events,
+            [(EventKind::Up, "tun0".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_rejected_report_is_dropped() {
+        let mut source = ScriptedSource(VecDeque::from([Wakeup::Timeout, Wakeup::Timeout]));
+        let mut scanner = ScriptedScanner(VecDeque::from([
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.3")],
+        ]));
+        let (mut tracker, reporter) = recorded(&[400]);
+
+        tracker.run(&mut source, &mut scanner).unwrap();
+
+        assert_eq!(
+            reporter.borrow().events,
+            [(EventKind::Changed, "tun0".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_revoked_token_stops_tracking() {
+        let mut source = ScriptedSource(VecDeque::from([Wakeup::Timeout, Wakeup::Timeout]));
+        let mut scanner = ScriptedScanner(VecDeque::from([vec![iface("tun0", "10.8.0.2")]]));
+        let (mut tracker, reporter) = recorded(&[401]);
+        let result = tracker.run(&mut source, &mut scanner);
+
+        assert!(result.unwrap_err().is_unauthorized());
+        assert!(reporter.borrow().events.is_empty());
+        assert_eq!(source.0.len(), 2);
+    }
+
+    #[test]
+    fn test_error_classification() {
+        assert!(status(503).is_retryable());
+        assert!(status(408).is_retryable());
+        assert!(status(429).is_retryable());
+        assert!(!status(400).is_retryable());
+        assert!(!status(401).is_retryable());
+        assert!(status(403).is_unauthorized());
+        assert!(!status(500).is_unauthorized());
+    }
+
+    #[test]
+    fn test_queued_reports_keep_order() {
+        let mut source = ScriptedSource(VecDeque::from([Wakeup::Changed]));
+        let mut scanner = ScriptedScanner(VecDeque::from([
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.3")],
+        ]));
+        let (mut tracker, reporter) = recorded(&[503]);
+
+        tracker.run(&mut source, &mut scanner).unwrap();
+
+        assert_eq!(
+            reporter.borrow().events,
+            [
+                (EventKind::Up, "tun0".to_string()),
+                (EventKind::Changed, "tun0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_heartbeats() {
+        let mut source = ScriptedSource(VecDeque::from([Wakeup::Timeout]));
+        let mut scanner = ScriptedScanner(VecDeque::from([vec![iface("tun0", "10.8.0.2")]]));
+        let (mut tracker, reporter) = recorded(&[503]);
+
+        tracker.heartbeat = Some(Duration::ZERO);
+        tracker.run(&mut source, &mut scanner).unwrap();
+
+        assert_eq!(
+            reporter.borrow().events,
+            [
+                (EventKind::Up, "tun0".to_string()),
+                (EventKind::Heartbeat, "tun0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sinks_are_independent() {
+        let mut source = ScriptedSource(VecDeque::from([Wakeup::Timeout, Wakeup::Timeout]));
+        let mut scanner = ScriptedScanner(VecDeque::from([
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.3")],
+        ]));
+        let failing = Rc::new(RefCell::new(RecordingReporter {
+            failures: VecDeque::from([503, 503]),
+            ..Default::default()
+        }));
+        let filtered = Rc::new(RefCell::new(RecordingReporter::default()));
+        let mut tracker = tracker(vec![
+            sink("failing", &[], &failing),
+            sink("filtered", &[EventKind::Changed], &filtered),
+        ]);
+
+        tracker.update(&mut scanner).unwrap();
+        tracker.update(&mut scanner).unwrap();
+
+        assert!(failing.borrow().events.is_empty());
+        assert_eq!(
+            filtered.borrow().events,
+            [(EventKind::Changed, "tun0".to_string())]
+        );
+
+        tracker.run(&mut source, &mut scanner).unwrap();
+
+        assert_eq!(
+            failing.borrow().events,
+            [
+                (EventKind::Up, "tun0".to_string()),
+                (EventKind::Changed, "tun0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejecting_sink_is_dropped() {
+        let mut scanner = ScriptedScanner(VecDeque::from([
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.3")],
+        ]));
+        let rejecting = Rc::new(RefCell::new(RecordingReporter {
+            failures: VecDeque::from([401]),
+            ..Default::default()
+        }));
+        let working = Rc::new(RefCell::new(RecordingReporter::default()));
+        let mut tracker = tracker(vec![
+            sink("rejecting", &[], &rejecting),
+            sink("working", &[], &working),
+        ]);
+
+        tracker.update(&mut scanner).unwrap();
+        tracker.update(&mut scanner).unwrap();
+
+        assert_eq!(tracker.sinks.len(), 1);
+        assert!(rejecting.borrow().events.is_empty());
+        assert_eq!(working.borrow().events.len(), 2);
+    }
+
+    #[test]
+    fn test_backoff_does_not_block_other_sinks() {
+        let mut scanner = ScriptedScanner(VecDeque::from([
+            vec![iface("tun0", "10.8.0.2")],
+            vec![iface("tun0", "10.8.0.3")],
+        ]));
+        let failing = Rc::new(RefCell::new(RecordingReporter {
+            failures: VecDeque::from([503, 503]),
+            ..Default::default()
+        }));
+        let working = Rc::new(RefCell::new(RecordingReporter::default()));
+        let mut backing_off = sink("failing", &[], &failing);
+
+        backing_off.reporter = Box::new(RetryingReporter::new(
+            failing.clone(),
+            &RetryConfig {
+                max_attempts: 3,
+                base_delay_ms: 60_000,
+                max_delay_ms: 60_000,
+                jitter: 0.0,
+            },
+        ));
+
+        let mut tracker = tracker(vec![backing_off, sink("working", &[], &working)]);
+        let started = Instant::now();
+
+        tracker.update(&mut scanner).unwrap();
+        tracker.update(&mut scanner).unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(failing.borrow().failures.len(), 1);
+        assert_eq!(working.borrow().events.len(), 2);
+        assert!(tracker.retry_delay().unwrap() > Duration::from_secs(50));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_state_is_stored_with_outboxes() {
+        let state_path =
+            std::env::temp_dir().join(format!("vpn-ip-tracker-queued-{}.json", std::process::id()));
+        let scanner = || ScriptedScanner(VecDeque::from([vec![iface("tun0", "10.8.0.2")]]));
+        let reporter = Rc::new(RefCell::new(RecordingReporter {
+            failures: VecDeque::from([503]),
+            ..Default::default()
+        }));
+        let mut broken = sink("broken", &[], &reporter);
+
+        broken.outbox = Outbox::empty(Path::new("/dev/null/outbox.json"), &OutboxConfig::default());
+
+        let mut tracker = Tracker::new(
+            IfaceStates::empty(&state_path),
+            vec![broken],
+            "laptop".into(),
+            None,
+            None,
+        );
+
+        tracker.update(&mut scanner()).unwrap();
+        tracker.update(&mut scanner()).unwrap();
+
+        assert!(!state_path.exists());
+        assert_eq!(
+            reporter.borrow().events,
+            [(EventKind::Up, "tun0".to_string())]
+        );
+
+        let mut tracker = Tracker::new(
+            IfaceStates::empty(&state_path),
+            vec![sink("working", &[], &reporter)],
+            "laptop".into(),
+            None,
+            None,
+        );
+
+        tracker.update(&mut scanner()).unwrap();
+
+        assert!(IfaceStates::open(&state_path)
+            .unwrap()
+            .events(&[iface("tun0", "10.8.0.2")], SystemTime::now())
+            .is_empty());
+        std::fs::remove_file(&state_path).unwrap();
+    }
+}
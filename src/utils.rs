@@ -1,13 +1,224 @@
 /* SPDX-License-Identifier: MIT OR Apache-2.0 */
-use std::net;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    time::Duration,
+};
 
-use ifcfg::IfCfg;
+use ifcfg::{Hops, IfCfg};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 
-#[derive(Debug, PartialEq, Clone)]
+use vpn_ip_tracker::{
+    matching::LinkKind,
+    report::{OperState, TunnelMode},
+    AddressConfig,
+};
+
+use crate::egress::EgressIp;
+use crate::procfs::Inet6Addr;
+use crate::sysfs::SysfsNet;
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub(crate) struct IfaceInfo {
     pub(crate) name: String,
-    pub(crate) ip: net::IpAddr,
+    /// IPv4 addresses with their prefix lengths
+    pub(crate) ipv4: BTreeMap<Ipv4Addr, u8>,
+    /// IPv6 addresses with their prefix lengths
+    pub(crate) ipv6: BTreeMap<Ipv6Addr, u8>,
     pub(crate) index: u32,
+    /// Destination address of the point-to-point link
+    pub(crate) peer: Option<net::IpAddr>,
+    pub(crate) mtu: Option<u32>,
+    pub(crate) oper_state: Option<OperState>,
+    pub(crate) kind: Option<LinkKind>,
+    /// Interface is administratively and operationally up
+    pub(crate) up: bool,
+    /// Share of the traffic carried by the interface
+    pub(crate) tunnel: Option<TunnelMode>,
+    /// Public address the traffic leaves the VPN with
+    pub(crate) egress: Option<EgressIp>,
+}
+
+impl IfaceInfo {
+    /// Check whether the interface has no tracked addresses
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// Check whether the interface differs from its `previous` state in a reported detail
+    /// Link state, tunnel mode and the egress providers disagreement may change between scans
+    /// without a report, they are sent along with the next reported change
+    pub(crate) fn is_changed(&self, previous: &IfaceInfo) -> bool {
+        self.ipv4 != previous.ipv4
+            || self.ipv6 != previous.ipv6
+            || self.peer != previous.peer
+            || self.mtu != previous.mtu
+            || self.index != previous.index
+            || self.kind != previous.kind
+            || self.egress.as_ref().map(|egress| egress.addr)
+                != previous.egress.as_ref().map(|egress| egress.addr)
+    }
+
+    /// Iterate over all tracked addresses, IPv4 addresses go first
+    pub(crate) fn addresses(&self) -> impl Iterator<Item = net::IpAddr> + '_ {
+        self.ipv4
+            .keys()
+            .copied()
+            .map(net::IpAddr::V4)
+            .chain(self.ipv6.keys().copied().map(net::IpAddr::V6))
+    }
+
+    /// Fill in link details that are not provided by the interface address list
+    pub(crate) fn read_link_info(&mut self, sysfs: &SysfsNet) {
+        self.mtu = sysfs.mtu(&self.name);
+        self.oper_state = sysfs.oper_state(&self.name);
+        self.kind = sysfs.link_kind(&self.name);
+        self.up = sysfs.is_up(&self.name).unwrap_or(true)
+            && !matches!(
+                self.oper_state,
+                Some(OperState::Down | OperState::LowerLayerDown | OperState::NotPresent)
+            );
+    }
+
+    /// Drop addresses that should not be tracked according to the configuration
+    /// Arguments:
+    /// - `config` - address configuration
+    /// - `inet6` - IPv6 address details used to find temporary and deprecated addresses
+    pub(crate) fn retain_addresses(&mut self, config: &AddressConfig, inet6: &[Inet6Addr]) {
+        if !config.family.has_ipv4() {
+            self.ipv4.clear();
+        }
+
+        if !config.family.has_ipv6() {
+            self.ipv6.clear();
+        }
+
+        if !config.link_local {
+            self.ipv4.retain(|addr, _| !addr.is_link_local());
+            self.ipv6.retain(|addr, _| !is_ipv6_link_local(addr));
+        }
+
+        if !config.temporary {
+            self.ipv6.retain(|addr, _| {
+                !inet6
+                    .iter()
+                    .any(|it| it.name == self.name && it.addr == *addr && it.is_temporary())
+            });
+        }
+    }
+}
+
+fn is_ipv6_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Get prefix length from the network `mask`
+fn prefix_len(mask: Option<net::SocketAddr>) -> Option<u8> {
+    match mask? {
+        net::SocketAddr::V4(mask) => Some(u32::from(*mask.ip()).count_ones() as u8),
+        net::SocketAddr::V6(mask) => Some(u128::from(*mask.ip()).count_ones() as u8),
+    }
+}
+
+/// Fill `buf` with random bytes from the randomly seeded std hasher
+/// Good enough for protocol identifiers, not for key material
+pub(crate) fn fill_random(buf: &mut [u8]) {
+    let state = RandomState::new();
+
+    for (i, chunk) in buf.chunks_mut(8).enumerate() {
+        let mut hasher = state.build_hasher();
+
+        hasher.write_usize(i);
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes()[..chunk.len()]);
+    }
+}
+
+/// Create UDP socket that sends from `local` through the interface `device`
+pub(crate) fn udp_socket(local: net::IpAddr, device: Option<&str>) -> io::Result<UdpSocket> {
+    Ok(bound_socket(local, device, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+/// Connect TCP stream to `addr` from `local` through the interface `device`
+pub(crate) fn tcp_connect(
+    local: net::IpAddr,
+    device: Option<&str>,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let socket = bound_socket(local, device, Type::STREAM, Protocol::TCP)?;
+
+    socket.connect_timeout(&addr.into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// Create socket bound to `local` and on Linux to the interface `device`
+/// The source address alone does not keep the traffic on the interface if the routes send it
+/// elsewhere. Binding to the interface needs `CAP_NET_RAW` before Linux 5.7, only the address
+/// is bound without it
+fn bound_socket(
+    local: net::IpAddr,
+    device: Option<&str>,
+    ty: Type,
+    protocol: Protocol,
+) -> io::Result<Socket> {
+    let local = SocketAddr::new(local, 0);
+    let socket = Socket::new(Domain::for_address(local), ty, Some(protocol))?;
+
+    #[cfg(target_os = "linux")]
+    if let Some(device) = device {
+        match socket.bind_device(Some(device.as_bytes())) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                log::debug!("Not permitted to bind socket to {}: {}", device, e)
+            }
+            result => result?,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = device;
+
+    socket.bind(&local.into())?;
+    Ok(socket)
+}
+
+/// Get name of the host the tracker runs on
+#[cfg(unix)]
+pub(crate) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+
+    // SAFETY: the buffer outlives the call and its length is passed along
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Get name of the host the tracker runs on
+#[cfg(windows)]
+pub(crate) fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// Get index of the interface with `name`
+#[cfg(unix)]
+pub(crate) fn iface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid NUL-terminated string that outlives the call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+
+    (index != 0).then_some(index)
+}
+
+/// Get index of the interface with `name`
+#[cfg(windows)]
+pub(crate) fn iface_index(_name: &str) -> Option<u32> {
+    None
 }
 
 impl TryFrom<IfCfg> for IfaceInfo {
@@ -18,17 +229,221 @@ impl TryFrom<IfCfg> for IfaceInfo {
             return Err("Address is unknown");
         }
 
-        match value
-            .addresses
+        let mut ipv4 = BTreeMap::new();
+        let mut ipv6 = BTreeMap::new();
+        let mut peer = None;
+
+        for iface_addr in value.addresses.iter() {
+            let prefix = prefix_len(iface_addr.mask);
+
+            match iface_addr.address {
+                Some(net::SocketAddr::V4(addr)) => {
+                    ipv4.insert(*addr.ip(), prefix.unwrap_or(32));
+                }
+                Some(net::SocketAddr::V6(addr)) => {
+                    ipv6.insert(*addr.ip(), prefix.unwrap_or(128));
+                }
+                None => continue,
+            }
+
+            if let Some(Hops::Destination(dest)) = iface_addr.hop {
+                peer = peer.or(Some(dest.ip()));
+            }
+        }
+
+        if ipv4.is_empty() && ipv6.is_empty() {
+            return Err("No supported IP address found");
+        }
+
+        Ok(IfaceInfo {
+            index: iface_index(&value.name).unwrap_or_default(),
+            name: value.name,
+            ipv4,
+            ipv6,
+            peer,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod utils_tests {
+    use std::net::SocketAddr;
+
+    use ifcfg::{AddressFamily, Hops, IfCfg, InterfaceAddress};
+    use vpn_ip_tracker::{
+        report::{OperState, TunnelMode},
+        AddressConfig, IpFamily,
+    };
+
+    use crate::procfs::ProcNet;
+    use crate::utils::IfaceInfo;
+
+    fn iface(addrs: &[&str]) -> IfaceInfo {
+        let addresses = addrs
             .iter()
-            .find(|addr| matches!(addr.address, Some(net::SocketAddr::V4(_))))
-        {
-            Some(iface_addr) => Ok(IfaceInfo {
-                name: value.name,
-                ip: iface_addr.address.unwrap().ip(),
-                index: 0,
-            }),
-            None => Err("No supported IP address found"),
+            .map(|addr| {
+                let address: SocketAddr = format!("{addr}:0")
+                    .parse()
+                    .or_else(|_| format!("[{addr}]:0").parse())
+                    .unwrap();
+
+                InterfaceAddress {
+                    address_family: match address {
+                        SocketAddr::V4(_) => AddressFamily::IPv4,
+                        SocketAddr::V6(_) => AddressFamily::IPv6,
+                    },
+                    address: Some(address),
+                    mask: None,
+                    hop: None,
+                }
+            })
+            .collect();
+
+        IfaceInfo::try_from(IfCfg {
+            name: "tun0".into(),
+            mac: String::new(),
+            addresses,
+            description: String::new(),
+        })
+        .unwrap()
+    }
+
+    fn addresses(iface: &IfaceInfo) -> Vec<String> {
+        iface.addresses().map(|addr| addr.to_string()).collect()
+    }
+
+    const ADDRS: [&str; 5] = [
+        "fd00::2",
+        "10.8.0.2",
+        "fe80::1",
+        "169.254.3.4",
+        "2001:db8::1c2a:3b4c:5d6e:7f80",
+    ];
+
+    #[test]
+    fn test_families() {
+        let mut ipv4 = iface(&ADDRS);
+        let mut ipv6 = iface(&ADDRS);
+        let mut both = iface(&ADDRS);
+
+        ipv4.retain_addresses(&AddressConfig::default(), &[]);
+        ipv6.retain_addresses(
+            &AddressConfig {
+                family: IpFamily::Ipv6,
+                ..Default::default()
+            },
+            &[],
+        );
+        both.retain_addresses(
+            &AddressConfig {
+                family: IpFamily::Both,
+                ..Default::default()
+            },
+            &[],
+        );
+
+        assert_eq!(addresses(&ipv4), ["10.8.0.2"]);
+        assert_eq!(
+            addresses(&ipv6),
+            ["2001:db8::1c2a:3b4c:5d6e:7f80", "fd00::2"]
+        );
+        assert_eq!(
+            addresses(&both),
+            ["10.8.0.2", "2001:db8::1c2a:3b4c:5d6e:7f80", "fd00::2"]
+        );
+    }
+
+    #[test]
+    fn test_link_local_and_temporary() {
+        let root = std::env::temp_dir().join(format!(
+            "vpn-ip-tracker-utils-{}-temporary",
+            std::process::id()
+        ));
+
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join("if_inet6"),
+            "20010db8000000001c2a3b4c5d6e7f80 05 40 00 01     tun0\n",
+        )
+        .unwrap();
+        let inet6 = ProcNet::new(&root).inet6_addrs();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let mut filtered = iface(&ADDRS);
+        let mut unfiltered = iface(&ADDRS);
+
+        filtered.retain_addresses(
+            &AddressConfig {
+                family: IpFamily::Both,
+                ..Default::default()
+            },
+            &inet6,
+        );
+        unfiltered.retain_addresses(
+            &AddressConfig {
+                family: IpFamily::Both,
+                link_local: true,
+                temporary: true,
+            },
+            &inet6,
+        );
+
+        assert_eq!(addresses(&filtered), ["10.8.0.2", "fd00::2"]);
+        assert_eq!(addresses(&unfiltered).len(), ADDRS.len());
+    }
+
+    #[test]
+    fn test_change_detection() {
+        let stored = iface(&["10.8.0.2", "fd00::2"]);
+
+        assert!(!iface(&["fd00::2", "10.8.0.2"]).is_changed(&stored));
+        assert!(iface(&["10.8.0.2", "fd00::3"]).is_changed(&stored));
+        assert!(iface(&["10.8.0.3", "fd00::2"]).is_changed(&stored));
+        assert!(IfaceInfo {
+            mtu: Some(1380),
+            ..stored.clone()
+        }
+        .is_changed(&stored));
+        assert!(!IfaceInfo {
+            oper_state: Some(OperState::Dormant),
+            up: !stored.up,
+            tunnel: Some(TunnelMode::Split),
+            ..stored.clone()
         }
+        .is_changed(&stored));
+    }
+
+    #[test]
+    fn test_metadata() {
+        fn ptp_iface(prefix_mask: &str) -> IfaceInfo {
+            IfaceInfo::try_from(IfCfg {
+                name: "tun0".into(),
+                mac: String::new(),
+                addresses: vec![
+                    InterfaceAddress {
+                        address_family: AddressFamily::IPv4,
+                        address: Some("10.8.0.2:0".parse().unwrap()),
+                        mask: Some(format!("{prefix_mask}:0").parse().unwrap()),
+                        hop: Some(Hops::Destination("10.8.0.1:0".parse().unwrap())),
+                    },
+                    InterfaceAddress {
+                        address_family: AddressFamily::IPv6,
+                        address: Some("[fd00::2]:0".parse().unwrap()),
+                        mask: Some("[ffff:ffff:ffff:ffff::]:0".parse().unwrap()),
+                        hop: None,
+                    },
+                ],
+                description: String::new(),
+            })
+            .unwrap()
+        }
+
+        let iface = ptp_iface("255.255.255.0");
+
+        assert_eq!(iface.ipv4.values().collect::<Vec<_>>(), [&24]);
+        assert_eq!(iface.ipv6.values().collect::<Vec<_>>(), [&64]);
+        assert_eq!(iface.peer, Some("10.8.0.1".parse().unwrap()));
+        assert_ne!(iface, ptp_iface("255.255.0.0"));
     }
 }